@@ -0,0 +1,876 @@
+use crate::complete::{self,Action,CommandLine,CompletionSpec};
+use crate::histdb::{self,Query};
+use crate::history::HistoryEntry;
+use crate::parser;
+use crate::shell::{Shell,ShellOptions};
+use crate::signals::{self,Trap};
+use crate::timing;
+use crate::variable::Value;
+use nix::sys::signal::Signal;
+use std::env;
+use std::fs;
+use std::io::{self,Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path,PathBuf};
+use std::process::Command;
+use std::time::{SystemTime,UNIX_EPOCH};
+
+/// Names `builtin_cmd` runs itself rather than looking for a program.
+pub const BUILTINS: &[&str] = &[".", "cd", "compgen", "complete", "disown", "exit", "fc", "history", "local", "logout", "quit", "return", "set", "source", "times", "trap"];
+
+
+/// Rebuilds the words of a command from the tokens of `parser::parseline`, which
+/// splits at operators such as `=` and keeps the whitespace between words as tokens.
+pub fn words(argv: &[String]) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    for arg in argv {
+        if arg.trim().is_empty() {
+            words.extend(word.take());
+        }
+        else {
+            word.get_or_insert_with(String::new).push_str(arg);
+        }
+    }
+    words.extend(word);
+    words
+}
+
+fn print_options(shell: &Shell, reusable: bool) {
+    for name in ShellOptions::NAMES {
+        let enabled = shell.options.get(name).unwrap_or(false);
+        if reusable {
+            println!("set {}o {}", if enabled { '-' } else { '+' }, name);
+        }
+        else {
+            println!("{:<15}\t{}", name, if enabled { "on" } else { "off" });
+        }
+    }
+}
+
+/// `set -o name` enables an option, `set +o name` disables it and
+/// either form without a name lists the current settings. Letters such as
+/// `-e` and `+x` turn the options they stand for on and off.
+pub fn set(shell: &mut Shell, argv: &[&str]) -> i32 {
+    if argv.len() == 1 {
+        print_options(shell, false);
+        return 0;
+    }
+
+    let mut args = argv[1..].iter();
+    while let Some(arg) = args.next() {
+        match *arg {
+            "-o" | "+o" => {
+                let enable = arg.starts_with('-');
+                match args.next() {
+                    Some(name) => {
+                        if !shell.options.set(name, enable) {
+                            eprintln!("set: {}: invalid option name", name);
+                            return 1;
+                        }
+                    },
+                    None => print_options(shell, !enable),
+                }
+            },
+            _ if arg.len() > 1 && (arg.starts_with('-') || arg.starts_with('+')) => {
+                let enable = arg.starts_with('-');
+                for flag in arg[1..].chars() {
+                    match ShellOptions::flag_name(flag) {
+                        Some(name) => {
+                            shell.options.set(name, enable);
+                        },
+                        None => {
+                            eprintln!("set: {}{}: invalid option", &arg[..1], flag);
+                            return 2;
+                        },
+                    }
+                }
+            },
+            _ => {
+                eprintln!("set: {}: invalid option", arg);
+                return 2;
+            },
+        }
+    }
+    0
+}
+
+/// Where `source` finds a file: as given when the name has a slash, otherwise the first
+/// file of that name in PATH, then the current directory unless in posix mode.
+fn find_sourced(shell: &Shell, name: &str) -> Option<PathBuf> {
+    if name.contains('/') {
+        return Some(PathBuf::from(name));
+    }
+    let path = shell.lookup_var("PATH").unwrap_or_default();
+    let found = env::split_paths(&path)
+        .map(|dir| dir.join(name))
+        .find(|file| file.is_file());
+    match found {
+        Some(file) => Some(file),
+        None if !shell.options.posix => Some(PathBuf::from(name)),
+        None => None,
+    }
+}
+
+/// `source file [args]` or `. file [args]` runs the commands in file in this shell,
+/// with args as `$1`, `$2` and so on while it runs.
+pub fn source(shell: &mut Shell, argv: &[&str]) -> i32 {
+    let prefix = shell.error_prefix().unwrap_or_default();
+    let name = match argv.get(1) {
+        Some(name) => unquote(name),
+        None => {
+            eprintln!("{}{}: filename argument required", prefix, argv[0]);
+            return 2;
+        },
+    };
+    let file = match find_sourced(shell, name) {
+        Some(file) => file,
+        None => {
+            eprintln!("{}{}: {}: file not found", prefix, argv[0], name);
+            return 1;
+        },
+    };
+
+    let args: Vec<String> = argv[2..].iter().map(|arg| unquote(arg).to_string()).collect();
+    let saved = (!args.is_empty()).then(|| shell.positional_args());
+    if !args.is_empty() {
+        shell.set_positional_args(&args);
+    }
+    let result = crate::source_file(shell, &file);
+    if let Some(saved) = saved {
+        shell.set_positional_args(&saved);
+    }
+
+    match result {
+        Ok(()) => shell.last_status(),
+        Err(e) => {
+            eprintln!("{}{}: {}: {}", prefix, argv[0], name, e);
+            1
+        },
+    }
+}
+
+/// `return [n]` leaves the function or file being sourced with status n, or that of the last command.
+pub fn r#return(shell: &mut Shell, argv: &[&str]) -> i32 {
+    if !shell.in_function() && !shell.sourcing() {
+        eprintln!("{}return: can only `return' from a function or sourced script", shell.error_prefix().unwrap_or_default());
+        return 1;
+    }
+    let status = match argv.get(1) {
+        Some(arg) => match arg.parse::<i32>() {
+            Ok(status) => status & 0xff,
+            Err(_) => {
+                eprintln!("{}return: {}: numeric argument required", shell.error_prefix().unwrap_or_default(), arg);
+                2
+            },
+        },
+        None => shell.last_status(),
+    };
+    shell.set_returning();
+    status
+}
+
+/// `local name[=value]...` gives the function being run variables of its own.
+pub fn local(shell: &mut Shell, argv: &[&str]) -> i32 {
+    let prefix = shell.error_prefix().unwrap_or_default();
+    if !shell.in_function() {
+        eprintln!("{}local: can only be used in a function", prefix);
+        return 1;
+    }
+    let mut status = 0;
+    for arg in argv[1..].iter() {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (*arg, None),
+        };
+        if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            eprintln!("{}local: `{}': not a valid identifier", prefix, arg);
+            status = 1;
+            continue;
+        }
+        let value = value.map(|value| match value.strip_prefix('$') {
+            Some(var) => shell.lookup_var(var).unwrap_or_default(),
+            None => parser::unquote(value),
+        });
+        shell.set_local_var(name, value.map(Value::String));
+    }
+    status
+}
+
+/// `logout [n]`, `exit` for login shells.
+pub fn logout(shell: &mut Shell, argv: &[&str]) -> i32 {
+    if !shell.login() {
+        eprintln!("logout: not login shell: use `exit'");
+        return 1;
+    }
+    exit(shell, argv)
+}
+
+/// `exit [n]`, refuses once when there are stopped jobs like an interactive bash.
+pub fn exit(shell: &mut Shell, argv: &[&str]) -> i32 {
+    let status = match argv.get(1) {
+        Some(arg) => match arg.parse::<i32>() {
+            Ok(status) => status & 0xff,
+            Err(_) => {
+                eprintln!("exit: {}: numeric argument required", arg);
+                2
+            },
+        },
+        None => shell.last_status(),
+    };
+
+    if shell.has_stopped_jobs() && !shell.exit_warned() {
+        eprintln!("There are stopped jobs.");
+        shell.set_exit_warned(true);
+        return 1;
+    }
+
+    shell.exit(status)
+}
+
+/// `disown [-a] [jobspec...]` removes jobs from the table so exiting does not hang them up.
+pub fn disown(shell: &mut Shell, argv: &[&str]) -> i32 {
+    let mut specs: Vec<&str> = argv[1..].to_vec();
+    if specs.is_empty() {
+        specs.push("%+");
+    }
+
+    let mut status = 0;
+    for spec in specs {
+        if spec == "-a" {
+            while let Some(job) = shell.find_job("%+") {
+                shell.disown(&job);
+            }
+            continue;
+        }
+
+        match shell.find_job(spec) {
+            Some(job) => shell.disown(&job),
+            None => {
+                eprintln!("disown: {}: no such job", spec);
+                status = 1;
+            },
+        }
+    }
+    status
+}
+
+/// Strips the single quotes the parser leaves around a quoted word.
+pub fn unquote(word: &str) -> &str {
+    word.strip_prefix('\'')
+        .and_then(|word| word.strip_suffix('\''))
+        .unwrap_or(word)
+}
+
+fn print_trap(trap: Trap, action: &str) {
+    println!("trap -- '{}' {}", action, trap.name());
+}
+
+fn list_signals() {
+    for (i, signal) in Signal::iterator().enumerate() {
+        let end = if (i + 1) % 5 == 0 { "\n" } else { "\t" };
+        print!("{:2}) {}{}", signal as i32, signal.as_str(), end);
+    }
+    println!();
+}
+
+/// `trap [-lp] [[action] condition...]`
+///
+/// An empty action ignores the condition, `-` or a lone condition puts it back.
+pub fn trap(shell: &mut Shell, argv: &[&str]) -> i32 {
+    let mut args = &argv[1..];
+    match args.first() {
+        Some(&"-l") => {
+            list_signals();
+            return 0;
+        },
+        Some(&"-p") => args = &args[1..],
+        Some(&"--") => args = &args[1..],
+        _ => (),
+    }
+
+    if args.is_empty() || argv.get(1) == Some(&"-p") {
+        for (trap, action) in shell.traps() {
+            if args.is_empty() || args.iter().any(|name| Trap::parse(name) == Some(trap)) {
+                print_trap(trap, action);
+            }
+        }
+        return 0;
+    }
+
+    let (action, names) = if args[0] == "-" {
+        (None, &args[1..])
+    }
+    else if args.len() == 1 || args[0].parse::<u32>().is_ok() {
+        (None, args)
+    }
+    else {
+        (Some(unquote(args[0])), &args[1..])
+    };
+
+    let mut status = 0;
+    for name in names {
+        let trap = match Trap::parse(name) {
+            Some(trap) => trap,
+            None => {
+                eprintln!("trap: {}: invalid signal specification", name);
+                status = 1;
+                continue;
+            },
+        };
+
+        if let Trap::Signal(signal) = trap {
+            if signals::ignored_on_entry(signal) {
+                continue;
+            }
+            let result = match action {
+                Some("") => signals::ignore(signal),
+                Some(_) => signals::catch(signal),
+                None => signals::restore(signal),
+            };
+            if let Err(e) = result {
+                eprintln!("trap: {}: {}", name, e);
+                status = 1;
+                continue;
+            }
+        }
+
+        match action {
+            Some(action) => shell.set_trap(trap, action),
+            None => {
+                shell.remove_trap(trap);
+            },
+        }
+    }
+    status
+}
+
+fn print_history_entry(shell: &Shell, number: usize, entry: &HistoryEntry) {
+    let time = shell.lookup_var("HISTTIMEFORMAT")
+        .map(|format| timing::strftime(&format, entry.timestamp))
+        .unwrap_or_default();
+    println!("{:5}  {}{}", number, time, entry.line);
+}
+
+/// Turns a `history -d` operand into a history number, negative ones count back from the end.
+fn history_offset(shell: &Shell, offset: &str) -> Option<usize> {
+    match offset.parse::<isize>().ok()? {
+        offset if offset < 0 => (shell.history().last_number() + 1).checked_sub(offset.unsigned_abs()),
+        offset => Some(offset as usize),
+    }
+}
+
+/// `history --cwd [dir] --failed --since when` searches the HISTDB records,
+/// `history --compact` rewrites the file keeping the newest HISTDBSIZE of them.
+fn history_db(shell: &mut Shell, argv: &[&str]) -> i32 {
+    let mut query = Query::default();
+    let mut compact = false;
+
+    let mut args = argv[1..].iter().peekable();
+    while let Some(arg) = args.next() {
+        match *arg {
+            "--cwd" => {
+                let dir = match args.next_if(|dir| !dir.starts_with("--")) {
+                    Some(dir) => Path::new(dir).canonicalize().unwrap_or_else(|_| dir.into()),
+                    None => env::current_dir().unwrap_or_default(),
+                };
+                query.cwd = Some(dir.to_string_lossy().into_owned());
+            },
+            "--failed" => query.failed = true,
+            "--since" => match args.next().and_then(|since| histdb::parse_since(since)) {
+                Some(since) => query.since = Some(since),
+                None => {
+                    eprintln!("history: --since: expected seconds since the epoch, YYYY-MM-DD or an age like 2h");
+                    return 2;
+                },
+            },
+            "--compact" => compact = true,
+            _ => {
+                eprintln!("history: {}: invalid option", arg);
+                return 2;
+            },
+        }
+    }
+
+    let limit = shell.lookup_var("HISTDBSIZE").and_then(|size| size.parse().ok());
+    let time_format = shell.lookup_var("HISTTIMEFORMAT").unwrap_or_else(|| "%F %T  ".to_string());
+    let db = shell.history_db();
+    if !db.enabled() {
+        eprintln!("history: HISTDB not set");
+        return 1;
+    }
+
+    if compact {
+        return match db.compact(limit) {
+            Ok(_) => 0,
+            Err(e) => {
+                eprintln!("history: {}", e);
+                1
+            },
+        };
+    }
+
+    match db.query(&query) {
+        Ok(records) => {
+            for record in records {
+                println!("{}{:>3}  {:>8.3}s  {}  {}",
+                    timing::strftime(&time_format, record.timestamp),
+                    record.status,
+                    record.duration.as_secs_f64(),
+                    record.cwd,
+                    record.command);
+            }
+            0
+        },
+        Err(e) => {
+            eprintln!("history: {}", e);
+            1
+        },
+    }
+}
+
+/// `history [n]` lists the last n entries, `-c` clears them, `-d offset[-last]` deletes
+/// and `-w`, `-r` and `-a [file]` write, read or append to the history file.
+pub fn history(shell: &mut Shell, argv: &[&str]) -> i32 {
+    if argv.get(1).is_some_and(|arg| arg.starts_with("--")) {
+        return history_db(shell, argv);
+    }
+
+    let file = argv.get(2).map(Path::new);
+    let result = match argv.get(1).cloned() {
+        Some("-c") => {
+            shell.history_mut().clear();
+            Ok(())
+        },
+        Some("-d") => {
+            let operand = argv.get(2).cloned().unwrap_or("");
+            // a range is `first-last`, either of which may itself be negative
+            let dash = operand.char_indices()
+                .skip(1)
+                .find(|(i, c)| *c == '-' && operand.as_bytes()[i - 1].is_ascii_digit())
+                .map(|(i, _)| i);
+            let (first, last) = match dash {
+                Some(i) => (history_offset(shell, &operand[..i]), history_offset(shell, &operand[i + 1..])),
+                None => (history_offset(shell, operand), history_offset(shell, operand)),
+            };
+            match (first, last) {
+                (Some(first), Some(last)) if first <= last && shell.history().get(last).is_some() && shell.history().get(first).is_some() => {
+                    for _ in first..=last {
+                        shell.history_mut().delete(first);
+                    }
+                    Ok(())
+                },
+                _ => {
+                    eprintln!("history: {}: history position out of range", operand);
+                    return 1;
+                },
+            }
+        },
+        Some("-w") => {
+            let settings = shell.history_settings();
+            shell.history_mut().write(file, &settings)
+        },
+        Some("-r") => {
+            let settings = shell.history_settings();
+            shell.history_mut().read(file, &settings)
+        },
+        Some("-a") => {
+            let settings = shell.history_settings();
+            shell.history_mut().append_session(file, &settings)
+        },
+        count => {
+            let count = match count.map(|count| count.parse::<usize>()) {
+                Some(Ok(count)) => count,
+                Some(Err(_)) => {
+                    eprintln!("history: {}: numeric argument required", count.unwrap_or(""));
+                    return 2;
+                },
+                None => shell.history().len(),
+            };
+            let skip = shell.history().len().saturating_sub(count);
+            for (number, entry) in shell.history().numbered().skip(skip) {
+                print_history_entry(shell, number, entry);
+            }
+            Ok(())
+        },
+    };
+
+    match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("history: {}", e);
+            1
+        },
+    }
+}
+
+/// Resolves an `fc` operand: a history number, a negative offset from the previous
+/// command, or the most recent command starting with the given text.
+fn fc_number(shell: &Shell, spec: &str) -> Option<usize> {
+    let end = shell.history_end();
+    let first = shell.history().first_number();
+    if end < first {
+        return None;
+    }
+    let number = match spec.parse::<isize>() {
+        Ok(offset) if offset < 0 => (end + 1).saturating_sub(offset.unsigned_abs()).max(first),
+        Ok(0) => end,
+        Ok(number) => (number as usize).clamp(first, end),
+        Err(_) => shell.history().find_prefix(spec, end)?,
+    };
+    shell.history().get(number).map(|_| number)
+}
+
+/// Echoes and runs lines produced by `fc`, recording them in the history as they go.
+pub fn fc_run(shell: &mut Shell, lines: &str) {
+    for line in lines.lines().filter(|line| !line.trim().is_empty()) {
+        let line = format!("{}\n", line);
+        print!("{}", line);
+        shell.add_history(&line);
+        crate::eval(&line, shell);
+    }
+}
+
+/// Creates a file in the temporary directory that only this user can read, never
+/// opening one that is already there so a planted symlink cannot redirect it.
+fn create_temp_file() -> io::Result<(PathBuf, fs::File)> {
+    let mut attempts = 0;
+    loop {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.subsec_nanos());
+        let path = env::temp_dir().join(format!("rshell-fc-{}-{:x}", std::process::id(), nanos));
+        match fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists && attempts < 100 => attempts += 1,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Opens `lines` in `editor` and returns what was saved, None if the editor failed.
+pub fn fc_edit(shell: &Shell, editor: &str, lines: &str) -> io::Result<Option<String>> {
+    let (path, mut file) = create_temp_file()?;
+    let written = file.write_all(lines.as_bytes());
+    drop(file);
+    if let Err(e) = written {
+        let _ = fs::remove_file(&path);
+        return Err(e);
+    }
+
+    let mut words = editor.split_whitespace();
+    let mut command = Command::new(words.next().unwrap_or("vi"));
+    command.args(words).arg(&path);
+    let status = signals::reset_in_child(&mut command, shell).status();
+
+    let edited = fs::read_to_string(&path);
+    let _ = fs::remove_file(&path);
+    match status {
+        Ok(status) if status.success() => Ok(Some(edited?)),
+        Ok(_) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// POSIX `fc`: `-l` lists a range, `-s [old=new] [first]` re-runs a command with
+/// substitutions and otherwise the range is opened in an editor and the result run.
+pub fn fc(shell: &mut Shell, argv: &[&str]) -> i32 {
+    let mut list = false;
+    let mut numbers = true;
+    let mut reverse = false;
+    let mut substitute = false;
+    let mut editor = None;
+
+    let mut args = argv[1..].iter().peekable();
+    while let Some(arg) = args.peek() {
+        // "-1" is an operand, not a flag
+        if !arg.starts_with('-') || arg.len() == 1 || arg[1..].parse::<isize>().is_ok() {
+            break;
+        }
+        let arg = args.next().unwrap();
+        if *arg == "--" {
+            break;
+        }
+        for flag in arg[1..].chars() {
+            match flag {
+                'l' => list = true,
+                'n' => numbers = false,
+                'r' => reverse = true,
+                's' => substitute = true,
+                'e' => match args.next() {
+                    Some(&"-") => substitute = true,
+                    Some(name) => editor = Some(name.to_string()),
+                    None => {
+                        eprintln!("fc: -e: option requires an argument");
+                        return 2;
+                    },
+                },
+                _ => {
+                    eprintln!("fc: -{}: invalid option", flag);
+                    eprintln!("fc: usage: fc [-e ename] [-lnr] [first] [last] or fc -s [pat=rep] [command]");
+                    return 2;
+                },
+            }
+        }
+    }
+    let operands: Vec<&str> = args.cloned().collect();
+
+    if substitute {
+        let (patterns, spec): (Vec<&str>, Vec<&str>) = operands.iter().partition(|operand| operand.contains('='));
+        let number = match spec.first() {
+            Some(spec) => fc_number(shell, spec),
+            None => fc_number(shell, "-1"),
+        };
+        let mut line = match number.and_then(|number| shell.history().get(number)) {
+            Some(entry) => entry.line.clone(),
+            None => {
+                eprintln!("fc: no command found");
+                return 1;
+            },
+        };
+        for pattern in patterns {
+            if let Some((old, new)) = pattern.split_once('=') {
+                line = line.replace(old, new);
+            }
+        }
+        fc_run(shell, &line);
+        return shell.last_status();
+    }
+
+    let default_first = if list { "-16" } else { "-1" };
+    let first = fc_number(shell, operands.first().cloned().unwrap_or(default_first));
+    let last = match operands.get(1) {
+        Some(spec) => fc_number(shell, spec),
+        None if list => fc_number(shell, "-1"),
+        None => first,
+    };
+    let (mut first, mut last) = match (first, last) {
+        (Some(first), Some(last)) => (first, last),
+        _ => {
+            eprintln!("fc: history specification out of range");
+            return 1;
+        },
+    };
+    if first > last {
+        std::mem::swap(&mut first, &mut last);
+        reverse = !reverse;
+    }
+
+    let mut range: Vec<(usize, String)> = (first..=last)
+        .filter_map(|number| shell.history().get(number).map(|entry| (number, entry.line.clone())))
+        .collect();
+    if reverse {
+        range.reverse();
+    }
+
+    if list {
+        for (number, line) in range {
+            if numbers {
+                println!("{}\t {}", number, line);
+            }
+            else {
+                println!("\t {}", line);
+            }
+        }
+        return 0;
+    }
+
+    let editor = editor
+        .or_else(|| shell.lookup_var("FCEDIT"))
+        .or_else(|| shell.lookup_var("EDITOR"))
+        .unwrap_or_else(|| "vi".to_string());
+    let lines: String = range.iter().map(|(_, line)| format!("{}\n", line)).collect();
+    match fc_edit(shell, &editor, &lines) {
+        Ok(Some(edited)) => {
+            fc_run(shell, &edited);
+            shell.last_status()
+        },
+        Ok(None) => 1,
+        Err(e) => {
+            eprintln!("fc: {}", e);
+            1
+        },
+    }
+}
+
+/// Reads the options `complete` and `compgen` share into a spec. Flags can be clustered and
+/// options with a value take it attached or as the next word. `flags` are the extra
+/// single letter flags the builtin accepts, which end up in the returned string.
+fn parse_spec(name: &str, argv: &[&str], flags: &str) -> Result<(CompletionSpec, String, Vec<String>), i32> {
+    let mut spec = CompletionSpec::default();
+    let mut seen = String::new();
+    let mut args = argv[1..].iter().map(|arg| unquote(arg));
+    let mut rest = Vec::new();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            rest.extend(args.map(str::to_string));
+            break;
+        }
+        if !arg.starts_with('-') || arg.len() == 1 {
+            rest.push(arg.to_string());
+            rest.extend(args.map(str::to_string));
+            break;
+        }
+
+        let chars = arg[1..].char_indices();
+        for (i, flag) in chars {
+            if !"oAGWFCXPS".contains(flag) {
+                if let Some(action) = Action::from_flag(flag) {
+                    spec.actions.push(action);
+                }
+                else if flags.contains(flag) {
+                    seen.push(flag);
+                }
+                else {
+                    eprintln!("{}: -{}: invalid option", name, flag);
+                    return Err(2);
+                }
+                continue;
+            }
+
+            let attached = &arg[i + 2..];
+            let value = if attached.is_empty() { args.next() } else { Some(attached) };
+            let value = match value {
+                Some(value) => value.to_string(),
+                None => {
+                    eprintln!("{}: -{}: option requires an argument", name, flag);
+                    return Err(2);
+                },
+            };
+            match flag {
+                'o' => if !spec.options.set(&value, true) {
+                    eprintln!("{}: {}: invalid option name", name, value);
+                    return Err(2);
+                },
+                'A' => match Action::parse(&value) {
+                    Some(action) => spec.actions.push(action),
+                    None => {
+                        eprintln!("{}: {}: invalid action name", name, value);
+                        return Err(2);
+                    },
+                },
+                'G' => spec.glob = Some(value),
+                'W' => spec.words = Some(value),
+                'F' => spec.function = Some(value),
+                'C' => spec.command = Some(value),
+                'X' => spec.filter = Some(value),
+                'P' => spec.prefix = value,
+                _ => spec.suffix = value,
+            }
+            break;
+        }
+    }
+    Ok((spec, seen, rest))
+}
+
+/// `complete [-pr] [options] [name...]` sets, prints or removes the completion spec of commands.
+pub fn complete(shell: &mut Shell, argv: &[&str]) -> i32 {
+    let (spec, flags, names) = match parse_spec("complete", argv, "pr") {
+        Ok(parsed) => parsed,
+        Err(status) => return status,
+    };
+
+    if flags.contains('r') {
+        if names.is_empty() {
+            shell.clear_completions();
+            return 0;
+        }
+        let mut status = 0;
+        for name in names.iter() {
+            if !shell.remove_completion(name) {
+                eprintln!("complete: {}: no completion specification", name);
+                status = 1;
+            }
+        }
+        return status;
+    }
+
+    if flags.contains('p') || (names.is_empty() && spec == CompletionSpec::default()) {
+        if names.is_empty() {
+            for (name, spec) in shell.completions() {
+                println!("complete {} {}", spec.to_args(), name);
+            }
+            return 0;
+        }
+        let mut status = 0;
+        for name in names.iter() {
+            match shell.completion(name) {
+                Some(spec) => println!("complete {} {}", spec.to_args(), name),
+                None => {
+                    eprintln!("complete: {}: no completion specification", name);
+                    status = 1;
+                },
+            }
+        }
+        return status;
+    }
+
+    if names.is_empty() {
+        eprintln!("complete: usage: complete [-pr] [-abcdefjkuv] [-o option] [-A action] [-G globpat] [-W wordlist] [-F function] [-C command] [-X filterpat] [-P prefix] [-S suffix] [name ...]");
+        return 2;
+    }
+    for name in names.iter() {
+        shell.set_completion(name, spec.clone());
+    }
+    0
+}
+
+/// `compgen [options] [word]` prints what a spec with the same options would complete `word` to.
+pub fn compgen(shell: &mut Shell, argv: &[&str]) -> i32 {
+    let (spec, _, rest) = match parse_spec("compgen", argv, "") {
+        Ok(parsed) => parsed,
+        Err(status) => return status,
+    };
+    let word = rest.first().cloned().unwrap_or_default();
+
+    let mut words = spec.generate(shell, &word);
+    let command_line = CommandLine {
+        words: vec!["compgen".to_string(), word.clone()],
+        current: 1,
+        start: 0,
+        redirect: false,
+    };
+    let line = format!("compgen {}", word);
+    if let Some(function) = &spec.function {
+        words.extend(complete::call_function(shell, function, &command_line, &line, line.len()));
+    }
+    if let Some(command) = &spec.command {
+        words.extend(complete::run_command(command, &command_line, &line, line.len()));
+    }
+    let words = spec.finish(words, &word);
+
+    for word in words.iter() {
+        println!("{}", word);
+    }
+    if words.is_empty() { 1 } else { 0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::HistorySettings;
+
+    fn shell_with_history(lines: &[&str]) -> Shell {
+        let mut shell = Shell::new(Path::new(""));
+        for line in lines {
+            shell.history_mut().add(line, &HistorySettings::default());
+        }
+        shell
+    }
+
+    #[test]
+    fn fc_operands_resolve_to_history_numbers() {
+        let shell = shell_with_history(&["echo one", "ls", "echo two", "make"]);
+        assert_eq!(fc_number(&shell, "1"), Some(1));
+        assert_eq!(fc_number(&shell, "-1"), Some(4));
+        assert_eq!(fc_number(&shell, "-3"), Some(2));
+        assert_eq!(fc_number(&shell, "0"), Some(4));
+        // numbers past either end are taken as the end
+        assert_eq!(fc_number(&shell, "99"), Some(4));
+        assert_eq!(fc_number(&shell, "-99"), Some(1));
+        // text is the newest command starting with it
+        assert_eq!(fc_number(&shell, "echo"), Some(3));
+        assert_eq!(fc_number(&shell, "l"), Some(2));
+        assert_eq!(fc_number(&shell, "cargo"), None);
+        assert_eq!(fc_number(&shell_with_history(&[]), "-1"), None);
+        assert_eq!(fc_number(&shell_with_history(&[]), "1"), None);
+    }
+}
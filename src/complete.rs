@@ -0,0 +1,783 @@
+mod options;
+
+use crate::builtin;
+use crate::glob;
+use crate::parser::unquote;
+use crate::shell::{Shell,ShellOptions};
+use crate::variable::Value;
+use nix::sys::signal::Signal;
+use std::collections::{BTreeSet,HashMap};
+use std::env;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::{Command,Stdio};
+
+/// Reserved words completed in command position along with the builtins.
+const KEYWORDS: &[&str] = &["time", "function"];
+/// Chars that make the parser split a word or the shell treat it specially.
+const SPECIAL: &str = " \t\n'\"\\$&|;<>()*?[]#!{}=`";
+
+
+/// One way to complete the word under the cursor.
+#[derive(Debug,Clone,PartialEq,Eq,PartialOrd,Ord)]
+pub struct Candidate {
+    /// What replaces the word, quoted where needed.
+    pub replacement: String,
+    /// What the menu shows.
+    pub display: String,
+    /// Whether the word is finished and a space goes after it, false for directories.
+    pub finished: bool,
+    /// What the menu shows next to it, empty for most candidates.
+    pub description: String,
+}
+
+impl Candidate {
+    fn new(replacement: String, display: String, finished: bool) -> Candidate {
+        Candidate { replacement, display, finished, description: String::new() }
+    }
+
+    fn with_description(mut self, description: String) -> Candidate {
+        self.description = description;
+        self
+    }
+}
+
+/// The candidates for the word that starts at `start`, a char index into the line.
+#[derive(Debug,Default)]
+pub struct Completion {
+    pub start: usize,
+    pub candidates: Vec<Candidate>,
+}
+
+/// The simple command the cursor is in, split into words as they were typed.
+#[derive(Debug)]
+pub struct CommandLine {
+    /// Words up to and including the one under the cursor, leading assignments left out.
+    pub words: Vec<String>,
+    /// Index in `words` of the word under the cursor.
+    pub current: usize,
+    /// Where the word under the cursor starts, in chars.
+    pub start: usize,
+    /// The word under the cursor follows `<` or `>`.
+    pub redirect: bool,
+}
+
+fn is_name(text: &str) -> bool {
+    !text.is_empty()
+        && !text.starts_with(|c: char| c.is_ascii_digit())
+        && text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// `name=` and whatever follows, the way a leading assignment looks.
+fn assignment(word: &str) -> Option<usize> {
+    word.find('=').filter(|eq| is_name(&word[..*eq]))
+}
+
+impl CommandLine {
+    /// Splits the line before the cursor into words, starting over after `|`, `&`, `;` and parentheses.
+    pub fn parse(line: &[char], cursor: usize) -> CommandLine {
+        let mut words: Vec<String> = Vec::new();
+        let mut word = String::new();
+        let mut start: Option<usize> = None;
+        let mut quoted = false;
+        let mut redirect = false;
+
+        let finish = |words: &mut Vec<String>, word: &mut String, start: &mut Option<usize>, redirect: &mut bool| {
+            if start.take().is_none() {
+                return;
+            }
+            let word = std::mem::take(word);
+            if *redirect {
+                // the target of a redirection is not one of the command's words
+                *redirect = false;
+            }
+            else if !(words.is_empty() && assignment(&word).is_some()) {
+                words.push(word);
+            }
+        };
+
+        for (i, &c) in line[..cursor.min(line.len())].iter().enumerate() {
+            if quoted {
+                word.push(c);
+                quoted = c != '\'';
+                continue;
+            }
+            match c {
+                ' ' | '\t' | '\n' => finish(&mut words, &mut word, &mut start, &mut redirect),
+                '|' | '&' | ';' | '(' | ')' => {
+                    finish(&mut words, &mut word, &mut start, &mut redirect);
+                    words.clear();
+                    redirect = false;
+                },
+                '<' | '>' => {
+                    finish(&mut words, &mut word, &mut start, &mut redirect);
+                    redirect = true;
+                },
+                _ => {
+                    start.get_or_insert(i);
+                    word.push(c);
+                    quoted = c == '\'';
+                },
+            }
+        }
+
+        let current = words.len();
+        words.push(word);
+        CommandLine { words, current, start: start.unwrap_or(cursor), redirect }
+    }
+
+    #[inline]
+    pub fn word(&self) -> &str {
+        &self.words[self.current]
+    }
+
+    /// Whether the word under the cursor names the command, `time` and its `-p` do not count.
+    pub fn command_position(&self) -> bool {
+        !self.redirect && self.words[..self.current].iter().all(|word| word == "time" || word == "-p")
+    }
+}
+
+/// Quotes `text` in single quotes if the parser would otherwise split it or the shell
+/// expand it. A leading `~` or `~user` stays outside the quotes so it is still expanded.
+pub fn quote(text: &str) -> String {
+    let (tilde, rest) = match text.strip_prefix('~') {
+        Some(_) => text.split_at(text.find('/').unwrap_or(text.len())),
+        None => ("", text),
+    };
+    if !rest.contains(|c| SPECIAL.contains(c)) {
+        return text.to_string();
+    }
+    format!("{}'{}'", tilde, rest.replace('\'', "'\\''"))
+}
+
+fn home_dir(user: &str) -> Option<String> {
+    if user.is_empty() {
+        return env::var("HOME").ok();
+    }
+    let passwd = fs::read_to_string("/etc/passwd").ok()?;
+    passwd.lines()
+        .map(|line| line.split(':').collect::<Vec<&str>>())
+        .find(|fields| fields.len() > 5 && fields[0] == user)
+        .map(|fields| fields[5].to_string())
+}
+
+pub fn is_executable(path: &Path) -> bool {
+    fs::metadata(path).is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+}
+
+/// Whether `name` runs something: a builtin, reserved word, alias, function or a program,
+/// found on the PATH unless the name has a slash.
+pub fn is_command(shell: &Shell, name: &str) -> bool {
+    if name.contains('/') {
+        return is_executable(Path::new(name));
+    }
+    if builtin::BUILTINS.contains(&name) || KEYWORDS.contains(&name) {
+        return true;
+    }
+    if shell.alias_names().iter().chain(shell.function_names().iter()).any(|known| known == name) {
+        return true;
+    }
+    let path = shell.lookup_var("PATH").unwrap_or_default();
+    !name.is_empty() && path.split(':')
+        .filter(|dir| !dir.is_empty())
+        .any(|dir| is_executable(&Path::new(dir).join(name)))
+}
+
+/// The names in directories completion has read. The line editor keeps them while a line
+/// is edited, so suggesting a completion after every key does not read them again.
+#[derive(Debug,Default)]
+pub struct Listings {
+    directories: HashMap<String, Option<Vec<String>>>,
+}
+
+impl Listings {
+    /// The names in `dir`, None if it cannot be read.
+    fn names(&mut self, dir: &str) -> Option<&[String]> {
+        self.directories.entry(dir.to_string())
+            .or_insert_with(|| {
+                let entries = fs::read_dir(dir).ok()?;
+                Some(entries.flatten().map(|entry| entry.file_name().to_string_lossy().into_owned()).collect())
+            })
+            .as_deref()
+    }
+}
+
+/// File names completing `word`, relative to the working directory unless it says otherwise.
+pub fn complete_path(word: &str, directories_only: bool, listings: &mut Listings) -> Vec<Candidate> {
+    let raw = unquote(word);
+    let (dir, prefix) = match raw.rfind('/') {
+        Some(slash) => raw.split_at(slash + 1),
+        None => ("", raw.as_str()),
+    };
+
+    let search = match dir.strip_prefix('~') {
+        Some(rest) => {
+            let (user, rest) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+            match home_dir(user) {
+                Some(home) => format!("{}{}", home, rest),
+                None => return Vec::new(),
+            }
+        },
+        None if dir.is_empty() => ".".to_string(),
+        None => dir.to_string(),
+    };
+    let names = match listings.names(&search) {
+        Some(names) => names,
+        None => return Vec::new(),
+    };
+
+    let mut candidates = Vec::new();
+    for name in names {
+        if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+            continue;
+        }
+        let is_dir = Path::new(&search).join(name).is_dir();
+        if directories_only && !is_dir {
+            continue;
+        }
+        let slash = if is_dir { "/" } else { "" };
+        candidates.push(Candidate::new(
+            format!("{}{}", quote(&format!("{}{}", dir, name)), slash),
+            format!("{}{}", name, slash),
+            !is_dir));
+    }
+    candidates
+}
+
+/// Builtins, reserved words, aliases, functions and programs on the PATH.
+pub fn complete_command(shell: &Shell, prefix: &str, listings: &mut Listings) -> Vec<Candidate> {
+    let mut names: BTreeSet<String> = BTreeSet::new();
+    names.extend(builtin::BUILTINS.iter().chain(KEYWORDS).map(|name| name.to_string()));
+    names.extend(shell.alias_names());
+    names.extend(shell.function_names());
+
+    let path = shell.lookup_var("PATH").unwrap_or_default();
+    for dir in path.split(':').filter(|dir| !dir.is_empty()) {
+        for name in listings.names(dir).unwrap_or_default() {
+            if name.starts_with(prefix) && is_executable(&Path::new(dir).join(name)) {
+                names.insert(name.clone());
+            }
+        }
+    }
+
+    names.into_iter()
+        .filter(|name| name.starts_with(prefix))
+        .map(|name| Candidate::new(quote(&name), name, true))
+        .collect()
+}
+
+/// `$name` or `${name` completed from the shell's variables and the environment.
+pub fn complete_variable(shell: &Shell, word: &str) -> Vec<Candidate> {
+    let (prefix, braced) = match word.strip_prefix("${") {
+        Some(prefix) => (prefix, true),
+        None => (word.strip_prefix('$').unwrap_or(word), false),
+    };
+    let mut names: BTreeSet<String> = shell.var_names().into_iter().collect();
+    names.extend(env::vars_os().map(|(name, _)| name.to_string_lossy().into_owned()));
+
+    names.into_iter()
+        .filter(|name| name.starts_with(prefix))
+        .map(|name| {
+            let replacement = if braced { format!("${{{}}}", name) } else { format!("${}", name) };
+            Candidate::new(replacement, name, true)
+        })
+        .collect()
+}
+
+/// `~user` from the password file.
+pub fn complete_user(word: &str) -> Vec<Candidate> {
+    let prefix = word.strip_prefix('~').unwrap_or(word);
+    let passwd = fs::read_to_string("/etc/passwd").unwrap_or_default();
+    let users: BTreeSet<&str> = passwd.lines()
+        .filter_map(|line| line.split(':').next())
+        .filter(|user| !user.is_empty() && user.starts_with(prefix))
+        .collect();
+    users.into_iter()
+        .map(|user| Candidate::new(format!("~{}/", user), format!("~{}", user), false))
+        .collect()
+}
+
+/// `%n` for the jobs whose number or command starts with what follows the `%`.
+pub fn complete_job(shell: &Shell, word: &str) -> Vec<Candidate> {
+    let prefix = word.strip_prefix('%').unwrap_or(word);
+    shell.job_specs().into_iter()
+        .filter(|(id, pipeline, _)| id.to_string().starts_with(prefix) || pipeline.starts_with(prefix))
+        .map(|(id, pipeline, _)| Candidate::new(format!("%{}", id), format!("%{}  {}", id, pipeline.trim()), true))
+        .collect()
+}
+
+/// What `complete -A` and `compgen -A` can generate.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Action {
+    Alias,
+    Builtin,
+    Command,
+    Directory,
+    Export,
+    File,
+    Function,
+    Hostname,
+    Job,
+    Keyword,
+    Running,
+    Setopt,
+    Signal,
+    Stopped,
+    User,
+    Variable,
+}
+
+impl Action {
+    const NAMES: &'static [(&'static str, Action)] = &[
+        ("alias", Action::Alias),
+        ("builtin", Action::Builtin),
+        ("command", Action::Command),
+        ("directory", Action::Directory),
+        ("export", Action::Export),
+        ("file", Action::File),
+        ("function", Action::Function),
+        ("hostname", Action::Hostname),
+        ("job", Action::Job),
+        ("keyword", Action::Keyword),
+        ("running", Action::Running),
+        ("setopt", Action::Setopt),
+        ("signal", Action::Signal),
+        ("stopped", Action::Stopped),
+        ("user", Action::User),
+        ("variable", Action::Variable),
+    ];
+
+    pub fn parse(name: &str) -> Option<Action> {
+        Action::NAMES.iter().find(|(n, _)| *n == name).map(|(_, action)| *action)
+    }
+
+    pub fn name(self) -> &'static str {
+        Action::NAMES.iter().find(|(_, action)| *action == self).map_or("", |(name, _)| name)
+    }
+
+    /// The single letter options that stand for an action, `-c` for `-A command` and so on.
+    pub fn from_flag(flag: char) -> Option<Action> {
+        Some(match flag {
+            'a' => Action::Alias,
+            'b' => Action::Builtin,
+            'c' => Action::Command,
+            'd' => Action::Directory,
+            'e' => Action::Export,
+            'f' => Action::File,
+            'j' => Action::Job,
+            'k' => Action::Keyword,
+            'u' => Action::User,
+            'v' => Action::Variable,
+            _ => return None,
+        })
+    }
+
+    /// The names this action produces that start with `word`.
+    fn generate(self, shell: &Shell, word: &str) -> Vec<String> {
+        let raw = |candidates: Vec<Candidate>| -> Vec<String> {
+            candidates.into_iter()
+                .map(|candidate| {
+                    let text = unquote(&candidate.replacement);
+                    match text.strip_suffix('/') {
+                        Some(dir) if !dir.is_empty() && !candidate.finished => dir.to_string(),
+                        _ => text,
+                    }
+                })
+                .collect()
+        };
+        let names: Vec<String> = match self {
+            Action::Alias => shell.alias_names(),
+            Action::Builtin => builtin::BUILTINS.iter().map(|name| name.to_string()).collect(),
+            Action::Command => raw(complete_command(shell, word, &mut Listings::default())),
+            Action::Directory => raw(complete_path(word, true, &mut Listings::default())),
+            Action::Export => env::vars_os().map(|(name, _)| name.to_string_lossy().into_owned()).collect(),
+            Action::File => raw(complete_path(word, false, &mut Listings::default())),
+            Action::Function => shell.function_names(),
+            Action::Hostname => fs::read_to_string("/etc/hosts").unwrap_or_default()
+                .lines()
+                .map(|line| line.split('#').next().unwrap_or(""))
+                .flat_map(|line| line.split_whitespace().skip(1).map(str::to_string).collect::<Vec<String>>())
+                .collect(),
+            Action::Job | Action::Running | Action::Stopped => shell.job_specs().into_iter()
+                .filter(|(_, _, stopped)| match self {
+                    Action::Running => !stopped,
+                    Action::Stopped => *stopped,
+                    _ => true,
+                })
+                .map(|(_, pipeline, _)| pipeline.split_whitespace().next().unwrap_or("").to_string())
+                .collect(),
+            Action::Keyword => KEYWORDS.iter().map(|name| name.to_string()).collect(),
+            Action::Setopt => ShellOptions::NAMES.iter().map(|name| name.to_string()).collect(),
+            Action::Signal => Signal::iterator().map(|signal| signal.as_str().to_string()).collect(),
+            Action::User => complete_user(word).into_iter().map(|candidate| candidate.display[1..].to_string()).collect(),
+            Action::Variable => {
+                let mut names = shell.var_names();
+                names.extend(env::vars_os().map(|(name, _)| name.to_string_lossy().into_owned()));
+                names
+            },
+        };
+        names.into_iter().filter(|name| name.starts_with(word)).collect()
+    }
+}
+
+/// The `-o` options of a completion spec.
+#[derive(Debug,Default,Clone,PartialEq,Eq)]
+pub struct SpecOptions {
+    /// Fall back to the shell's own completion when the spec comes up empty.
+    pub bashdefault: bool,
+    /// Fall back to file names when the spec comes up empty.
+    pub default: bool,
+    /// Fall back to directory names when the spec comes up empty.
+    pub dirnames: bool,
+    /// The words are file names: quote them and mark directories.
+    pub filenames: bool,
+    pub noquote: bool,
+    pub nospace: bool,
+    /// Add directory names to whatever the spec produces.
+    pub plusdirs: bool,
+}
+
+impl SpecOptions {
+    pub const NAMES: &'static [&'static str] = &["bashdefault", "default", "dirnames", "filenames", "noquote", "nospace", "plusdirs"];
+
+    /// Returns false if `name` is not a known option.
+    pub fn set(&mut self, name: &str, value: bool) -> bool {
+        match name {
+            "bashdefault" => self.bashdefault = value,
+            "default" => self.default = value,
+            "dirnames" => self.dirnames = value,
+            "filenames" => self.filenames = value,
+            "noquote" => self.noquote = value,
+            "nospace" => self.nospace = value,
+            "plusdirs" => self.plusdirs = value,
+            _ => return false,
+        }
+        true
+    }
+
+    pub fn get(&self, name: &str) -> bool {
+        match name {
+            "bashdefault" => self.bashdefault,
+            "default" => self.default,
+            "dirnames" => self.dirnames,
+            "filenames" => self.filenames,
+            "noquote" => self.noquote,
+            "nospace" => self.nospace,
+            "plusdirs" => self.plusdirs,
+            _ => false,
+        }
+    }
+}
+
+/// How to complete the arguments of one command, set up by `complete`.
+#[derive(Debug,Default,Clone,PartialEq,Eq)]
+pub struct CompletionSpec {
+    pub actions: Vec<Action>,
+    pub options: SpecOptions,
+    /// `-G`: file names matching a pattern.
+    pub glob: Option<String>,
+    /// `-W`: a list of words.
+    pub words: Option<String>,
+    /// `-F`: a shell function that fills COMPREPLY.
+    pub function: Option<String>,
+    /// `-C`: a command that prints one completion per line.
+    pub command: Option<String>,
+    /// `-X`: a pattern of words to drop, or with a leading `!` to keep.
+    pub filter: Option<String>,
+    pub prefix: String,
+    pub suffix: String,
+}
+
+fn single_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
+}
+
+impl CompletionSpec {
+    /// The options that recreate the spec, as `complete -p` prints them.
+    pub fn to_args(&self) -> String {
+        let mut args = Vec::new();
+        for name in SpecOptions::NAMES.iter().filter(|name| self.options.get(name)) {
+            args.push(format!("-o {}", name));
+        }
+        for action in self.actions.iter() {
+            args.push(format!("-A {}", action.name()));
+        }
+        let quoted = [("-G", &self.glob), ("-W", &self.words), ("-C", &self.command), ("-X", &self.filter)];
+        for (flag, value) in quoted {
+            if let Some(value) = value {
+                args.push(format!("{} {}", flag, single_quote(value)));
+            }
+        }
+        if !self.prefix.is_empty() {
+            args.push(format!("-P {}", single_quote(&self.prefix)));
+        }
+        if !self.suffix.is_empty() {
+            args.push(format!("-S {}", single_quote(&self.suffix)));
+        }
+        if let Some(function) = &self.function {
+            args.push(format!("-F {}", function));
+        }
+        args.join(" ")
+    }
+
+    /// Everything the spec produces for `word` apart from `-F` and `-C`.
+    pub fn generate(&self, shell: &Shell, word: &str) -> Vec<String> {
+        let mut words = Vec::new();
+        for action in self.actions.iter() {
+            words.extend(action.generate(shell, word));
+        }
+        if let Some(pattern) = &self.glob {
+            words.extend(Action::File.generate(shell, "").into_iter().filter(|name| glob::matches(pattern, name)));
+        }
+        if let Some(list) = &self.words {
+            words.extend(list.split_whitespace().filter(|candidate| candidate.starts_with(word)).map(str::to_string));
+        }
+        words
+    }
+
+    /// Applies `-X`, where `&` stands for the word being completed, then `-P` and `-S`.
+    pub fn finish(&self, words: Vec<String>, word: &str) -> Vec<String> {
+        let words = match &self.filter {
+            Some(filter) => {
+                let (keep, pattern) = match filter.strip_prefix('!') {
+                    Some(pattern) => (true, pattern),
+                    None => (false, filter.as_str()),
+                };
+                let pattern = pattern.replace('&', word);
+                words.into_iter().filter(|candidate| glob::matches(&pattern, candidate) == keep).collect()
+            },
+            None => words,
+        };
+        words.into_iter().map(|candidate| format!("{}{}{}", self.prefix, candidate, self.suffix)).collect()
+    }
+}
+
+/// Runs a `-F` function the way bash does, with the command, the word and the word before
+/// it as arguments and COMP_WORDS, COMP_CWORD, COMP_LINE and COMP_POINT set, and returns COMPREPLY.
+pub fn call_function(shell: &mut Shell, function: &str, command_line: &CommandLine, line: &str, point: usize) -> Vec<String> {
+    if !shell.function_names().iter().any(|name| name == function) {
+        eprintln!("\nrshell: completion: function `{}' not found", function);
+        return Vec::new();
+    }
+
+    let words = &command_line.words;
+    let previous = command_line.current.checked_sub(1).map_or("", |i| words[i].as_str());
+    shell.set_var("COMP_WORDS", Value::Array(words.clone()));
+    shell.set_var("COMP_CWORD", Value::String(command_line.current.to_string()));
+    shell.set_var("COMP_LINE", Value::String(line.to_string()));
+    shell.set_var("COMP_POINT", Value::String(point.to_string()));
+    shell.set_var("COMP_TYPE", Value::String("9".to_string()));
+    shell.set_var("COMPREPLY", Value::Array(Vec::new()));
+
+    let call = format!("{} {} {} {}\n", function, single_quote(&words[0]), single_quote(command_line.word()), single_quote(previous));
+    crate::eval(&call, shell);
+
+    match shell.get_var("COMPREPLY").as_deref().map(|var| var.value()) {
+        Some(Some(Value::Array(reply))) => reply.clone(),
+        Some(Some(Value::String(reply))) => vec![reply.clone()],
+        _ => Vec::new(),
+    }
+}
+
+/// Runs a `-C` command with the command, the word and the word before it as arguments
+/// and COMP_LINE and COMP_POINT in its environment. Each line it prints is a completion.
+pub fn run_command(command: &str, command_line: &CommandLine, line: &str, point: usize) -> Vec<String> {
+    let words = &command_line.words;
+    let previous = command_line.current.checked_sub(1).map_or("", |i| words[i].as_str());
+    let mut program = command.split_whitespace();
+    let output = Command::new(program.next().unwrap_or(""))
+        .args(program)
+        .args([words[0].as_str(), command_line.word(), previous])
+        .env("COMP_LINE", line)
+        .env("COMP_POINT", point.to_string())
+        .env("COMP_TYPE", "9")
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output();
+    match output {
+        Ok(output) => String::from_utf8_lossy(&output.stdout).lines().map(str::to_string).collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Candidates for the word under the cursor from the spec of the command it belongs to.
+fn complete_from_spec(shell: &mut Shell, spec: &CompletionSpec, command_line: &CommandLine, line: &[char], cursor: usize) -> Vec<Candidate> {
+    let word = unquote(command_line.word());
+    let text: String = line.iter().collect();
+    let point: usize = line[..cursor].iter().map(|c| c.len_utf8()).sum();
+
+    let mut words = spec.generate(shell, &word);
+    if let Some(function) = &spec.function {
+        words.extend(call_function(shell, function, command_line, &text, point));
+    }
+    if let Some(command) = &spec.command {
+        words.extend(run_command(command, command_line, &text, point));
+    }
+    let words = spec.finish(words, &word);
+
+    let filenames = spec.options.filenames || spec.actions.iter().any(|action| matches!(action, Action::File | Action::Directory));
+    let mut candidates: Vec<Candidate> = words.into_iter()
+        .map(|word| {
+            if !filenames {
+                return Candidate::new(word.clone(), word, !spec.options.nospace);
+            }
+            let is_dir = expand_tilde(&word).is_some_and(|path| Path::new(&path).is_dir());
+            let slash = if is_dir && !word.ends_with('/') { "/" } else { "" };
+            let name = word.trim_end_matches('/').rsplit('/').next().unwrap_or(&word).to_string();
+            let replacement = if spec.options.noquote { word.clone() } else { quote(&word) };
+            Candidate::new(format!("{}{}", replacement, slash), format!("{}{}", name, slash), !is_dir && !spec.options.nospace)
+        })
+        .collect();
+    if spec.options.plusdirs {
+        candidates.extend(complete_path(command_line.word(), true, &mut Listings::default()));
+    }
+    candidates
+}
+
+pub fn expand_tilde(path: &str) -> Option<String> {
+    let rest = match path.strip_prefix('~') {
+        Some(rest) => rest,
+        None => return Some(path.to_string()),
+    };
+    let (user, rest) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+    home_dir(user).map(|home| format!("{}{}", home, rest))
+}
+
+/// Where a `$name` being typed starts in `word`, in bytes.
+fn variable_start(word: &str) -> Option<usize> {
+    let dollar = word.rfind('$')?;
+    let name = word[dollar + 1..].strip_prefix('{').unwrap_or(&word[dollar + 1..]);
+    let quoted = word[..dollar].matches('\'').count() % 2 == 1;
+    if quoted || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return None;
+    }
+    Some(dollar)
+}
+
+/// What the word completes to whatever the command is: variables, users, jobs, paths
+/// assigned in front of the command and the command itself. None for its arguments.
+fn complete_common(shell: &Shell, command_line: &CommandLine, listings: &mut Listings) -> Option<Completion> {
+    let word = command_line.word();
+    let start = command_line.start;
+    let chars_before = |byte: usize| start + word[..byte].chars().count();
+
+    if let Some(dollar) = variable_start(word) {
+        return Some(Completion { start: chars_before(dollar), candidates: complete_variable(shell, &word[dollar..]) });
+    }
+    if word.starts_with('~') && !word.contains('/') {
+        return Some(Completion { start, candidates: complete_user(word) });
+    }
+    if word.starts_with('%') {
+        return Some(Completion { start, candidates: complete_job(shell, word) });
+    }
+    // the value of an assignment in front of the command is a path
+    if let Some(eq) = assignment(word).filter(|_| command_line.command_position()) {
+        return Some(Completion { start: chars_before(eq + 1), candidates: complete_path(&word[eq + 1..], false, listings) });
+    }
+    if command_line.command_position() {
+        let candidates = if word.contains('/') { complete_path(word, false, listings) } else { complete_command(shell, &unquote(word), listings) };
+        return Some(Completion { start, candidates });
+    }
+    None
+}
+
+/// Like `complete` but without running anything, so the arguments of a command
+/// complete to file names whatever its completion spec says.
+pub fn complete_quietly(shell: &Shell, line: &[char], cursor: usize, listings: &mut Listings) -> Completion {
+    let command_line = CommandLine::parse(line, cursor);
+    complete_common(shell, &command_line, listings)
+        .unwrap_or_else(|| Completion { start: command_line.start, candidates: complete_path(command_line.word(), false, listings) })
+}
+
+/// Completes the word before the cursor, which is a char index into `line`.
+pub fn complete(shell: &mut Shell, line: &[char], cursor: usize) -> Completion {
+    let command_line = CommandLine::parse(line, cursor);
+    let listings = &mut Listings::default();
+    if let Some(completion) = complete_common(shell, &command_line, listings) {
+        return completion;
+    }
+    let word = command_line.word();
+    let start = command_line.start;
+
+    // the spec for the command, looked up by its full name and then its base name
+    let command = unquote(&command_line.words[0]);
+    let base = command.rsplit('/').next().unwrap_or(&command).to_string();
+    let spec = shell.completion(&command).or_else(|| shell.completion(&base)).cloned();
+    if let Some(spec) = spec.filter(|_| !command_line.redirect) {
+        let candidates = complete_from_spec(shell, &spec, &command_line, line, cursor);
+        let options = &spec.options;
+        if !candidates.is_empty() || !(options.default || options.bashdefault || options.dirnames) {
+            return Completion { start, candidates };
+        }
+        if options.dirnames {
+            return Completion { start, candidates: complete_path(command_line.word(), true, listings) };
+        }
+    }
+    else if shell.options.helpcomplete && word.starts_with('-') && !command_line.redirect {
+        let candidates = options::complete_options(shell, &command, word);
+        if !candidates.is_empty() {
+            return Completion { start, candidates };
+        }
+    }
+    Completion { start, candidates: complete_path(command_line.word(), false, listings) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+    use std::path::PathBuf;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("rshell-complete-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// The arguments `ls` gets when `line` follows it on the command line.
+    fn arguments(line: &str) -> Vec<String> {
+        let shell = Shell::new(Path::new(""));
+        let (_, argv) = parser::parseline(&format!("ls {}\n", line));
+        parser::parseargs(&argv, &shell).args.remove(0)
+    }
+
+    #[test]
+    fn completed_paths_parse_back_to_the_file() {
+        let dir = temp_dir("paths");
+        for name in ["q dir", "it's", "a|b"] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+        for (typed, name) in [("q", "q dir"), ("it", "it's"), ("a", "a|b")] {
+            let path = format!("{}/{}", dir.display(), name);
+            let candidates = complete_path(&format!("{}/{}", dir.display(), typed), false, &mut Listings::default());
+            assert_eq!(candidates.len(), 1);
+            assert_eq!(parser::unquote(&candidates[0].replacement), path);
+            assert_eq!(arguments(&candidates[0].replacement), [path]);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn typing_after_a_completed_directory_keeps_one_word() {
+        let dir = temp_dir("directories");
+        fs::create_dir_all(dir.join("q dir")).unwrap();
+        fs::write(dir.join("q dir/file"), "").unwrap();
+        let candidates = complete_path(&format!("{}/q", dir.display()), false, &mut Listings::default());
+        assert_eq!(candidates.len(), 1);
+        assert!(!candidates[0].finished);
+
+        let typed = format!("{}fi", candidates[0].replacement);
+        assert_eq!(arguments(&typed), [format!("{}/q dir/fi", dir.display())]);
+        let candidates = complete_path(&typed, false, &mut Listings::default());
+        assert_eq!(arguments(&candidates[0].replacement), [format!("{}/q dir/file", dir.display())]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn quotes_and_backslashes_come_off_arguments() {
+        assert_eq!(arguments("'a b' c\\ d 'x'y \\'"), ["a b", "c d", "xy", "'"]);
+        assert_eq!(arguments("'$HOME'"), ["$HOME"]);
+        assert_eq!(arguments("'a|b'"), ["a|b"]);
+    }
+}
@@ -0,0 +1,523 @@
+//! Options of commands without a completion spec, read from their man page or
+//! `--help` output and cached on disk by binary path and modification time.
+
+use super::Candidate;
+use crate::shell::Shell;
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::io::Read;
+use std::path::{Path,PathBuf};
+use std::process::{Child,Command,Stdio};
+use std::thread;
+use std::time::{Duration,Instant,UNIX_EPOCH};
+
+/// How long a command gets to print its `--help` before it is killed.
+const HELP_TIMEOUT: Duration = Duration::from_secs(2);
+const MAN_DIRS: &[&str] = &["/usr/local/share/man", "/usr/share/man", "/usr/local/man", "/usr/man"];
+const MAN_SECTIONS: &[&str] = &["1", "8", "6"];
+
+/// One option of a command and what it does.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct OptionHelp {
+    /// `-a` or `--all`, with a trailing `=` if the option takes its value attached.
+    pub name: String,
+    pub description: String,
+}
+
+/// The program `command` runs, looked up on the PATH unless it has a slash.
+fn find_program(shell: &Shell, command: &str) -> Option<PathBuf> {
+    if command.contains('/') {
+        return Some(PathBuf::from(command)).filter(|path| path.is_file());
+    }
+    let path = shell.lookup_var("PATH").unwrap_or_default();
+    path.split(':')
+        .filter(|dir| !dir.is_empty())
+        .map(|dir| Path::new(dir).join(command))
+        .find(|path| super::is_executable(path))
+}
+
+/// The names in the part of a help line that lists the options, such as
+/// `-a, --all`, `--color[=WHEN]` or `-I, --ignore=PATTERN`.
+fn option_names(spec: &str) -> Vec<String> {
+    let mut names = Vec::new();
+    for token in spec.split(|c: char| c == ',' || c == '|' || c.is_whitespace()) {
+        let token = token.trim_start_matches(['[', '(']);
+        let dashes = token.len() - token.trim_start_matches('-').len();
+        if dashes == 0 || dashes > 2 {
+            continue;
+        }
+        let name_len = token[dashes..]
+            .find(|c: char| !(c.is_alphanumeric() || c == '-' || c == '_' || (dashes == 1 && c == '?')))
+            .unwrap_or(token.len() - dashes);
+        if name_len == 0 {
+            continue;
+        }
+        let name = &token[..dashes + name_len];
+        let attached = token[dashes + name_len..].starts_with('=');
+        names.push(format!("{}{}", name, if attached { "=" } else { "" }));
+    }
+    names
+}
+
+/// The first sentence of a description, on one line.
+fn first_sentence(text: &str) -> String {
+    let text = text.split_whitespace().collect::<Vec<&str>>().join(" ");
+    // a sentence ends at a period after a word and before a capital, not at `.` or `..`
+    let end = text.match_indices(". ")
+        .map(|(i, _)| i)
+        .find(|&i| {
+            text[..i].ends_with(char::is_alphanumeric)
+                && text[i + 2..].starts_with(char::is_uppercase)
+        })
+        .unwrap_or(text.len());
+    let sentence = &text[..end];
+    match sentence.strip_suffix('.') {
+        Some(stripped) if stripped.ends_with(char::is_alphanumeric) => stripped.to_string(),
+        _ => sentence.to_string(),
+    }
+}
+
+/// Options listed one per line as most `--help` output does, with the description
+/// after two spaces or on the more indented lines that follow.
+pub fn parse_help(text: &str) -> Vec<OptionHelp> {
+    let mut options: Vec<OptionHelp> = Vec::new();
+    // the options of the last line that had no description yet, and its indent
+    let mut waiting: Option<(usize, usize)> = None;
+    for line in text.lines() {
+        let trimmed = line.trim_start();
+        let indent = line.len() - trimmed.len();
+        if trimmed.starts_with('-') && trimmed[1..].starts_with(|c: char| !c.is_whitespace()) {
+            let split = match (trimmed.find("  "), trimmed.find('\t')) {
+                (Some(a), Some(b)) => a.min(b),
+                (a, b) => a.or(b).unwrap_or(trimmed.len()),
+            };
+            let (spec, description) = trimmed.split_at(split);
+            let names = option_names(spec);
+            let first = options.len();
+            let description = first_sentence(description);
+            waiting = if description.is_empty() && !names.is_empty() { Some((first, indent)) } else { None };
+            options.extend(names.into_iter().map(|name| OptionHelp { name, description: description.clone() }));
+            continue;
+        }
+
+        match waiting.take() {
+            Some((first, option_indent)) if indent > option_indent && !trimmed.is_empty() => {
+                let description = first_sentence(trimmed);
+                for option in options[first..].iter_mut() {
+                    option.description = description.clone();
+                }
+            },
+            _ => (),
+        }
+    }
+    options
+}
+
+/// Splits the arguments of a request, keeping double quoted arguments together.
+fn roff_args(args: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut chars = args.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        let mut word = String::new();
+        if c == '"' {
+            chars.next();
+            while let Some(c) = chars.next() {
+                if c == '"' {
+                    if chars.peek() != Some(&'"') {
+                        break;
+                    }
+                    chars.next();
+                }
+                word.push(c);
+            }
+        }
+        else {
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                word.push(c);
+                chars.next();
+            }
+        }
+        words.push(word);
+    }
+    words
+}
+
+/// Replaces the escapes of a roff line with the text they stand for and drops font changes.
+fn roff_escapes(text: &str) -> String {
+    let mut plain = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            plain.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('f') => match chars.next() {
+                Some('(') => { chars.next(); chars.next(); },
+                Some('[') => { chars.by_ref().find(|c| *c == ']'); },
+                _ => (),
+            },
+            Some('(') => {
+                let name: String = chars.by_ref().take(2).collect();
+                plain += match name.as_str() {
+                    "em" | "en" | "mi" | "hy" => "-",
+                    "aq" | "cq" | "oq" => "'",
+                    "lq" | "rq" | "dq" => "\"",
+                    "bu" => "*",
+                    _ => "",
+                };
+            },
+            Some('*') => match chars.next() {
+                Some('(') => { chars.next(); chars.next(); },
+                Some('[') => { chars.by_ref().find(|c| *c == ']'); },
+                _ => (),
+            },
+            Some('s') => {
+                let mut rest = chars.clone();
+                if matches!(rest.next(), Some('+' | '-')) {
+                    chars = rest;
+                }
+                chars.next();
+            },
+            Some('-') => plain.push('-'),
+            Some('e') | Some('\\') => plain.push('\\'),
+            Some(' ') | Some('~') => plain.push(' '),
+            Some('&') | Some('c') | Some('%') | Some('|') | Some('^') | Some(':') | None => (),
+            Some(c) => plain.push(c),
+        }
+    }
+    plain
+}
+
+/// The text of an mdoc line such as `Fl a Ar file`, where `Fl` puts a dash in front of the next word.
+fn mdoc_text(args: &[String]) -> String {
+    let mut words: Vec<String> = Vec::new();
+    let mut flag = false;
+    for arg in args {
+        match arg.as_str() {
+            "Fl" => {
+                if flag {
+                    words.push("-".to_string());
+                }
+                flag = true;
+            },
+            "Ar" | "Cm" | "Op" | "Oo" | "Oc" | "Ns" | "Pa" | "Xo" | "Xc" | "Ic" | "Sy" | "Em" | "Li" | "Dq" | "Qq" | "Ql" | "Ek" => (),
+            _ => {
+                words.push(format!("{}{}", if flag { "-" } else { "" }, roff_escapes(arg)));
+                flag = false;
+            },
+        }
+    }
+    if flag {
+        words.push("-".to_string());
+    }
+    words.join(" ")
+}
+
+/// The text of one line of a man page, with font macros such as `.BR` turned into text.
+fn roff_line(line: &str) -> String {
+    let request = match line.strip_prefix('.').or_else(|| line.strip_prefix('\'')) {
+        Some(request) => request.trim_start(),
+        None => return roff_escapes(line),
+    };
+    let (name, args) = request.split_at(request.find(char::is_whitespace).unwrap_or(request.len()));
+    let args = roff_args(args);
+    match name {
+        "B" | "I" | "SM" | "SB" => args.iter().map(|arg| roff_escapes(arg)).collect::<Vec<String>>().join(" "),
+        "BR" | "BI" | "IR" | "IB" | "RB" | "RI" => args.iter().map(|arg| roff_escapes(arg)).collect(),
+        "It" | "Fl" | "Ar" | "Nm" | "Op" | "Pa" | "Cm" | "Ic" | "Sy" | "Em" | "Dq" | "Qq" | "Xo" => {
+            let mut words = args;
+            if name == "Fl" {
+                words.insert(0, name.to_string());
+            }
+            mdoc_text(&words)
+        },
+        _ => String::new(),
+    }
+}
+
+/// Options from the tagged paragraphs of a man page: `.TP` with the tag on the next
+/// line, `.IP "tag"` and the `.It` items of mdoc lists.
+pub fn parse_man(source: &str) -> Vec<OptionHelp> {
+    let mut options = Vec::new();
+    let mut tag: Option<String> = None;
+    let mut body = String::new();
+    let mut lines = source.lines();
+    let mut flush = |tag: &mut Option<String>, body: &mut String| {
+        if let Some(tag) = tag.take().filter(|tag| tag.trim_start().starts_with('-')) {
+            let description = first_sentence(body);
+            options.extend(option_names(&tag).into_iter().map(|name| OptionHelp { name, description: description.clone() }));
+        }
+        body.clear();
+    };
+
+    while let Some(line) = lines.next() {
+        if line.starts_with(".\\\"") || line.starts_with("'\\\"") {
+            continue;
+        }
+        let request = line.strip_prefix('.').map(str::trim_start).unwrap_or("");
+        let name = request.split_whitespace().next().unwrap_or("");
+        match name {
+            "TP" | "TQ" => {
+                if name == "TP" {
+                    flush(&mut tag, &mut body);
+                }
+                let next = lines.by_ref().find(|line| !line.starts_with(".\\\"")).unwrap_or("");
+                let text = roff_line(next);
+                tag = Some(match tag.take() {
+                    Some(previous) if name == "TQ" => format!("{}, {}", previous, text),
+                    _ => text,
+                });
+            },
+            "IP" => {
+                flush(&mut tag, &mut body);
+                tag = roff_args(&request[2..]).first().map(|arg| roff_escapes(arg));
+            },
+            "It" => {
+                flush(&mut tag, &mut body);
+                tag = Some(roff_line(line));
+            },
+            "SH" | "SS" | "Sh" | "Ss" | "PP" | "P" | "LP" | "HP" | "Pp" | "El" | "Bl" => flush(&mut tag, &mut body),
+            _ if tag.is_some() && body.len() < 200 => {
+                body.push(' ');
+                body += &roff_line(line);
+            },
+            _ => (),
+        }
+    }
+    flush(&mut tag, &mut body);
+    options
+}
+
+/// The source of the man page for `name` in MANPATH or the usual places, gzipped or not.
+fn read_man_page(name: &str) -> Option<String> {
+    let manpath = env::var("MANPATH").unwrap_or_default();
+    let mut dirs: Vec<String> = Vec::new();
+    for dir in manpath.split(':') {
+        // an empty entry stands for the default directories
+        if dir.is_empty() {
+            dirs.extend(MAN_DIRS.iter().map(|dir| dir.to_string()));
+        }
+        else {
+            dirs.push(dir.to_string());
+        }
+    }
+
+    for dir in dirs.iter() {
+        for section in MAN_SECTIONS {
+            let page = Path::new(dir).join(format!("man{}", section)).join(format!("{}.{}", name, section));
+            let source = read_maybe_gzipped(&page);
+            if let Some(source) = source {
+                // a page that is only `.so man1/other.1` includes another one
+                return match source.trim().strip_prefix(".so ") {
+                    Some(other) => read_maybe_gzipped(&Path::new(dir).join(other.trim())),
+                    None => Some(source),
+                };
+            }
+        }
+    }
+    None
+}
+
+fn read_maybe_gzipped(path: &Path) -> Option<String> {
+    if let Ok(source) = fs::read_to_string(path) {
+        return Some(source);
+    }
+    let mut gzipped = path.as_os_str().to_owned();
+    gzipped.push(".gz");
+    if !Path::new(&gzipped).is_file() {
+        return None;
+    }
+    let output = Command::new("gzip").arg("-dc").arg(&gzipped).stderr(Stdio::null()).output().ok()?;
+    Some(String::from_utf8_lossy(&output.stdout).into_owned()).filter(|source| !source.is_empty())
+}
+
+fn read_all(child: &mut Child) -> thread::JoinHandle<String> {
+    let stdout = child.stdout.take();
+    let stderr = child.stderr.take();
+    thread::spawn(move || {
+        let mut text = String::new();
+        if let Some(mut stdout) = stdout {
+            let _ = stdout.read_to_string(&mut text);
+        }
+        // some programs print their usage on stderr
+        if let Some(mut stderr) = stderr {
+            let _ = stderr.read_to_string(&mut text);
+        }
+        text
+    })
+}
+
+/// What `program --help` prints, or nothing if it does not finish in time.
+fn run_help(program: &Path) -> Option<String> {
+    let mut child = Command::new(program)
+        .arg("--help")
+        .env("MANPAGER", "cat")
+        .env("PAGER", "cat")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .ok()?;
+    let reader = read_all(&mut child);
+    let deadline = Instant::now() + HELP_TIMEOUT;
+    loop {
+        match child.try_wait() {
+            Ok(Some(_)) => break,
+            Ok(None) if Instant::now() < deadline => thread::sleep(Duration::from_millis(10)),
+            _ => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return None;
+            },
+        }
+    }
+    reader.join().ok()
+}
+
+fn cache_dir() -> Option<PathBuf> {
+    let cache = env::var("XDG_CACHE_HOME").ok()
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var("HOME").ok().map(|home| Path::new(&home).join(".cache")))?;
+    Some(cache.join("rshell").join("options"))
+}
+
+/// The cache file for a program, named after its path with the slashes replaced.
+fn cache_file(program: &Path) -> Option<PathBuf> {
+    let name = program.to_string_lossy().replace('%', "%%").replace('/', "%");
+    cache_dir().map(|dir| dir.join(name))
+}
+
+fn read_cache(file: &Path, stamp: &str) -> Option<Vec<OptionHelp>> {
+    let text = fs::read_to_string(file).ok()?;
+    let mut lines = text.lines();
+    if lines.next() != Some(stamp) {
+        return None;
+    }
+    Some(lines
+        .filter_map(|line| line.split_once('\t'))
+        .map(|(name, description)| OptionHelp { name: name.to_string(), description: description.to_string() })
+        .collect())
+}
+
+fn write_cache(file: &Path, stamp: &str, options: &[OptionHelp]) {
+    let mut text = format!("{}\n", stamp);
+    for option in options {
+        text += &format!("{}\t{}\n", option.name, option.description.replace(['\t', '\n'], " "));
+    }
+    if let Some(dir) = file.parent() {
+        let _ = fs::create_dir_all(dir);
+    }
+    let _ = fs::write(file, text);
+}
+
+/// The options of `program`, from the cache while the program is unchanged.
+pub fn program_options(program: &Path) -> Vec<OptionHelp> {
+    let modified = match fs::metadata(program).and_then(|metadata| metadata.modified()) {
+        Ok(modified) => modified.duration_since(UNIX_EPOCH).unwrap_or_default(),
+        Err(_) => return Vec::new(),
+    };
+    let stamp = format!("{}.{:09}", modified.as_secs(), modified.subsec_nanos());
+    let file = cache_file(program);
+    if let Some(options) = file.as_deref().and_then(|file| read_cache(file, &stamp)) {
+        return options;
+    }
+
+    let name = program.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+    let mut options = read_man_page(&name).map(|source| parse_man(&source)).unwrap_or_default();
+    if options.is_empty() {
+        options = run_help(program).map(|text| parse_help(&text)).unwrap_or_default();
+    }
+    let mut seen = HashSet::new();
+    options.retain(|option| seen.insert(option.name.clone()));
+
+    if let Some(file) = file {
+        write_cache(&file, &stamp, &options);
+    }
+    options
+}
+
+/// The options of `command` that start with `word`, described.
+pub fn complete_options(shell: &Shell, command: &str, word: &str) -> Vec<Candidate> {
+    let program = match find_program(shell, command) {
+        Some(program) => program,
+        None => return Vec::new(),
+    };
+    program_options(&program).into_iter()
+        .filter(|option| option.name.starts_with(word))
+        .map(|option| {
+            let finished = !option.name.ends_with('=');
+            Candidate::new(option.name.clone(), option.name, finished).with_description(option.description)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn option(name: &str, description: &str) -> OptionHelp {
+        OptionHelp { name: name.to_string(), description: description.to_string() }
+    }
+
+    #[test]
+    fn help_output() {
+        let help = "\
+Usage: ls [OPTION]... [FILE]...
+  -a, --all                  do not ignore entries starting with .
+      --color[=WHEN]         color the output. WHEN can be always
+  -I, --ignore=PATTERN       do not list entries matching PATTERN
+  -l
+        use a long listing format. More text follows.
+";
+        assert_eq!(parse_help(help), [
+            option("-a", "do not ignore entries starting with ."),
+            option("--all", "do not ignore entries starting with ."),
+            option("--color", "color the output"),
+            option("-I", "do not list entries matching PATTERN"),
+            option("--ignore=", "do not list entries matching PATTERN"),
+            option("-l", "use a long listing format"),
+        ]);
+    }
+
+    #[test]
+    fn man_page_paragraphs() {
+        let man = r#".SH OPTIONS
+.\" a comment
+.TP
+.BR \-n ", " \-\-number
+number all output lines
+.TQ
+.B \-\-lines
+.TP
+\fB\-s\fR, \fB\-\-squeeze\-blank\fR
+suppress repeated empty output lines. Really.
+.IP "\-v" 4
+use ^ and M\- notation
+.SH SEE ALSO
+"#;
+        assert_eq!(parse_man(man), [
+            option("-n", "number all output lines"),
+            option("--number", "number all output lines"),
+            option("--lines", "number all output lines"),
+            option("-s", "suppress repeated empty output lines"),
+            option("--squeeze-blank", "suppress repeated empty output lines"),
+            option("-v", "use ^ and M- notation"),
+        ]);
+    }
+
+    #[test]
+    fn mdoc_lists() {
+        let man = ".Bl -tag -width Ds\n.It Fl a\nInclude hidden entries.\n.It Fl f Ar file\nRead from\n.Pa file\n.El\n";
+        assert_eq!(parse_man(man), [option("-a", "Include hidden entries"), option("-f", "Read from file")]);
+    }
+}
@@ -0,0 +1,1068 @@
+mod highlight;
+mod search;
+mod suggest;
+mod vi;
+
+use crate::builtin;
+use crate::complete;
+use crate::parser;
+use crate::prompt::{AsyncPrompt,Prompts};
+use crate::shell::Shell;
+use nix::libc;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::termios::{self, ControlFlags, InputFlags, LocalFlags, SetArg, SpecialCharacterIndices, Termios};
+use nix::unistd;
+use std::io::{self, Write};
+use std::os::unix::io::RawFd;
+use highlight::{Highlighter, Style};
+use search::Search;
+use vi::ViState;
+
+const STDIN: RawFd = 0;
+const STDOUT: RawFd = 1;
+const KILL_RING_SIZE: usize = 16;
+/// How long to wait after an ESC for the rest of an escape sequence.
+const ESCAPE_TIMEOUT_MS: i32 = 30;
+
+
+/// A decoded key press.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Key {
+    Char(char),
+    Ctrl(char),
+    Alt(char),
+    AltBackspace,
+    Enter,
+    Tab,
+    BackTab,
+    Backspace,
+    Delete,
+    Escape,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    CtrlLeft,
+    CtrlRight,
+    Unknown,
+}
+
+/// What reading a line produced.
+#[derive(Debug,Clone,PartialEq)]
+pub enum Input {
+    /// A line including its trailing newline.
+    Line(String),
+    Eof,
+    /// The line was abandoned with Ctrl-C.
+    Interrupted,
+    /// Lines written in an external editor, run like `fc` runs them.
+    Edited(String),
+}
+
+/// What the main loop of `read_line` does after a key was handled.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+enum Outcome {
+    Continue,
+    Accept,
+    Eof,
+    Interrupt,
+    ClearScreen,
+    /// Open the line in `$VISUAL` or `$EDITOR`.
+    Edit,
+    /// List the completions in `LineEditor::menu` below the line.
+    Menu,
+}
+
+/// Tracks what the previous command was, so kills append to each other,
+/// inserts share one undo step and Alt-Y only works right after a yank.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+enum LastAction {
+    Other,
+    Insert,
+    Kill,
+    /// A yank of `len` chars ending at the cursor, taken from kill ring slot `index`.
+    Yank { len: usize, index: usize },
+}
+
+
+/// Puts the terminal in raw mode for as long as it lives.
+struct RawMode {
+    original: Termios,
+}
+
+impl RawMode {
+    fn enable() -> nix::Result<RawMode> {
+        let original = termios::tcgetattr(STDIN)?;
+        let mut raw = original.clone();
+        raw.input_flags &= !(InputFlags::BRKINT | InputFlags::ICRNL | InputFlags::INPCK | InputFlags::ISTRIP | InputFlags::IXON);
+        raw.control_flags |= ControlFlags::CS8;
+        raw.local_flags &= !(LocalFlags::ECHO | LocalFlags::ICANON | LocalFlags::IEXTEN | LocalFlags::ISIG);
+        raw.control_chars[SpecialCharacterIndices::VMIN as usize] = 1;
+        raw.control_chars[SpecialCharacterIndices::VTIME as usize] = 0;
+        termios::tcsetattr(STDIN, SetArg::TCSADRAIN, &raw)?;
+        Ok(RawMode { original })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = termios::tcsetattr(STDIN, SetArg::TCSADRAIN, &self.original);
+    }
+}
+
+
+/// Reads one byte, None at end of file. With a timeout, None also means nothing arrived in time.
+fn read_byte(timeout_ms: Option<i32>) -> nix::Result<Option<u8>> {
+    if let Some(timeout) = timeout_ms {
+        let mut fds = [PollFd::new(STDIN, PollFlags::POLLIN)];
+        if poll(&mut fds, timeout)? == 0 {
+            return Ok(None);
+        }
+    }
+    let mut byte = [0u8];
+    match unistd::read(STDIN, &mut byte)? {
+        0 => Ok(None),
+        _ => Ok(Some(byte[0])),
+    }
+}
+
+/// Decodes the rest of a CSI (`ESC [`) or SS3 (`ESC O`) sequence.
+fn read_escape_sequence(introducer: u8) -> nix::Result<Key> {
+    let mut params = String::new();
+    let last = loop {
+        match read_byte(Some(ESCAPE_TIMEOUT_MS))? {
+            Some(byte) if introducer == b'[' && (0x20..0x40).contains(&byte) => params.push(byte as char),
+            Some(byte) => break byte,
+            None => return Ok(Key::Unknown),
+        }
+    };
+
+    let modified = params.ends_with(";5") || params.ends_with(";3");
+    Ok(match (last, params.as_str()) {
+        (b'A', _) => Key::Up,
+        (b'B', _) => Key::Down,
+        (b'C', _) if modified => Key::CtrlRight,
+        (b'D', _) if modified => Key::CtrlLeft,
+        (b'C', _) => Key::Right,
+        (b'D', _) => Key::Left,
+        (b'H', _) => Key::Home,
+        (b'F', _) => Key::End,
+        (b'Z', _) => Key::BackTab,
+        (b'~', "1") | (b'~', "7") => Key::Home,
+        (b'~', "4") | (b'~', "8") => Key::End,
+        (b'~', "3") => Key::Delete,
+        _ => Key::Unknown,
+    })
+}
+
+/// Reads and decodes one key press, None at end of file.
+pub fn read_key() -> nix::Result<Option<Key>> {
+    let byte = match read_byte(None)? {
+        Some(byte) => byte,
+        None => return Ok(None),
+    };
+
+    let key = match byte {
+        b'\r' | b'\n' => Key::Enter,
+        b'\t' => Key::Tab,
+        127 | 8 => Key::Backspace,
+        0 => Key::Ctrl('@'),
+        1..=26 => Key::Ctrl((b'a' + byte - 1) as char),
+        0x1f => Key::Ctrl('_'),
+        0x1b => match read_byte(Some(ESCAPE_TIMEOUT_MS))? {
+            None => Key::Escape,
+            Some(b'[') => read_escape_sequence(b'[')?,
+            Some(b'O') => read_escape_sequence(b'O')?,
+            Some(127) | Some(8) => Key::AltBackspace,
+            Some(byte) if byte.is_ascii() => Key::Alt(byte as char),
+            Some(_) => Key::Unknown,
+        },
+        byte if byte < 0x80 => Key::Char(byte as char),
+        byte => {
+            // the rest of a UTF-8 sequence
+            let len = match byte {
+                0xc0..=0xdf => 2,
+                0xe0..=0xef => 3,
+                _ => 4,
+            };
+            let mut bytes = vec![byte];
+            for _ in 1..len {
+                bytes.extend(read_byte(Some(ESCAPE_TIMEOUT_MS))?);
+            }
+            match std::str::from_utf8(&bytes).ok().and_then(|s| s.chars().next()) {
+                Some(c) => Key::Char(c),
+                None => Key::Unknown,
+            }
+        },
+    };
+    Ok(Some(key))
+}
+
+/// Reads one line from stdin a byte at a time, so nothing past the newline is taken
+/// from commands that share our stdin and so a caught signal interrupts the read.
+pub fn read_plain_line() -> nix::Result<Input> {
+    let mut line = Vec::new();
+    let mut byte = [0u8];
+    loop {
+        match unistd::read(STDIN, &mut byte)? {
+            0 => break,
+            _ => {
+                line.push(byte[0]);
+                if byte[0] == b'\n' {
+                    break;
+                }
+            },
+        }
+    }
+    if line.is_empty() {
+        return Ok(Input::Eof);
+    }
+    Ok(Input::Line(String::from_utf8_lossy(&line).into_owned()))
+}
+
+/// Columns and rows of the terminal, 80x24 if it cannot tell.
+pub fn terminal_size() -> (usize, usize) {
+    let mut size = std::mem::MaybeUninit::<libc::winsize>::zeroed();
+    let result = unsafe { libc::ioctl(STDOUT, libc::TIOCGWINSZ, size.as_mut_ptr()) };
+    let size = unsafe { size.assume_init() };
+    if result == -1 || size.ws_col == 0 {
+        return (80, 24);
+    }
+    (size.ws_col as usize, size.ws_row.max(1) as usize)
+}
+
+/// Columns `text` takes up on screen. Escape sequences and anything between the
+/// \x01 and \x02 markers (what `\[` and `\]` become in a prompt) take up none.
+pub fn display_width(text: &str) -> usize {
+    let mut width = 0;
+    let mut hidden = false;
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\x01' => hidden = true,
+            '\x02' => hidden = false,
+            '\x1b' => {
+                if chars.next() == Some('[') {
+                    for c in chars.by_ref() {
+                        if ('\x40'..='\x7e').contains(&c) {
+                            break;
+                        }
+                    }
+                }
+            },
+            _ if hidden || c.is_control() => (),
+            _ => width += 1,
+        }
+    }
+    width
+}
+
+/// Splits a prompt into the lines above the input line, which are printed once, and the
+/// last line, which is redrawn with it.
+fn split_prompt(prompt: &str) -> (String, String) {
+    let (head, prompt) = prompt.split_at(prompt.rfind('\n').map_or(0, |newline| newline + 1));
+    (head.replace(['\x01', '\x02'], ""), prompt.to_string())
+}
+
+/// How many rows the lines printed above the input line take up.
+fn head_rows(head: &str, columns: usize) -> usize {
+    head.split_terminator('\n')
+        .map(|line| display_width(line).div_ceil(columns).max(1))
+        .sum()
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Lays `items` out in as many columns as fit, sorted down the columns like `ls`.
+fn format_menu(items: &[String], columns: usize) -> String {
+    let width = items.iter().map(|item| display_width(item)).max().unwrap_or(0) + 2;
+    let per_row = (columns / width).max(1);
+    let rows = items.len().div_ceil(per_row);
+
+    let mut menu = String::new();
+    for row in 0..rows {
+        let mut line = String::new();
+        for column in 0..per_row {
+            if let Some(item) = items.get(column * rows + row) {
+                line += item;
+                line += &" ".repeat(width - display_width(item));
+            }
+        }
+        menu += line.trim_end();
+        menu.push('\n');
+    }
+    menu
+}
+
+/// What the menu shows for each candidate, one described candidate per row.
+fn menu_items(candidates: Vec<complete::Candidate>) -> Vec<String> {
+    if candidates.iter().all(|candidate| candidate.description.is_empty()) {
+        return candidates.into_iter().map(|candidate| candidate.display).collect();
+    }
+    let (columns, _) = terminal_size();
+    let width = candidates.iter().map(|candidate| display_width(&candidate.display)).max().unwrap_or(0);
+    candidates.into_iter()
+        .map(|candidate| {
+            if candidate.description.is_empty() {
+                return candidate.display;
+            }
+            let padding = " ".repeat(width - display_width(&candidate.display));
+            let item = format!("{}{}  -- {}", candidate.display, padding, candidate.description);
+            // a described row that wraps would throw off the layout
+            match item.char_indices().nth(columns.saturating_sub(1)) {
+                Some((end, _)) => item[..end].to_string(),
+                None => item,
+            }
+        })
+        .collect()
+}
+
+fn common_prefix<'a>(mut words: impl Iterator<Item = &'a str>) -> String {
+    let first = match words.next() {
+        Some(first) => first,
+        None => return String::new(),
+    };
+    let mut len = first.len();
+    for word in words {
+        len = first.chars().zip(word.chars())
+            .take_while(|(a, b)| a == b)
+            .map(|(a, _)| a.len_utf8())
+            .sum::<usize>()
+            .min(len);
+    }
+    first[..len].to_string()
+}
+
+fn write_out(text: &str) {
+    let mut stdout = io::stdout();
+    let _ = stdout.write_all(text.as_bytes());
+    let _ = stdout.flush();
+}
+
+
+/// A line editor for the interactive prompt with emacs or, after `set -o vi`, vi key bindings.
+#[derive(Debug)]
+pub struct LineEditor {
+    buffer: Vec<char>,
+    cursor: usize,
+    kill_ring: Vec<String>,
+    undo: Vec<(Vec<char>, usize)>,
+    last: LastAction,
+    /// Index into the history while browsing it, None while on the new line.
+    history_index: Option<usize>,
+    /// The new line, kept while browsing the history.
+    saved_line: Vec<char>,
+    /// Rows between the start of the prompt and the cursor at the last refresh.
+    cursor_row: usize,
+    tty: bool,
+    /// Whether vi key bindings are in use for the current line.
+    vi_enabled: bool,
+    vi: ViState,
+    search: Option<Search>,
+    /// Completions waiting to be listed.
+    menu: Vec<String>,
+    /// What the last incremental search looked for, Ctrl-R Ctrl-R looks for it again.
+    last_search: String,
+    highlighter: Highlighter,
+    /// Grey text after the line that Right or Ctrl-F takes into it.
+    suggestion: String,
+    /// Commands run in the working directory, newest first, which suggestions prefer.
+    local_commands: Vec<String>,
+    /// Directories read for suggested completions while this line is edited.
+    listings: complete::Listings,
+    /// Shown at the right edge of the input line while the line leaves room for it.
+    right_prompt: String,
+    /// Replaces the prompt once the line is entered.
+    transient_prompt: Option<String>,
+    /// Slow segments of the prompts still being worked out, which are drawn once ready.
+    pending: Option<AsyncPrompt>,
+}
+
+impl LineEditor {
+    pub fn new() -> LineEditor {
+        LineEditor {
+            buffer: Vec::new(),
+            cursor: 0,
+            kill_ring: Vec::new(),
+            undo: Vec::new(),
+            last: LastAction::Other,
+            history_index: None,
+            saved_line: Vec::new(),
+            cursor_row: 0,
+            tty: unistd::isatty(STDIN).unwrap_or(false) && unistd::isatty(STDOUT).unwrap_or(false),
+            vi_enabled: false,
+            vi: ViState::default(),
+            search: None,
+            menu: Vec::new(),
+            last_search: String::new(),
+            highlighter: Highlighter::default(),
+            suggestion: String::new(),
+            local_commands: Vec::new(),
+            listings: complete::Listings::default(),
+            right_prompt: String::new(),
+            transient_prompt: None,
+            pending: None,
+        }
+    }
+
+    /// Sets what the next `read_line` shows at the right edge of the line.
+    pub fn set_right_prompt(&mut self, right_prompt: String) {
+        self.right_prompt = right_prompt;
+    }
+
+    /// Sets what the next `read_line` leaves in place of its prompt, lines above it
+    /// included, once the line is entered.
+    pub fn set_transient_prompt(&mut self, transient_prompt: Option<String>) {
+        self.transient_prompt = transient_prompt;
+    }
+
+    /// Hands the next `read_line` prompts whose slow segments are still being worked out.
+    /// Once they are, they replace the prompt, right prompt and transient prompt it shows.
+    pub fn set_pending_prompt(&mut self, pending: AsyncPrompt) {
+        self.pending = Some(pending);
+    }
+
+    #[inline]
+    fn line(&self) -> String {
+        self.buffer.iter().collect()
+    }
+
+    /// Shows `prompt` and reads a line, editing it in place when on a terminal.
+    pub fn read_line(&mut self, prompt: &str, shell: &mut Shell) -> nix::Result<Input> {
+        let right_prompt = std::mem::take(&mut self.right_prompt);
+        let mut transient_prompt = self.transient_prompt.take();
+        if !self.tty {
+            // nothing is drawn again without a terminal, so the prompt waits for everything
+            let prompt = match self.pending.take() {
+                Some(mut pending) => {
+                    pending.wait();
+                    pending.prompts().prompt
+                },
+                None => prompt.to_string(),
+            };
+            write_out(&prompt.replace(['\x01', '\x02'], ""));
+            return read_plain_line();
+        }
+        self.right_prompt = right_prompt.replace(['\x01', '\x02'], "");
+
+        // only the last line of the prompt is redrawn, the ones above it are printed once
+        let (mut head, mut prompt) = split_prompt(prompt);
+        let raw = RawMode::enable()?;
+        write_out(&head);
+        self.buffer.clear();
+        self.cursor = 0;
+        self.undo.clear();
+        self.last = LastAction::Other;
+        self.history_index = None;
+        self.cursor_row = 0;
+        self.search = None;
+        self.vi_enabled = shell.options.vi;
+        if self.vi_enabled {
+            self.vi_start(shell);
+        }
+        self.highlighter.forget_commands();
+        self.highlighter.update(shell, &self.buffer);
+        self.suggestion.clear();
+        self.local_commands = suggest::local_commands(shell);
+        self.listings = complete::Listings::default();
+        self.refresh(&prompt);
+
+        // dropped on the way out, which cancels whatever is still being worked out
+        let mut pending = self.pending.take();
+        loop {
+            self.wait_for_key(&mut pending, &mut head, &mut prompt, &mut transient_prompt)?;
+            let key = match read_key()? {
+                Some(key) => key,
+                None => return Ok(Input::Eof),
+            };
+
+            let outcome = self.handle_key(key, shell);
+            self.highlighter.update(shell, &self.buffer);
+            self.suggestion = match outcome {
+                Outcome::Continue | Outcome::ClearScreen => self.suggest(shell),
+                _ => String::new(),
+            };
+            match outcome {
+                Outcome::Continue => self.refresh(&prompt),
+                Outcome::ClearScreen => {
+                    write_out("\x1b[H\x1b[2J");
+                    write_out(&head);
+                    self.cursor_row = 0;
+                    self.refresh(&prompt);
+                },
+                Outcome::Accept => {
+                    self.cursor = self.buffer.len();
+                    self.leave_prompt(&head, &prompt, transient_prompt.as_deref());
+                    write_out("\n");
+                    return Ok(Input::Line(format!("{}\n", self.line())));
+                },
+                Outcome::Eof => {
+                    write_out("\n");
+                    return Ok(Input::Eof);
+                },
+                Outcome::Interrupt => {
+                    self.cursor = self.buffer.len();
+                    self.leave_prompt(&head, &prompt, transient_prompt.as_deref());
+                    write_out("^C\n");
+                    return Ok(Input::Interrupted);
+                },
+                Outcome::Menu => {
+                    let cursor = self.cursor;
+                    self.cursor = self.buffer.len();
+                    self.refresh(&prompt);
+                    let (columns, rows) = terminal_size();
+                    let menu = format_menu(&self.menu, columns);
+                    let mut show = true;
+                    if menu.lines().count() + 1 >= rows {
+                        write_out(&format!("\nDisplay all {} possibilities? (y or n)", self.menu.len()));
+                        show = matches!(read_key()?, Some(Key::Char('y' | 'Y' | ' ')));
+                        if !show {
+                            write_out("\n");
+                        }
+                    }
+                    if show {
+                        write_out("\n");
+                        write_out(&menu);
+                    }
+                    self.menu.clear();
+                    self.cursor = cursor;
+                    self.cursor_row = 0;
+                    self.refresh(&prompt);
+                },
+                Outcome::Edit => {
+                    self.cursor = self.buffer.len();
+                    self.refresh(&prompt);
+                    write_out("\n");
+                    drop(raw);
+                    let editor = ["VISUAL", "EDITOR"].iter()
+                        .find_map(|name| shell.lookup_var(name).filter(|value| !value.is_empty()))
+                        .unwrap_or_else(|| "vi".to_string());
+                    return match builtin::fc_edit(shell, &editor, &format!("{}\n", self.line())) {
+                        Ok(lines) => Ok(Input::Edited(lines.unwrap_or_default())),
+                        Err(e) => {
+                            eprintln!("{}: {}", editor, e);
+                            Ok(Input::Edited(String::new()))
+                        },
+                    };
+                },
+            }
+        }
+    }
+
+    /// Waits until a key can be read. Should the slow segments of the prompts be ready
+    /// first, the prompts are drawn again with them.
+    fn wait_for_key(&mut self, pending: &mut Option<AsyncPrompt>, head: &mut String, prompt: &mut String, transient_prompt: &mut Option<String>) -> nix::Result<()> {
+        let pending = match pending {
+            Some(pending) => pending,
+            None => return Ok(()),
+        };
+        while let Some(fd) = pending.fd() {
+            let mut fds = [PollFd::new(STDIN, PollFlags::POLLIN), PollFd::new(fd, PollFlags::POLLIN)];
+            poll(&mut fds, -1)?;
+            if fds[0].revents().is_some_and(|events| !events.is_empty()) {
+                return Ok(());
+            }
+            if pending.update() {
+                let prompts = pending.prompts();
+                *transient_prompt = prompts.transient_prompt.clone();
+                self.replace_prompt(prompts, head, prompt);
+            }
+        }
+        Ok(())
+    }
+
+    /// Draws the line again under new prompts, along with the lines above it if they changed.
+    fn replace_prompt(&mut self, prompts: Prompts, head: &mut String, prompt: &mut String) {
+        let (new_head, new_prompt) = split_prompt(&prompts.prompt);
+        if new_head != *head {
+            let rows = self.cursor_row + head_rows(head, terminal_size().0);
+            let mut out = String::new();
+            if rows > 0 {
+                out += &format!("\x1b[{}A", rows);
+            }
+            out += "\r\x1b[J";
+            out += &new_head;
+            write_out(&out);
+            self.cursor_row = 0;
+        }
+        *head = new_head;
+        *prompt = new_prompt;
+        self.right_prompt = prompts.right_prompt.replace(['\x01', '\x02'], "");
+        self.refresh(prompt);
+    }
+
+    /// Draws the line one last time before it is run, with the transient prompt in place
+    /// of the prompt and the lines printed above it if there is one.
+    fn leave_prompt(&mut self, head: &str, prompt: &str, transient_prompt: Option<&str>) {
+        let transient_prompt = match transient_prompt {
+            Some(transient_prompt) => transient_prompt,
+            None => return self.refresh(prompt),
+        };
+        self.cursor_row += head_rows(head, terminal_size().0);
+        self.right_prompt.clear();
+        self.vi_enabled = false;
+        self.refresh(transient_prompt);
+    }
+
+    /// Redraws the prompt and line, which may wrap over several rows, and puts the cursor back.
+    fn refresh(&mut self, prompt: &str) {
+        let prompt = match &self.search {
+            Some(search) => search.prompt(),
+            None if self.vi_enabled => format!("{}{}", self.vi.indicator(), prompt),
+            None => prompt.to_string(),
+        };
+        let prompt = prompt.as_str();
+        let highlight = self.search.as_ref().and_then(Search::highlight);
+        let styles = self.highlighter.styles(&self.buffer);
+        let columns = terminal_size().0;
+        let prompt_width = display_width(prompt);
+        let total = prompt_width + self.buffer.len() + self.suggestion.chars().count();
+
+        let mut out = String::new();
+        if self.cursor_row > 0 {
+            out += &format!("\x1b[{}A", self.cursor_row);
+        }
+        out += "\r\x1b[J";
+        out += &prompt.replace(['\x01', '\x02'], "");
+        let mut style = Style::Plain;
+        for (i, c) in self.buffer.iter().enumerate() {
+            match highlight {
+                Some((start, _)) if start == i => out += "\x1b[7m",
+                Some((_, end)) if end == i => out += "\x1b[27m",
+                _ => (),
+            }
+            if let Some(next) = styles.map(|styles| styles[i]).filter(|next| *next != style) {
+                out += next.sgr();
+                style = next;
+            }
+            out.push(*c);
+        }
+        if highlight.is_some() {
+            out += "\x1b[27m";
+        }
+        if style != Style::Plain {
+            out += Style::Plain.sgr();
+        }
+        if !self.suggestion.is_empty() {
+            out += &format!("\x1b[90m{}\x1b[39m", self.suggestion);
+        }
+        // like zsh the right prompt keeps a column free at the edge and gives way to the line
+        let right_width = display_width(&self.right_prompt);
+        if right_width > 0 && self.search.is_none() && total + right_width + 2 <= columns {
+            out += &format!("\x1b[{}G{}", columns - right_width, self.right_prompt);
+        }
+        // leave the terminal's pending wrap state so the cursor maths below hold
+        if total > 0 && total.is_multiple_of(columns) {
+            out += "\n";
+        }
+
+        let end_row = total / columns;
+        let position = prompt_width + self.cursor;
+        let (row, column) = (position / columns, position % columns);
+        if end_row > row {
+            out += &format!("\x1b[{}A", end_row - row);
+        }
+        out += "\r";
+        if column > 0 {
+            out += &format!("\x1b[{}C", column);
+        }
+
+        self.cursor_row = row;
+        write_out(&out);
+    }
+
+    fn save_undo(&mut self) {
+        self.undo.push((self.buffer.clone(), self.cursor));
+    }
+
+    fn insert(&mut self, text: &[char]) {
+        self.buffer.splice(self.cursor..self.cursor, text.iter().cloned());
+        self.cursor += text.len();
+    }
+
+    /// Removes `start..end` from the line and returns it.
+    fn remove(&mut self, start: usize, end: usize) -> String {
+        let removed: String = self.buffer.drain(start..end).collect();
+        if self.cursor > end {
+            self.cursor -= end - start;
+        }
+        else if self.cursor > start {
+            self.cursor = start;
+        }
+        removed
+    }
+
+    /// Kills `start..end` into the kill ring, consecutive kills grow the same entry.
+    fn kill(&mut self, start: usize, end: usize) {
+        if start >= end {
+            return;
+        }
+        let backward = end <= self.cursor;
+        let text = self.remove(start, end);
+        match (self.last, self.kill_ring.last_mut()) {
+            (LastAction::Kill, Some(entry)) if backward => entry.insert_str(0, &text),
+            (LastAction::Kill, Some(entry)) => entry.push_str(&text),
+            _ => {
+                self.kill_ring.push(text);
+                if self.kill_ring.len() > KILL_RING_SIZE {
+                    self.kill_ring.remove(0);
+                }
+            },
+        }
+    }
+
+    fn yank(&mut self, index: usize) {
+        if let Some(text) = self.kill_ring.get(index) {
+            let text: Vec<char> = text.chars().collect();
+            self.insert(&text);
+            self.last = LastAction::Yank { len: text.len(), index };
+        }
+    }
+
+    /// Start of the word before `from`, as emacs' backward-word sees words.
+    fn word_start(&self, from: usize) -> usize {
+        let mut i = from;
+        while i > 0 && !is_word_char(self.buffer[i - 1]) {
+            i -= 1;
+        }
+        while i > 0 && is_word_char(self.buffer[i - 1]) {
+            i -= 1;
+        }
+        i
+    }
+
+    /// End of the word after `from`, as emacs' forward-word sees words.
+    fn word_end(&self, from: usize) -> usize {
+        let mut i = from;
+        while i < self.buffer.len() && !is_word_char(self.buffer[i]) {
+            i += 1;
+        }
+        while i < self.buffer.len() && is_word_char(self.buffer[i]) {
+            i += 1;
+        }
+        i
+    }
+
+    /// Start of the whitespace separated word before the cursor, for Ctrl-W.
+    fn big_word_start(&self, from: usize) -> usize {
+        let mut i = from;
+        while i > 0 && self.buffer[i - 1].is_whitespace() {
+            i -= 1;
+        }
+        while i > 0 && !self.buffer[i - 1].is_whitespace() {
+            i -= 1;
+        }
+        i
+    }
+
+    fn set_line(&mut self, line: Vec<char>) {
+        self.buffer = line;
+        self.cursor = self.buffer.len();
+    }
+
+    /// Moves through the history, `back` towards older entries.
+    fn browse_history(&mut self, shell: &Shell, back: bool) {
+        let history = shell.history();
+        let len = history.len();
+        let index = match (self.history_index, back) {
+            (None, true) if len > 0 => len - 1,
+            (None, _) => return,
+            (Some(0), true) => return,
+            (Some(index), true) => index - 1,
+            (Some(index), false) => index + 1,
+        };
+
+        if self.history_index.is_none() {
+            self.saved_line = self.buffer.clone();
+        }
+        if index >= len {
+            self.history_index = None;
+            let line = std::mem::take(&mut self.saved_line);
+            self.set_line(line);
+            return;
+        }
+
+        self.history_index = Some(index);
+        if let Some(entry) = history.get(history.first_number() + index) {
+            self.set_line(entry.line.chars().collect());
+        }
+    }
+
+    /// Completes the word before the cursor: the only candidate, else as much as all
+    /// candidates share, else a menu of them.
+    fn complete(&mut self, shell: &mut Shell) -> Outcome {
+        let completion = complete::complete(shell, &self.buffer, self.cursor);
+        let mut candidates = completion.candidates;
+        candidates.sort();
+        candidates.dedup_by(|a, b| a.replacement == b.replacement);
+        if candidates.is_empty() {
+            write_out("\x07");
+            return Outcome::Continue;
+        }
+
+        let typed = self.cursor - completion.start;
+        let insert = match &candidates[..] {
+            [candidate] => format!("{}{}", candidate.replacement, if candidate.finished { " " } else { "" }),
+            _ => {
+                let common = common_prefix(candidates.iter().map(|candidate| candidate.replacement.as_str()));
+                // candidates quoted differently can still share the start of their text
+                let unquoted: Vec<String> = candidates.iter().map(|candidate| parser::unquote(&candidate.replacement)).collect();
+                let unquoted_common = common_prefix(unquoted.iter().map(String::as_str));
+                let typed_text: String = self.buffer[completion.start..self.cursor].iter().collect();
+                if common.chars().count() > typed {
+                    common
+                }
+                else if unquoted_common.chars().count() > parser::unquote(&typed_text).chars().count() {
+                    complete::quote(&unquoted_common)
+                }
+                else {
+                    self.menu = menu_items(candidates);
+                    return Outcome::Menu;
+                }
+            },
+        };
+
+        self.save_undo();
+        self.buffer.splice(completion.start..self.cursor, insert.chars());
+        self.cursor = completion.start + insert.chars().count();
+        Outcome::Continue
+    }
+
+    fn transpose_chars(&mut self) {
+        if self.buffer.len() < 2 || self.cursor == 0 {
+            return;
+        }
+        // at the end of the line the two chars before the cursor are swapped
+        let i = self.cursor.min(self.buffer.len() - 1);
+        self.buffer.swap(i - 1, i);
+        self.cursor = i + 1;
+    }
+
+    fn transpose_words(&mut self) {
+        let second_end = self.word_end(self.cursor);
+        let second_start = self.word_start(second_end);
+        let first_start = self.word_start(second_start);
+        let first_end = self.word_end(first_start);
+        if first_start >= second_start || first_end > second_start {
+            return;
+        }
+
+        let first: Vec<char> = self.buffer[first_start..first_end].to_vec();
+        let second: Vec<char> = self.buffer[second_start..second_end].to_vec();
+        self.buffer.splice(second_start..second_end, first);
+        self.buffer.splice(first_start..first_end, second);
+        self.cursor = second_end;
+    }
+
+    fn handle_key(&mut self, key: Key, shell: &mut Shell) -> Outcome {
+        if self.search.is_some() {
+            self.handle_search_key(key, shell)
+        }
+        else if self.vi_enabled {
+            self.handle_vi_key(key, shell)
+        }
+        else {
+            self.handle_emacs_key(key, shell)
+        }
+    }
+
+    fn handle_emacs_key(&mut self, key: Key, shell: &mut Shell) -> Outcome {
+        let last = self.last;
+        self.last = LastAction::Other;
+
+        match key {
+            Key::Enter | Key::Ctrl('j') | Key::Ctrl('m') => return Outcome::Accept,
+            Key::Ctrl('c') => return Outcome::Interrupt,
+            Key::Ctrl('d') if self.buffer.is_empty() => return Outcome::Eof,
+            Key::Ctrl('l') => return Outcome::ClearScreen,
+
+            Key::Char(c) => {
+                if last != LastAction::Insert {
+                    self.save_undo();
+                }
+                self.insert(&[c]);
+                self.last = LastAction::Insert;
+            },
+            Key::Tab => return self.complete(shell),
+
+            Key::Ctrl('a') | Key::Home => self.cursor = 0,
+            Key::Ctrl('e') | Key::End => self.cursor = self.buffer.len(),
+            Key::Ctrl('b') | Key::Left => self.cursor = self.cursor.saturating_sub(1),
+            Key::Ctrl('f') | Key::Right if self.has_suggestion() => self.accept_suggestion(true),
+            Key::Ctrl('f') | Key::Right => self.cursor = (self.cursor + 1).min(self.buffer.len()),
+            Key::Alt('b') | Key::CtrlLeft => self.cursor = self.word_start(self.cursor),
+            Key::Alt('f') | Key::CtrlRight if self.has_suggestion() => self.accept_suggestion(false),
+            Key::Alt('f') | Key::CtrlRight => self.cursor = self.word_end(self.cursor),
+
+            Key::Backspace | Key::Ctrl('h') if self.cursor > 0 => {
+                self.save_undo();
+                self.remove(self.cursor - 1, self.cursor);
+            },
+            Key::Ctrl('d') | Key::Delete if self.cursor < self.buffer.len() => {
+                self.save_undo();
+                self.remove(self.cursor, self.cursor + 1);
+            },
+
+            Key::Ctrl('k') | Key::Ctrl('u') | Key::Ctrl('w') | Key::AltBackspace | Key::Alt('d') => {
+                let (start, end) = match key {
+                    Key::Ctrl('k') => (self.cursor, self.buffer.len()),
+                    Key::Ctrl('u') => (0, self.cursor),
+                    Key::Ctrl('w') => (self.big_word_start(self.cursor), self.cursor),
+                    Key::AltBackspace => (self.word_start(self.cursor), self.cursor),
+                    _ => (self.cursor, self.word_end(self.cursor)),
+                };
+                if start < end {
+                    self.save_undo();
+                    self.last = last;
+                    self.kill(start, end);
+                }
+                self.last = LastAction::Kill;
+            },
+            Key::Ctrl('y') if !self.kill_ring.is_empty() => {
+                self.save_undo();
+                self.yank(self.kill_ring.len() - 1);
+            },
+            Key::Alt('y') => {
+                // replace the text just yanked with the previous kill
+                if let LastAction::Yank { len, index } = last {
+                    self.remove(self.cursor - len, self.cursor);
+                    let index = if index == 0 { self.kill_ring.len() - 1 } else { index - 1 };
+                    self.yank(index);
+                }
+            },
+
+            Key::Ctrl('t') => {
+                self.save_undo();
+                self.transpose_chars();
+            },
+            Key::Alt('t') => {
+                self.save_undo();
+                self.transpose_words();
+            },
+            Key::Ctrl('_') => {
+                if let Some((buffer, cursor)) = self.undo.pop() {
+                    self.buffer = buffer;
+                    self.cursor = cursor;
+                }
+            },
+
+            Key::Ctrl('r') => self.start_search(true),
+            Key::Ctrl('s') => self.start_search(false),
+            Key::Alt('r') => return self.pick_history(shell),
+
+            Key::Ctrl('p') | Key::Up => self.browse_history(shell, true),
+            Key::Ctrl('n') | Key::Down => self.browse_history(shell, false),
+            Key::Alt('<') => {
+                while self.history_index != Some(0) && !shell.history().is_empty() {
+                    self.browse_history(shell, true);
+                }
+            },
+            Key::Alt('>') => {
+                while self.history_index.is_some() {
+                    self.browse_history(shell, false);
+                }
+            },
+
+            _ => (),
+        }
+        Outcome::Continue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history::HistorySettings;
+    use std::path::Path;
+
+    /// The keys that type `text`.
+    fn chars(text: &str) -> Vec<Key> {
+        text.chars().map(Key::Char).collect()
+    }
+
+    /// An editor after `keys` were pressed with `history` behind it.
+    fn edit(history: &[&str], keys: &[Key]) -> LineEditor {
+        let mut shell = Shell::new(Path::new(""));
+        for line in history {
+            shell.history_mut().add(line, &HistorySettings::default());
+        }
+        let mut editor = LineEditor::new();
+        for key in keys {
+            editor.handle_key(*key, &mut shell);
+        }
+        editor
+    }
+
+    fn line(history: &[&str], keys: &[Key]) -> (String, usize) {
+        let editor = edit(history, keys);
+        (editor.line(), editor.cursor)
+    }
+
+    #[test]
+    fn moving_and_deleting() {
+        let keys = [chars("echo world"), vec![Key::Ctrl('a'), Key::Alt('f'), Key::Char(' '), Key::Ctrl('e'), Key::Backspace]].concat();
+        assert_eq!(line(&[], &keys), ("echo  worl".to_string(), 10));
+        let keys = [chars("abc"), vec![Key::Home, Key::Delete, Key::Right, Key::Ctrl('d')]].concat();
+        assert_eq!(line(&[], &keys), ("b".to_string(), 1));
+    }
+
+    #[test]
+    fn kills_grow_together_and_yank_back() {
+        let keys = [chars("git commit -m msg"), vec![Key::Ctrl('w'), Key::Ctrl('w'), Key::End, Key::Ctrl('y')]].concat();
+        assert_eq!(line(&[], &keys), ("git commit -m msg".to_string(), 17));
+        // Alt-Y swaps the text just yanked for the kill before it
+        let keys = [chars("one two"), vec![Key::Ctrl('w'), Key::Left, Key::Ctrl('u'), Key::Ctrl('y')]].concat();
+        assert_eq!(line(&[], &keys), ("one ".to_string(), 3));
+        assert_eq!(line(&[], &[keys, vec![Key::Alt('y')]].concat()), ("two ".to_string(), 3));
+    }
+
+    #[test]
+    fn typing_undoes_as_one_step() {
+        let keys = [chars("ls"), vec![Key::Ctrl('u')], chars("cd /tmp"), vec![Key::Ctrl('_')]].concat();
+        assert_eq!(line(&[], &keys), ("".to_string(), 0));
+        let keys = [keys, vec![Key::Ctrl('_')]].concat();
+        assert_eq!(line(&[], &keys), ("ls".to_string(), 2));
+    }
+
+    #[test]
+    fn transposing() {
+        assert_eq!(line(&[], &[chars("sl"), vec![Key::Ctrl('t')]].concat()).0, "ls");
+        assert_eq!(line(&[], &[chars("cat ls"), vec![Key::Alt('t')]].concat()), ("ls cat".to_string(), 6));
+    }
+
+    #[test]
+    fn browsing_history_keeps_the_new_line() {
+        let history = ["make", "make test"];
+        assert_eq!(line(&history, &[chars("ec"), vec![Key::Up]].concat()).0, "make test");
+        assert_eq!(line(&history, &[chars("ec"), vec![Key::Up, Key::Up, Key::Up]].concat()).0, "make");
+        assert_eq!(line(&history, &[chars("ec"), vec![Key::Up, Key::Up, Key::Down, Key::Down]].concat()).0, "ec");
+        assert_eq!(line(&history, &[chars("ec"), vec![Key::Alt('<')]].concat()).0, "make");
+    }
+
+    #[test]
+    fn prompt_widths() {
+        assert_eq!(display_width("\x01\x1b[1m\x02user\x1b[0m> "), 6);
+        assert_eq!(display_width("→ ok"), 4);
+        let (head, prompt) = split_prompt("\x01\x1b[2m\x02~/src\nmain\n$ ");
+        assert_eq!((head.as_str(), prompt.as_str()), ("\x1b[2m~/src\nmain\n", "$ "));
+        assert_eq!(split_prompt("$ "), (String::new(), "$ ".to_string()));
+        // the lines above the input line wrap at the terminal's width
+        assert_eq!(head_rows("~/src\nmain\n", 80), 2);
+        assert_eq!(head_rows(&format!("{}\n\n", "x".repeat(81)), 80), 3);
+    }
+
+    #[test]
+    fn control_keys_end_the_line() {
+        let mut shell = Shell::new(Path::new(""));
+        let mut editor = LineEditor::new();
+        assert_eq!(editor.handle_key(Key::Ctrl('d'), &mut shell), Outcome::Eof);
+        editor.handle_key(Key::Char('x'), &mut shell);
+        assert_eq!(editor.handle_key(Key::Ctrl('d'), &mut shell), Outcome::Continue);
+        assert_eq!(editor.handle_key(Key::Ctrl('c'), &mut shell), Outcome::Interrupt);
+        assert_eq!(editor.handle_key(Key::Enter, &mut shell), Outcome::Accept);
+    }
+}
@@ -0,0 +1,317 @@
+use crate::complete;
+use crate::parser;
+use crate::shell::Shell;
+use std::collections::HashMap;
+
+/// How one char of the line is coloured.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Style {
+    Plain,
+    /// A command that can be run: a builtin, reserved word, alias, function or program.
+    Command,
+    /// A command that cannot be found.
+    Missing,
+    String,
+    Variable,
+    Redirect,
+    Operator,
+    Comment,
+    /// Something the shell will refuse, such as an unterminated quote or `| |`.
+    Error,
+}
+
+impl Style {
+    /// The escape that switches to the style. Each one sets the colour, weight and underline
+    /// so that switching never needs a reset that would end a search match highlight.
+    pub fn sgr(self) -> &'static str {
+        match self {
+            Style::Plain => "\x1b[39;22;24m",
+            Style::Command => "\x1b[32;22;24m",
+            Style::Missing => "\x1b[31;22;24m",
+            Style::String => "\x1b[33;22;24m",
+            Style::Variable => "\x1b[36;22;24m",
+            Style::Redirect | Style::Operator => "\x1b[35;1;24m",
+            Style::Comment => "\x1b[90;22;24m",
+            Style::Error => "\x1b[31;1;4m",
+        }
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Styles a `$` expansion that starts at `start` and returns where it ends.
+fn variable(line: &[char], start: usize, styles: &mut [Style]) -> usize {
+    let mut end = start + 1;
+    match line.get(end) {
+        Some('{') => {
+            end = line[end..].iter().position(|c| *c == '}').map_or(line.len(), |i| end + i + 1);
+        },
+        Some('(') => {
+            let mut depth = 0;
+            end = line.len();
+            for (i, c) in line.iter().enumerate().skip(start + 1) {
+                match c {
+                    '(' => depth += 1,
+                    ')' => depth -= 1,
+                    _ => (),
+                }
+                if depth == 0 {
+                    end = i + 1;
+                    break;
+                }
+            }
+        },
+        Some(c) if "?!$#*@-0123456789".contains(*c) => end += 1,
+        _ => {
+            while end < line.len() && is_name_char(line[end]) {
+                end += 1;
+            }
+        },
+    }
+    // a lone `$` is just a dollar sign
+    let style = if end == start + 1 { Style::Plain } else { Style::Variable };
+    styles[start..end].fill(style);
+    end
+}
+
+/// Styles the quotes and expansions of the word from `start` to `end`. A quote that is
+/// never closed marks the rest of the word, which the parser runs to the end of the
+/// line, as an error.
+fn word(line: &[char], start: usize, end: usize, styles: &mut [Style]) {
+    let mut i = start;
+    while i < end {
+        match line[i] {
+            '\\' => i += 2,
+            '\'' => match line[i + 1..end].iter().position(|c| *c == '\'') {
+                Some(close) => {
+                    styles[i..i + close + 2].fill(Style::String);
+                    i += close + 2;
+                },
+                None => {
+                    styles[i..end].fill(Style::Error);
+                    return;
+                },
+            },
+            '$' => i = variable(&line[..end], i, styles),
+            _ => i += 1,
+        }
+    }
+}
+
+/// Styles every char of `line`, asking `is_command` about the words in command position.
+/// It walks the tokens `parser::parseline` splits the line into, so the colours follow
+/// how the line runs. It never fails: whatever it does not understand is left plain or
+/// marked as an error.
+pub fn highlight(line: &[char], mut is_command: impl FnMut(&str) -> bool) -> Vec<Style> {
+    let mut styles = vec![Style::Plain; line.len()];
+    let comments = parser::comment_chars(&line.iter().collect::<String>());
+    // the line with its comments blanked out, so the tokens line up with its chars
+    let chars: Vec<char> = line.iter().zip(&comments).map(|(c, comment)| if *comment { ' ' } else { *c }).collect();
+    let code: String = chars.iter().collect();
+    let mut offsets: Vec<usize> = code.char_indices().map(|(i, _)| i).collect();
+    offsets.push(code.len());
+    let index = |byte: usize| offsets.binary_search(&byte).unwrap_or_else(|i| i);
+    for (i, comment) in comments.iter().enumerate() {
+        if *comment {
+            styles[i] = Style::Comment;
+        }
+    }
+
+    let tokens: Vec<(usize, usize)> = parser::tokens(&code)
+        .into_iter()
+        .map(|(start, end)| (index(start), index(end)))
+        .collect();
+    let token = |n: usize| tokens.get(n).map(|&(start, end)| chars[start..end].iter().collect::<String>());
+    let mut command_position = true;
+    // no word since the last operator, so another operator there is an error
+    let mut empty = true;
+    // the last redirection while it still waits for its target
+    let mut redirect: Option<(usize, usize)> = None;
+    // `time` was the last word, so `-p` is its option rather than the command
+    let mut timed = false;
+    // the words of an array value are still open
+    let mut array = false;
+    // the next word names a function rather than running a command
+    let mut defining = false;
+
+    for (n, &(start, end)) in tokens.iter().enumerate() {
+        let text = token(n).unwrap_or_default();
+        let next = token(n + 1);
+        match text.as_str() {
+            " " => continue,
+            "<" | ">" | ">>" => {
+                if let Some((start, end)) = redirect {
+                    styles[start..end].fill(Style::Error);
+                }
+                styles[start..end].fill(Style::Redirect);
+                redirect = Some((start, end));
+                continue;
+            },
+            "|" | "||" | "&&" | "&" | "\n" => {
+                if let Some((start, end)) = redirect.take() {
+                    styles[start..end].fill(Style::Error);
+                }
+                if text != "\n" {
+                    // `&` only sends the whole line to the background, so it has to end it
+                    let last = tokens[n + 1..].iter().all(|&(start, end)| chars[start..end] == [' ']);
+                    let valid = !empty && (text != "&" || last);
+                    styles[start..end].fill(if valid { Style::Operator } else { Style::Error });
+                }
+                if text != "&" {
+                    command_position = true;
+                    empty = true;
+                    timed = false;
+                }
+                continue;
+            },
+            "=" => continue,
+            _ => (),
+        }
+
+        word(&chars, start, end, &mut styles);
+        if redirect.take().is_some() {
+            continue;
+        }
+        empty = false;
+        if array {
+            array = !text.ends_with(')');
+            continue;
+        }
+        // any word followed by `=` is an assignment, and its value follows the `=`
+        if next.as_deref() == Some("=") {
+            let value = tokens.get(n + 2).map(|&(start, end)| &chars[start..end]);
+            array = value.is_some_and(|value| value.first() == Some(&'(') && value.last() != Some(&')'));
+            continue;
+        }
+        if n > 0 && token(n - 1).as_deref() == Some("=") {
+            continue;
+        }
+        if !command_position {
+            continue;
+        }
+
+        if defining || (text.ends_with("()") && text.len() > 2) {
+            styles[start..end].fill(Style::Command);
+            defining = false;
+            continue;
+        }
+        if text == "{" || text == "}" {
+            styles[start..end].fill(Style::Operator);
+            continue;
+        }
+        // what the command is only shows once expanded
+        if styles[start..end].iter().any(|style| *style != Style::Plain && *style != Style::String) {
+            command_position = false;
+            continue;
+        }
+        let name = parser::unquote(&text);
+        if timed && name == "-p" {
+            timed = false;
+            continue;
+        }
+        let style = if is_command(&name) { Style::Command } else { Style::Missing };
+        styles[start..end].fill(style);
+        timed = name == "time";
+        defining = name == "function";
+        command_position = timed || defining;
+    }
+    styles
+}
+
+/// Keeps the styles of the line it saw last and the commands it has looked up,
+/// so a keystroke that changes nothing costs nothing and a new one only lexes.
+#[derive(Debug,Default)]
+pub struct Highlighter {
+    line: Vec<char>,
+    styles: Vec<Style>,
+    commands: HashMap<String, bool>,
+}
+
+impl Highlighter {
+    /// Forgets the commands looked up so far, which may have been installed or removed since.
+    pub fn forget_commands(&mut self) {
+        self.commands.clear();
+    }
+
+    pub fn update(&mut self, shell: &Shell, line: &[char]) {
+        if self.line == line && self.styles.len() == line.len() {
+            return;
+        }
+        let commands = &mut self.commands;
+        self.styles = highlight(line, |name| {
+            *commands.entry(name.to_string()).or_insert_with(|| complete::is_command(shell, name))
+        });
+        self.line = line.to_vec();
+    }
+
+    /// The style of each char of `line`, or nothing if the line changed since the last update.
+    pub fn styles(&self, line: &[char]) -> Option<&[Style]> {
+        Some(self.styles.as_slice()).filter(|_| self.line == line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The style of each char of `line`, with `ls`, `echo` and `time` as the only commands.
+    fn styles(line: &str) -> Vec<Style> {
+        let line: Vec<char> = line.chars().collect();
+        highlight(&line, |name| ["ls", "echo", "time"].contains(&name))
+    }
+
+    /// The style of the first char of each place `marks` points at with a `^`.
+    fn marked(line: &str, marks: &str) -> Vec<Style> {
+        let styles = styles(line);
+        marks.char_indices().filter(|(_, c)| *c == '^').map(|(i, _)| styles[i]).collect()
+    }
+
+    #[test]
+    fn commands_are_looked_up_in_command_position() {
+        assert_eq!(
+            marked("ls -l | nope x && echo y", "^  ^  ^ ^    ^ ^  ^    ^"),
+            [Style::Command, Style::Plain, Style::Operator, Style::Missing, Style::Plain,
+             Style::Operator, Style::Command, Style::Plain],
+        );
+    }
+
+    #[test]
+    fn words_end_where_the_parser_ends_them() {
+        // the parser does not split at `;` or double quotes, so neither does the highlighter
+        assert_eq!(marked("ls; echo", "^   ^"), [Style::Missing, Style::Plain]);
+        assert_eq!(marked("echo \"a b\"", "     ^ ^"), [Style::Plain, Style::Plain]);
+        // but it does at `=`, which makes an assignment anywhere on the line
+        assert_eq!(marked("x=ls echo", "^ ^  ^"), [Style::Plain, Style::Plain, Style::Command]);
+        // and it keeps a command substitution whole
+        assert_eq!(marked("echo $(ls | x) ls", "     ^  ^ ^  ^"), [Style::Variable; 4]);
+    }
+
+    #[test]
+    fn quotes_comments_and_redirections() {
+        assert_eq!(marked("echo 'a # b' # c", "     ^   ^   ^ ^"),
+            [Style::String, Style::String, Style::Comment, Style::Comment]);
+        assert_eq!(marked("echo a#b", "      ^"), [Style::Plain]);
+        assert_eq!(marked("echo é # çx ls ' ", "       ^       ^"), [Style::Comment; 2]);
+        assert_eq!(marked("echo 'open", "     ^   ^"), [Style::Error, Style::Error]);
+        assert_eq!(marked("ls > out", "^  ^ ^"), [Style::Command, Style::Redirect, Style::Plain]);
+        assert_eq!(marked("ls > | x", "   ^ ^"), [Style::Error, Style::Operator]);
+    }
+
+    #[test]
+    fn operators_without_a_command_are_errors() {
+        assert_eq!(marked("| ls", "^"), [Style::Error]);
+        assert_eq!(marked("ls && || ls", "   ^  ^"), [Style::Operator, Style::Error]);
+        assert_eq!(marked("ls & echo", "   ^"), [Style::Error]);
+        assert_eq!(marked("ls &", "   ^"), [Style::Operator]);
+    }
+
+    #[test]
+    fn functions_and_keywords() {
+        assert_eq!(marked("greet() {\n  nope\n}", "^       ^   ^    ^"),
+            [Style::Command, Style::Operator, Style::Missing, Style::Operator]);
+        assert_eq!(marked("time -p ls", "^    ^  ^"), [Style::Command, Style::Plain, Style::Command]);
+        assert_eq!(marked("x=(ls nope) ls", "  ^^  ^     ^"), [Style::Plain, Style::Plain, Style::Plain, Style::Command]);
+    }
+}
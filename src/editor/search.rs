@@ -0,0 +1,390 @@
+use super::{is_word_char, read_key, terminal_size, write_out, Key, LineEditor, Outcome};
+use crate::histdb::HistoryDb;
+use crate::shell::Shell;
+use std::collections::HashMap;
+use std::env;
+use std::path::{Path, PathBuf};
+
+
+/// An incremental search through the history started by Ctrl-R or Ctrl-S.
+#[derive(Debug)]
+pub struct Search {
+    query: String,
+    reverse: bool,
+    failed: bool,
+    /// Where the match starts in the line, in chars.
+    position: usize,
+    /// The line and history position to go back to if the search is cancelled.
+    original: (Vec<char>, usize, Option<usize>),
+}
+
+impl Search {
+    pub fn prompt(&self) -> String {
+        format!("({}{}i-search)`{}': ",
+            if self.failed { "failed " } else { "" },
+            if self.reverse { "reverse-" } else { "" },
+            self.query)
+    }
+
+    /// The part of the line to show highlighted.
+    pub fn highlight(&self) -> Option<(usize, usize)> {
+        if self.query.is_empty() || self.failed {
+            return None;
+        }
+        Some((self.position, self.position + self.query.chars().count()))
+    }
+}
+
+
+/// A history entry as the picker ranks it.
+#[derive(Debug)]
+struct Candidate {
+    command: String,
+    /// Index of the newest entry with this command.
+    last: usize,
+    count: usize,
+    cwd: Option<String>,
+}
+
+/// Scores `text` as a fuzzy match for `query`, which must appear in it as a subsequence.
+/// Consecutive matches and matches at the start of words score higher, gaps lower.
+/// Returns the score and the positions of the matched chars.
+fn fuzzy_score(query: &[char], text: &str) -> Option<(i64, Vec<usize>)> {
+    let mut score = 0;
+    let mut positions = Vec::with_capacity(query.len());
+    let mut q = 0;
+    let mut previous: Option<char> = None;
+
+    for (i, c) in text.chars().enumerate() {
+        if q < query.len() && c.to_lowercase().eq(query[q].to_lowercase()) {
+            score += 16;
+            match positions.last() {
+                Some(&last) if last + 1 == i => score += 24,
+                Some(&last) => score -= ((i - last - 1) as i64).min(8),
+                None => (),
+            }
+            if previous.is_none_or(|previous| !is_word_char(previous)) {
+                score += 12;
+            }
+            positions.push(i);
+            q += 1;
+        }
+        previous = Some(c);
+    }
+
+    if q < query.len() {
+        return None;
+    }
+    Some((score - text.len() as i64 / 8, positions))
+}
+
+/// Every distinct command in the history with how often and how recently it was run,
+/// and where it was last run if the structured history knows.
+fn candidates(shell: &Shell) -> Vec<Candidate> {
+    let mut db = HistoryDb::new();
+    db.set_path(shell.lookup_var("HISTDB").map(PathBuf::from).as_deref());
+    let mut cwds = HashMap::new();
+    for record in db.records().unwrap_or_default() {
+        cwds.insert(record.command, record.cwd);
+    }
+
+    let mut candidates: Vec<Candidate> = Vec::new();
+    let mut seen: HashMap<&str, usize> = HashMap::new();
+    for (i, (_, entry)) in shell.history().numbered().enumerate() {
+        match seen.get(entry.line.as_str()) {
+            Some(&index) => {
+                candidates[index].last = i;
+                candidates[index].count += 1;
+            },
+            None => {
+                seen.insert(&entry.line, candidates.len());
+                candidates.push(Candidate {
+                    command: entry.line.clone(),
+                    last: i,
+                    count: 1,
+                    cwd: cwds.get(&entry.line).cloned(),
+                });
+            },
+        }
+    }
+    candidates
+}
+
+/// Orders the candidates matching `query`, best first, with the positions to highlight.
+fn rank<'a>(candidates: &'a [Candidate], query: &str) -> Vec<(&'a Candidate, Vec<usize>)> {
+    let query: Vec<char> = query.chars().collect();
+    let total = candidates.iter().map(|candidate| candidate.last).max().unwrap_or(0) + 1;
+    let mut ranked: Vec<(f64, &Candidate, Vec<usize>)> = candidates.iter()
+        .filter_map(|candidate| {
+            let (score, positions) = fuzzy_score(&query, &candidate.command)?;
+            let recency = candidate.last as f64 / total as f64;
+            let frequency = (candidate.count as f64).ln();
+            Some((score as f64 + 20.0 * recency + 8.0 * frequency, candidate, positions))
+        })
+        .collect();
+    ranked.sort_by(|a, b| b.0.total_cmp(&a.0).then(b.1.last.cmp(&a.1.last)));
+    ranked.into_iter().map(|(_, candidate, positions)| (candidate, positions)).collect()
+}
+
+/// Shortens a directory to `width` columns, `~` for the home directory and an
+/// ellipsis in place of whatever is cut from the front.
+fn short_cwd(cwd: &str, home: &str, width: usize) -> String {
+    let cwd = match Path::new(cwd).strip_prefix(home) {
+        Ok(_) if home.is_empty() || home == "/" => cwd.to_string(),
+        Ok(rest) if rest.as_os_str().is_empty() => "~".to_string(),
+        Ok(rest) => format!("~/{}", rest.display()),
+        Err(_) => cwd.to_string(),
+    };
+    let len = cwd.chars().count();
+    if len <= width {
+        return cwd;
+    }
+    let tail: String = cwd.chars().skip(len + 1 - width).collect();
+    format!("…{}", tail)
+}
+
+fn draw_picker(query: &str, ranked: &[(&Candidate, Vec<usize>)], selected: usize, scroll: usize, total: usize) {
+    let (columns, rows) = terminal_size();
+    let home = env::var("HOME").unwrap_or_default();
+    let mut out = String::from("\x1b[H\x1b[2J");
+    out += &format!("> {}  \x1b[2m{}/{}\x1b[22m", query, ranked.len(), total);
+
+    for (i, (candidate, positions)) in ranked.iter().enumerate().skip(scroll).take(rows.saturating_sub(1)) {
+        let cwd = candidate.cwd.as_deref().map(|cwd| short_cwd(cwd, &home, columns / 3)).unwrap_or_default();
+        let cwd_width = cwd.chars().count();
+        let width = columns.saturating_sub(cwd_width + 3);
+
+        out += "\n";
+        out += if i == selected { "\x1b[7m> " } else { "  " };
+        let mut used = 0;
+        for (position, c) in candidate.command.chars().enumerate().take(width) {
+            let c = if c.is_control() { ' ' } else { c };
+            if positions.contains(&position) {
+                out += &format!("\x1b[1m{}\x1b[22m", c);
+            }
+            else {
+                out.push(c);
+            }
+            used += 1;
+        }
+        out += &" ".repeat(columns.saturating_sub(used + cwd_width + 2));
+        out += &format!("\x1b[2m{}\x1b[0m", cwd);
+    }
+
+    out += &format!("\x1b[1;{}H", query.chars().count() + 3);
+    write_out(&out);
+}
+
+impl LineEditor {
+    pub(super) fn start_search(&mut self, reverse: bool) {
+        self.search = Some(Search {
+            query: String::new(),
+            reverse,
+            failed: false,
+            position: 0,
+            original: (self.buffer.clone(), self.cursor, self.history_index),
+        });
+    }
+
+    /// Looks for the query from history index `start` on, in the direction of the search.
+    /// With `skip_current` the line now shown does not count, so repeating moves on.
+    fn search_from(&mut self, shell: &Shell, start: Option<usize>, skip_current: bool) {
+        let (query, reverse) = match self.search.as_ref() {
+            Some(search) => (search.query.clone(), search.reverse),
+            None => return,
+        };
+        let history = shell.history();
+        let current = self.line();
+        let indices: Box<dyn Iterator<Item = usize>> = match (reverse, start) {
+            (true, Some(start)) => Box::new((0..=start.min(history.len().saturating_sub(1))).rev()),
+            (true, None) => Box::new((0..history.len()).rev()),
+            (false, Some(start)) => Box::new(start..history.len()),
+            (false, None) => Box::new(0..0),
+        };
+
+        let found = indices
+            .filter_map(|index| Some((index, history.get(history.first_number() + index)?)))
+            .filter(|(_, entry)| !skip_current || entry.line != current)
+            .find_map(|(index, entry)| entry.line.find(&query).map(|at| (index, entry.line[..at].chars().count(), &entry.line)));
+
+        if let Some(search) = self.search.as_mut() {
+            search.failed = found.is_none();
+        }
+        if let Some((index, position, line)) = found {
+            if let Some(search) = self.search.as_mut() {
+                search.position = position;
+            }
+            if self.history_index.is_none() {
+                self.saved_line = self.buffer.clone();
+            }
+            self.history_index = Some(index);
+            self.buffer = line.chars().collect();
+            self.cursor = position;
+        }
+    }
+
+    /// Ctrl-R or Ctrl-S during a search: the next match that way, or the last
+    /// search again if nothing was typed yet.
+    fn search_again(&mut self, shell: &Shell, reverse: bool) {
+        let last_search = self.last_search.clone();
+        let search = match self.search.as_mut() {
+            Some(search) => search,
+            None => return,
+        };
+        search.reverse = reverse;
+        if search.query.is_empty() {
+            if last_search.is_empty() {
+                return;
+            }
+            search.query = last_search;
+            self.search_from(shell, self.history_index, false);
+            return;
+        }
+
+        let start = match (reverse, self.history_index) {
+            (true, Some(0)) => {
+                search.failed = true;
+                return;
+            },
+            (true, Some(index)) => Some(index - 1),
+            (false, Some(index)) => Some(index + 1),
+            (_, None) => None,
+        };
+        self.search_from(shell, start, true);
+    }
+
+    fn end_search(&mut self, restore: bool) {
+        if let Some(search) = self.search.take() {
+            if !search.query.is_empty() {
+                self.last_search = search.query;
+            }
+            if restore {
+                (self.buffer, self.cursor, self.history_index) = search.original;
+            }
+        }
+    }
+
+    pub(super) fn handle_search_key(&mut self, key: Key, shell: &mut Shell) -> Outcome {
+        match key {
+            Key::Ctrl('r') => self.search_again(shell, true),
+            Key::Ctrl('s') => self.search_again(shell, false),
+            Key::Char(c) => {
+                if let Some(search) = self.search.as_mut() {
+                    search.query.push(c);
+                }
+                self.search_from(shell, self.history_index, false);
+            },
+            Key::Backspace | Key::Ctrl('h') => {
+                let (original_index, query_left) = match self.search.as_mut() {
+                    Some(search) => {
+                        search.query.pop();
+                        (search.original.2, !search.query.is_empty())
+                    },
+                    None => return Outcome::Continue,
+                };
+                // search again from where it started
+                if let Some(search) = self.search.as_ref() {
+                    (self.buffer, self.cursor) = (search.original.0.clone(), search.original.1);
+                }
+                self.history_index = original_index;
+                if query_left {
+                    self.search_from(shell, original_index, false);
+                }
+                else if let Some(search) = self.search.as_mut() {
+                    search.failed = false;
+                }
+            },
+            Key::Ctrl('g') | Key::Escape => self.end_search(true),
+            Key::Ctrl('c') => {
+                self.end_search(true);
+                return Outcome::Interrupt;
+            },
+            Key::Enter | Key::Ctrl('j') | Key::Ctrl('m') => {
+                self.end_search(false);
+                return Outcome::Accept;
+            },
+            _ => {
+                // any other key ends the search and does what it does on the found line
+                self.end_search(false);
+                return self.handle_key(key, shell);
+            },
+        }
+        Outcome::Continue
+    }
+
+    /// A full screen picker over the whole history that ranks commands by a fuzzy match,
+    /// recency and frequency. The chosen command replaces the line.
+    pub(super) fn pick_history(&mut self, shell: &Shell) -> Outcome {
+        let candidates = candidates(shell);
+        let mut query = String::new();
+        let mut selected = 0;
+        let mut scroll = 0;
+
+        write_out("\x1b[?1049h");
+        let chosen = loop {
+            let ranked = rank(&candidates, &query);
+            let visible = terminal_size().1.saturating_sub(1).max(1);
+            selected = selected.min(ranked.len().saturating_sub(1));
+            if selected < scroll {
+                scroll = selected;
+            }
+            else if selected >= scroll + visible {
+                scroll = selected + 1 - visible;
+            }
+            draw_picker(&query, &ranked, selected, scroll, candidates.len());
+
+            let key = match read_key() {
+                Ok(Some(key)) => key,
+                _ => break None,
+            };
+            match key {
+                Key::Enter => break ranked.get(selected).map(|(candidate, _)| candidate.command.clone()),
+                Key::Escape | Key::Ctrl('g') | Key::Ctrl('c') | Key::Ctrl('d') => break None,
+                Key::Up | Key::Ctrl('p') | Key::Ctrl('k') => selected = selected.saturating_sub(1),
+                Key::Down | Key::Ctrl('n') | Key::Tab => selected += 1,
+                Key::Backspace | Key::Ctrl('h') => {
+                    query.pop();
+                    selected = 0;
+                },
+                Key::Ctrl('u') => {
+                    query.clear();
+                    selected = 0;
+                },
+                Key::Char(c) => {
+                    query.push(c);
+                    selected = 0;
+                },
+                _ => (),
+            }
+        };
+        write_out("\x1b[?1049l");
+
+        if let Some(command) = chosen {
+            self.save_undo();
+            self.history_index = None;
+            self.set_line(command.chars().collect());
+        }
+        Outcome::Continue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn home_is_shown_as_a_tilde() {
+        assert_eq!(short_cwd("/home/bob", "/home/bob", 40), "~");
+        assert_eq!(short_cwd("/home/bob/src", "/home/bob", 40), "~/src");
+        assert_eq!(short_cwd("/home/bob/src", "/home/bob/", 40), "~/src");
+        // only whole directories count, so a sibling whose name starts the same is left alone
+        assert_eq!(short_cwd("/home/bobby", "/home/bob", 40), "/home/bobby");
+        assert_eq!(short_cwd("/tmp", "", 40), "/tmp");
+        assert_eq!(short_cwd("/tmp", "/", 40), "/tmp");
+    }
+
+    #[test]
+    fn long_directories_lose_their_front() {
+        assert_eq!(short_cwd("/usr/local/share", "/home/bob", 8), "…l/share");
+    }
+}
@@ -0,0 +1,101 @@
+use super::{is_word_char, LineEditor};
+use crate::complete;
+use crate::parser;
+use crate::shell::Shell;
+use std::env;
+use std::path::Path;
+
+/// Commands the history database saw run in the working directory, newest first.
+pub(super) fn local_commands(shell: &mut Shell) -> Vec<String> {
+    let db = shell.history_db();
+    if !db.enabled() {
+        return Vec::new();
+    }
+    let cwd = env::current_dir().map(|cwd| cwd.to_string_lossy().into_owned()).unwrap_or_default();
+    db.records().unwrap_or_default().into_iter()
+        .rev()
+        .filter(|record| record.cwd == cwd)
+        .map(|record| record.command.trim_end().to_string())
+        .collect()
+}
+
+/// Whether a word of a suggested command names a file that is gone. Only words that look
+/// like paths are checked, along with whatever `cd` is given.
+fn missing_path(command: &str, word: &str) -> bool {
+    let word = parser::unquote(word);
+    let is_path = word.contains('/') || word.starts_with('~') || word.starts_with('.');
+    if word.is_empty() || !(is_path || command == "cd") || word.contains(['$', '*', '?', '[']) {
+        return false;
+    }
+    match complete::expand_tilde(&word) {
+        Some(path) => !Path::new(&path).exists(),
+        None => true,
+    }
+}
+
+/// Whether every path in what `line` adds to the `typed` text still exists.
+fn paths_exist(line: &str, typed: usize) -> bool {
+    let mut words = Vec::new();
+    let mut start = 0;
+    for (i, c) in line.char_indices().chain([(line.len(), ' ')]) {
+        if c.is_whitespace() || "|&;<>()".contains(c) {
+            if i > start {
+                words.push((start, &line[start..i]));
+            }
+            start = i + c.len_utf8();
+        }
+    }
+    let command = words.first().map_or("", |(_, word)| *word);
+    words.iter()
+        .skip(1)
+        .filter(|(start, word)| start + word.len() > typed)
+        .all(|(_, word)| !missing_path(command, word))
+}
+
+impl LineEditor {
+    /// What the line would most likely become: the newest history entry it starts,
+    /// preferring ones run in this directory, or else the one way to complete it.
+    pub(super) fn suggest(&mut self, shell: &Shell) -> String {
+        if self.cursor != self.buffer.len() || self.search.is_some() || self.buffer.iter().all(|c| c.is_whitespace()) {
+            return String::new();
+        }
+        let line = self.line();
+        // an entry over several lines cannot be shown after the cursor
+        let suggests = |entry: &str| entry.len() > line.len() && entry.starts_with(&line) && !entry.contains('\n') && paths_exist(entry, line.len());
+
+        let history = shell.history().numbered().rev().map(|(_, entry)| entry.line.trim_end());
+        let entry = self.local_commands.iter().map(String::as_str).chain(history).find(|entry| suggests(entry));
+        if let Some(entry) = entry {
+            return entry[line.len()..].to_string();
+        }
+
+        let completion = complete::complete_quietly(shell, &self.buffer, self.cursor, &mut self.listings);
+        let typed: String = self.buffer[completion.start..].iter().collect();
+        match &completion.candidates[..] {
+            [candidate] if candidate.replacement.len() > typed.len() && candidate.replacement.starts_with(&typed) => {
+                candidate.replacement[typed.len()..].to_string()
+            },
+            _ => String::new(),
+        }
+    }
+
+    #[inline]
+    pub(super) fn has_suggestion(&self) -> bool {
+        !self.suggestion.is_empty() && self.cursor == self.buffer.len()
+    }
+
+    /// Takes the suggestion into the line, or just its next word.
+    pub(super) fn accept_suggestion(&mut self, whole: bool) {
+        let suggestion: Vec<char> = self.suggestion.chars().collect();
+        let end = if whole {
+            suggestion.len()
+        }
+        else {
+            let start = suggestion.iter().position(|c| is_word_char(*c)).unwrap_or(suggestion.len());
+            suggestion[start..].iter().position(|c| !is_word_char(*c)).map_or(suggestion.len(), |i| start + i)
+        };
+        self.save_undo();
+        self.insert(&suggestion[..end]);
+        self.suggestion = suggestion[end..].iter().collect();
+    }
+}
@@ -0,0 +1,752 @@
+use super::{is_word_char, Key, LineEditor, Outcome};
+use crate::shell::Shell;
+
+const DEFAULT_INSERT_INDICATOR: &str = "(ins) ";
+const DEFAULT_COMMAND_INDICATOR: &str = "(cmd) ";
+
+
+#[derive(Debug,Default,Clone,Copy,PartialEq,Eq)]
+pub enum ViMode {
+    #[default]
+    Insert,
+    Normal,
+}
+
+/// A change to the line as `.` replays it.
+#[derive(Debug,Clone)]
+struct Change {
+    count: Option<usize>,
+    /// The command keys after the count.
+    command: Vec<char>,
+    /// What was typed in insert mode if the command entered it.
+    inserted: Vec<Key>,
+}
+
+/// State of vi mode that lives on from one line to the next.
+#[derive(Debug,Default)]
+pub struct ViState {
+    pub mode: ViMode,
+    /// Keys of the normal mode command typed so far.
+    pending: Vec<char>,
+    /// The unnamed register filled by d, c and y.
+    register: String,
+    last_change: Option<Change>,
+    /// The change being made, recorded until insert mode is left.
+    recording: Option<Change>,
+    replaying: bool,
+    /// Depth of the undo stack when insert mode was entered, the whole insert undoes as one.
+    insert_undo: usize,
+    /// The last f, F, t or T and its char, for `;` and `,`.
+    last_find: Option<(char, char)>,
+    insert_indicator: String,
+    command_indicator: String,
+}
+
+/// A parsed normal mode command.
+#[derive(Debug)]
+struct Command {
+    count: Option<usize>,
+    /// Index of the first key after the count.
+    start: usize,
+    action: char,
+    /// The target of an operator: a motion, `i`/`a` and a text object, or the operator again.
+    target: Option<(char, Option<char>)>,
+    target_count: Option<usize>,
+    /// The char argument of f, F, t, T and r.
+    arg: Option<char>,
+}
+
+enum Parse {
+    Incomplete,
+    Invalid,
+    Complete(Command),
+}
+
+fn is_motion(c: char) -> bool {
+    "hlwWbBeE0^$|;,% ".contains(c)
+}
+
+fn needs_char(c: char) -> bool {
+    "fFtT".contains(c)
+}
+
+fn parse_count(keys: &[char], i: &mut usize) -> Option<usize> {
+    let start = *i;
+    while *i < keys.len() && keys[*i].is_ascii_digit() && !(*i == start && keys[*i] == '0') {
+        *i += 1;
+    }
+    if *i == start {
+        return None;
+    }
+    keys[start..*i].iter().collect::<String>().parse().ok()
+}
+
+fn parse(keys: &[char]) -> Parse {
+    let mut i = 0;
+    let count = parse_count(keys, &mut i);
+    let start = i;
+    let action = match keys.get(i) {
+        Some(c) => *c,
+        None => return Parse::Incomplete,
+    };
+    i += 1;
+
+    let mut command = Command { count, start, action, target: None, target_count: None, arg: None };
+    match action {
+        'd' | 'c' | 'y' => {
+            command.target_count = parse_count(keys, &mut i);
+            let target = match keys.get(i) {
+                Some(c) => *c,
+                None => return Parse::Incomplete,
+            };
+            if target == 'i' || target == 'a' || needs_char(target) {
+                match keys.get(i + 1) {
+                    Some(c) => command.target = Some((target, Some(*c))),
+                    None => return Parse::Incomplete,
+                }
+            }
+            else if target == action || is_motion(target) {
+                command.target = Some((target, None));
+            }
+            else {
+                return Parse::Invalid;
+            }
+        },
+        'r' | 'f' | 'F' | 't' | 'T' => match keys.get(i) {
+            Some(c) => command.arg = Some(*c),
+            None => return Parse::Incomplete,
+        },
+        _ => (),
+    }
+    Parse::Complete(command)
+}
+
+/// vi's classes of chars: blanks, keyword chars and other punctuation.
+/// With `big` words everything but blanks is one class.
+fn class(c: char, big: bool) -> u8 {
+    if c.is_whitespace() {
+        0
+    }
+    else if big || is_word_char(c) {
+        1
+    }
+    else {
+        2
+    }
+}
+
+fn next_word_start(buffer: &[char], from: usize, big: bool) -> usize {
+    let mut i = from;
+    if i >= buffer.len() {
+        return buffer.len();
+    }
+    let start_class = class(buffer[i], big);
+    while i < buffer.len() && start_class != 0 && class(buffer[i], big) == start_class {
+        i += 1;
+    }
+    while i < buffer.len() && class(buffer[i], big) == 0 {
+        i += 1;
+    }
+    i
+}
+
+fn next_word_end(buffer: &[char], from: usize, big: bool) -> usize {
+    let mut i = from + 1;
+    while i < buffer.len() && class(buffer[i], big) == 0 {
+        i += 1;
+    }
+    if i >= buffer.len() {
+        return buffer.len().saturating_sub(1);
+    }
+    let end_class = class(buffer[i], big);
+    while i + 1 < buffer.len() && class(buffer[i + 1], big) == end_class {
+        i += 1;
+    }
+    i
+}
+
+fn prev_word_start(buffer: &[char], from: usize, big: bool) -> usize {
+    if from == 0 {
+        return 0;
+    }
+    let mut i = from - 1;
+    while i > 0 && class(buffer[i], big) == 0 {
+        i -= 1;
+    }
+    let start_class = class(buffer[i], big);
+    while i > 0 && class(buffer[i - 1], big) == start_class {
+        i -= 1;
+    }
+    i
+}
+
+fn toggle_case(c: char) -> char {
+    if c.is_lowercase() {
+        c.to_uppercase().next().unwrap_or(c)
+    }
+    else {
+        c.to_lowercase().next().unwrap_or(c)
+    }
+}
+
+impl ViState {
+    /// Picks up the mode indicators from the shell, `VI_INS_MODE_STRING` and `VI_CMD_MODE_STRING`.
+    pub fn configure(&mut self, shell: &Shell) {
+        self.insert_indicator = shell.lookup_var("VI_INS_MODE_STRING").unwrap_or_else(|| DEFAULT_INSERT_INDICATOR.to_string());
+        self.command_indicator = shell.lookup_var("VI_CMD_MODE_STRING").unwrap_or_else(|| DEFAULT_COMMAND_INDICATOR.to_string());
+    }
+
+    /// What is shown in front of the prompt for the current mode.
+    pub fn indicator(&self) -> &str {
+        match self.mode {
+            ViMode::Insert => &self.insert_indicator,
+            ViMode::Normal => &self.command_indicator,
+        }
+    }
+}
+
+impl LineEditor {
+    /// Every line starts out in insert mode.
+    pub(super) fn vi_start(&mut self, shell: &Shell) {
+        self.vi.configure(shell);
+        self.vi.pending.clear();
+        self.vi_enter_insert();
+    }
+
+    fn vi_enter_insert(&mut self) {
+        self.save_undo();
+        self.vi.insert_undo = self.undo.len();
+        self.vi.mode = ViMode::Insert;
+    }
+
+    fn vi_escape(&mut self) {
+        self.vi.mode = ViMode::Normal;
+        self.undo.truncate(self.vi.insert_undo);
+        self.cursor = self.cursor.saturating_sub(1);
+        self.vi_finish_change();
+    }
+
+    fn vi_record(&mut self, count: Option<usize>, command: &[char]) {
+        if !self.vi.replaying {
+            self.vi.recording = Some(Change { count, command: command.to_vec(), inserted: Vec::new() });
+        }
+    }
+
+    fn vi_finish_change(&mut self) {
+        if let Some(change) = self.vi.recording.take() {
+            self.vi.last_change = Some(change);
+        }
+    }
+
+    /// Keeps the cursor on a char, as it always is in normal mode.
+    fn vi_clamp(&mut self) {
+        if self.cursor >= self.buffer.len() {
+            self.cursor = self.buffer.len().saturating_sub(1);
+        }
+    }
+
+    pub(super) fn handle_vi_key(&mut self, key: Key, shell: &mut Shell) -> Outcome {
+        match self.vi.mode {
+            ViMode::Insert => self.handle_vi_insert_key(key, shell),
+            ViMode::Normal => {
+                let outcome = self.handle_vi_normal_key(key, shell);
+                if self.vi.mode == ViMode::Normal {
+                    self.vi_clamp();
+                }
+                outcome
+            },
+        }
+    }
+
+    fn handle_vi_insert_key(&mut self, key: Key, shell: &mut Shell) -> Outcome {
+        if key == Key::Escape {
+            self.vi_escape();
+            return Outcome::Continue;
+        }
+        if let Some(change) = self.vi.recording.as_mut() {
+            change.inserted.push(key);
+        }
+        let outcome = self.handle_emacs_key(key, shell);
+        if outcome != Outcome::Continue {
+            self.vi_finish_change();
+        }
+        outcome
+    }
+
+    fn handle_vi_normal_key(&mut self, key: Key, shell: &mut Shell) -> Outcome {
+        let c = match key {
+            Key::Char(c) => c,
+            Key::Enter | Key::Ctrl('j') | Key::Ctrl('m') => return Outcome::Accept,
+            Key::Ctrl('c') => return Outcome::Interrupt,
+            Key::Ctrl('d') if self.buffer.is_empty() => return Outcome::Eof,
+            Key::Ctrl('l') => return Outcome::ClearScreen,
+            Key::Escape => {
+                self.vi.pending.clear();
+                return Outcome::Continue;
+            },
+            Key::Ctrl('r') if self.vi.pending.is_empty() => {
+                self.start_search(true);
+                return Outcome::Continue;
+            },
+            Key::Left | Key::Backspace => 'h',
+            Key::Right => 'l',
+            Key::Home => '0',
+            Key::End => '$',
+            Key::Up => 'k',
+            Key::Down => 'j',
+            Key::Delete => 'x',
+            _ => return Outcome::Continue,
+        };
+
+        self.vi.pending.push(c);
+        let keys = self.vi.pending.clone();
+        match parse(&keys) {
+            Parse::Incomplete => Outcome::Continue,
+            Parse::Invalid => {
+                self.vi.pending.clear();
+                Outcome::Continue
+            },
+            Parse::Complete(command) => {
+                self.vi.pending.clear();
+                self.vi_execute(&command, &keys, shell)
+            },
+        }
+    }
+
+    /// Where a motion repeated `count` times takes the cursor and whether
+    /// an operator includes the char it lands on. None if it cannot move.
+    fn vi_motion(&mut self, motion: char, arg: Option<char>, count: usize) -> Option<(usize, bool)> {
+        let len = self.buffer.len();
+        let mut target = self.cursor;
+        match motion {
+            'h' => target = target.saturating_sub(count),
+            'l' | ' ' => target = (target + count).min(len),
+            'w' | 'W' => for _ in 0..count {
+                target = next_word_start(&self.buffer, target, motion == 'W');
+            },
+            'b' | 'B' => for _ in 0..count {
+                target = prev_word_start(&self.buffer, target, motion == 'B');
+            },
+            'e' | 'E' => {
+                for _ in 0..count {
+                    target = next_word_end(&self.buffer, target, motion == 'E');
+                }
+                return Some((target, true));
+            },
+            '0' => target = 0,
+            '^' => target = self.buffer.iter().position(|c| !c.is_whitespace()).unwrap_or(len),
+            '$' => return Some((len.saturating_sub(1), true)),
+            '|' => target = (count - 1).min(len.saturating_sub(1)),
+            'f' | 'F' | 't' | 'T' | ';' | ',' => {
+                let (find, c) = match motion {
+                    ';' => self.vi.last_find?,
+                    ',' => {
+                        let (find, c) = self.vi.last_find?;
+                        let reversed = match find {
+                            'f' => 'F',
+                            'F' => 'f',
+                            't' => 'T',
+                            _ => 't',
+                        };
+                        (reversed, c)
+                    },
+                    _ => {
+                        self.vi.last_find = Some((motion, arg?));
+                        (motion, arg?)
+                    },
+                };
+                for _ in 0..count {
+                    target = self.vi_find(find, c, target, motion == ';' || motion == ',')?;
+                }
+                return Some((target, find == 'f' || find == 't'));
+            },
+            '%' => return self.vi_match_bracket().map(|target| (target, true)),
+            _ => return None,
+        }
+        Some((target, false))
+    }
+
+    /// Searches for `c` from `from` as f, F, t or T do. When repeating a t or T
+    /// the char right next to the cursor is skipped so the motion makes progress.
+    fn vi_find(&self, find: char, c: char, from: usize, repeat: bool) -> Option<usize> {
+        match find {
+            'f' | 't' => {
+                let skip = if find == 't' && repeat { 2 } else { 1 };
+                let found = (from + skip..self.buffer.len()).find(|i| self.buffer[*i] == c)?;
+                Some(if find == 't' { found - 1 } else { found })
+            },
+            _ => {
+                let skip = if find == 'T' && repeat { 1 } else { 0 };
+                let found = (0..from.checked_sub(skip)?).rev().find(|i| self.buffer[*i] == c)?;
+                Some(if find == 'T' { found + 1 } else { found })
+            },
+        }
+    }
+
+    /// The bracket matching the first one at or after the cursor.
+    fn vi_match_bracket(&self) -> Option<usize> {
+        const PAIRS: [(char, char); 3] = [('(', ')'), ('[', ']'), ('{', '}')];
+        let start = (self.cursor..self.buffer.len()).find(|i| "()[]{}".contains(self.buffer[*i]))?;
+        let c = self.buffer[start];
+        let (open, close, forward) = PAIRS.iter()
+            .find_map(|&(open, close)| {
+                if c == open {
+                    Some((open, close, true))
+                }
+                else if c == close {
+                    Some((open, close, false))
+                }
+                else {
+                    None
+                }
+            })?;
+
+        let mut depth = 0;
+        let mut i = start;
+        loop {
+            if self.buffer[i] == open {
+                depth += 1;
+            }
+            else if self.buffer[i] == close {
+                depth -= 1;
+            }
+            if depth == 0 {
+                return Some(i);
+            }
+            if forward {
+                i += 1;
+                if i >= self.buffer.len() {
+                    return None;
+                }
+            }
+            else {
+                i = i.checked_sub(1)?;
+            }
+        }
+    }
+
+    /// The range of a text object: words, quoted strings or bracketed text,
+    /// `around` including the surrounding blanks, quotes or brackets.
+    fn vi_text_object(&self, around: bool, object: char) -> Option<(usize, usize)> {
+        let len = self.buffer.len();
+        if len == 0 {
+            return None;
+        }
+        match object {
+            'w' | 'W' => {
+                let big = object == 'W';
+                let cursor = self.cursor.min(len - 1);
+                let object_class = class(self.buffer[cursor], big);
+                let mut start = cursor;
+                while start > 0 && class(self.buffer[start - 1], big) == object_class {
+                    start -= 1;
+                }
+                let mut end = cursor + 1;
+                while end < len && class(self.buffer[end], big) == object_class {
+                    end += 1;
+                }
+                if around {
+                    if end < len && self.buffer[end].is_whitespace() {
+                        while end < len && self.buffer[end].is_whitespace() {
+                            end += 1;
+                        }
+                    }
+                    else {
+                        while start > 0 && self.buffer[start - 1].is_whitespace() {
+                            start -= 1;
+                        }
+                    }
+                }
+                Some((start, end))
+            },
+            '"' | '\'' | '`' => {
+                let cursor = self.cursor.min(len - 1);
+                let open = match (0..=cursor).rev().find(|i| self.buffer[*i] == object) {
+                    // the cursor is on a closing quote
+                    Some(open) if open == cursor && self.buffer[..cursor].iter().filter(|c| **c == object).count() % 2 == 1 => {
+                        (0..cursor).rev().find(|i| self.buffer[*i] == object)?
+                    },
+                    Some(open) => open,
+                    None => (cursor..len).find(|i| self.buffer[*i] == object)?,
+                };
+                let close = (open + 1..len).find(|i| self.buffer[*i] == object)?;
+                Some(if around { (open, close + 1) } else { (open + 1, close) })
+            },
+            _ => {
+                let (open, close) = match object {
+                    '(' | ')' | 'b' => ('(', ')'),
+                    '[' | ']' => ('[', ']'),
+                    '{' | '}' | 'B' => ('{', '}'),
+                    '<' | '>' => ('<', '>'),
+                    _ => return None,
+                };
+                let cursor = self.cursor.min(len - 1);
+                let mut depth = 0;
+                let mut start = None;
+                for i in (0..=cursor).rev() {
+                    if self.buffer[i] == close && i != cursor {
+                        depth += 1;
+                    }
+                    else if self.buffer[i] == open {
+                        if depth == 0 {
+                            start = Some(i);
+                            break;
+                        }
+                        depth -= 1;
+                    }
+                }
+                let start = start?;
+                let mut depth = 0;
+                let end = (start + 1..len).find(|i| {
+                    if self.buffer[*i] == open {
+                        depth += 1;
+                    }
+                    else if self.buffer[*i] == close {
+                        if depth == 0 {
+                            return true;
+                        }
+                        depth -= 1;
+                    }
+                    false
+                })?;
+                Some(if around { (start, end + 1) } else { (start + 1, end) })
+            },
+        }
+    }
+
+    /// Applies d, c or y to a target, see `Command::target`.
+    fn vi_operator(&mut self, operator: char, target: (char, Option<char>), count: usize) -> Option<()> {
+        let (start, end) = match target {
+            (motion, _) if motion == operator => (0, self.buffer.len()),
+            ('i', Some(object)) => self.vi_text_object(false, object)?,
+            ('a', Some(object)) => self.vi_text_object(true, object)?,
+            // cw on a word changes to its end like ce
+            ('w', _) | ('W', _) if operator == 'c' && self.buffer.get(self.cursor).is_some_and(|c| !c.is_whitespace()) => {
+                let big = target.0 == 'W';
+                let mut end = self.cursor;
+                for i in 0..count {
+                    let at_end = end + 1 >= self.buffer.len() || class(self.buffer[end + 1], big) != class(self.buffer[end], big);
+                    if i > 0 || !at_end {
+                        end = next_word_end(&self.buffer, end, big);
+                    }
+                }
+                (self.cursor, (end + 1).min(self.buffer.len()))
+            },
+            (motion, arg) => {
+                let (target, inclusive) = self.vi_motion(motion, arg, count)?;
+                let start = self.cursor.min(target);
+                let end = self.cursor.max(target) + if inclusive { 1 } else { 0 };
+                (start, end.min(self.buffer.len()))
+            },
+        };
+
+        if operator == 'y' {
+            self.vi.register = self.buffer[start..end].iter().collect();
+            self.cursor = start;
+            return Some(());
+        }
+        self.save_undo();
+        self.vi.register = self.remove(start, end);
+        self.cursor = start;
+        if operator == 'c' {
+            self.vi.insert_undo = self.undo.len();
+            self.vi.mode = ViMode::Insert;
+        }
+        Some(())
+    }
+
+    fn vi_execute(&mut self, command: &Command, keys: &[char], shell: &mut Shell) -> Outcome {
+        let count = command.count.unwrap_or(1);
+        let change = &keys[command.start..];
+        let len = self.buffer.len();
+
+        match command.action {
+            'd' | 'c' | 'y' => {
+                let target = command.target.unwrap_or(('l', None));
+                let count = count * command.target_count.unwrap_or(1);
+                if command.action != 'y' {
+                    self.vi_record(command.count, change);
+                }
+                if self.vi_operator(command.action, target, count).is_none() {
+                    self.vi.recording = None;
+                }
+            },
+            'x' | 'X' | 's' | 'S' | 'D' | 'C' | 'Y' => {
+                let (operator, motion) = match command.action {
+                    'x' => ('d', 'l'),
+                    'X' => ('d', 'h'),
+                    's' => ('c', 'l'),
+                    'S' => ('c', 'c'),
+                    'D' => ('d', '$'),
+                    'C' => ('c', '$'),
+                    _ => ('y', 'y'),
+                };
+                if operator != 'y' {
+                    self.vi_record(command.count, change);
+                }
+                if self.vi_operator(operator, (motion, None), count).is_none() {
+                    self.vi.recording = None;
+                }
+            },
+            'i' | 'a' | 'I' | 'A' => {
+                self.vi_record(command.count, change);
+                self.cursor = match command.action {
+                    'a' => (self.cursor + 1).min(len),
+                    'I' => self.buffer.iter().position(|c| !c.is_whitespace()).unwrap_or(len),
+                    'A' => len,
+                    _ => self.cursor,
+                };
+                self.vi_enter_insert();
+            },
+            'r' => {
+                if let Some(c) = command.arg.filter(|_| self.cursor + count <= len) {
+                    self.vi_record(command.count, change);
+                    self.save_undo();
+                    for i in self.cursor..self.cursor + count {
+                        self.buffer[i] = c;
+                    }
+                    self.cursor += count - 1;
+                }
+            },
+            '~' => {
+                if len > 0 {
+                    self.vi_record(command.count, change);
+                    self.save_undo();
+                    let end = (self.cursor + count).min(len);
+                    for i in self.cursor..end {
+                        self.buffer[i] = toggle_case(self.buffer[i]);
+                    }
+                    self.cursor = end;
+                }
+            },
+            'p' | 'P' => {
+                let text: Vec<char> = self.vi.register.chars().collect();
+                if !text.is_empty() {
+                    self.vi_record(command.count, change);
+                    self.save_undo();
+                    if command.action == 'p' && len > 0 {
+                        self.cursor += 1;
+                    }
+                    for _ in 0..count {
+                        self.insert(&text);
+                    }
+                    self.cursor -= 1;
+                }
+            },
+            'u' => {
+                for _ in 0..count {
+                    if let Some((buffer, cursor)) = self.undo.pop() {
+                        self.buffer = buffer;
+                        self.cursor = cursor;
+                    }
+                }
+            },
+            '.' => return self.vi_repeat(command.count, shell),
+            '/' | '?' => self.start_search(command.action == '/'),
+            'v' => return Outcome::Edit,
+            'k' | '-' => {
+                for _ in 0..count {
+                    self.browse_history(shell, true);
+                }
+                self.cursor = 0;
+            },
+            'j' | '+' => {
+                for _ in 0..count {
+                    self.browse_history(shell, false);
+                }
+                self.cursor = 0;
+            },
+            motion => {
+                if let Some((target, _)) = self.vi_motion(motion, command.arg, count) {
+                    self.cursor = target;
+                }
+            },
+        }
+
+        if self.vi.mode == ViMode::Normal {
+            self.vi_finish_change();
+        }
+        Outcome::Continue
+    }
+
+    /// `.` runs the last change again, with a new count if one is given.
+    fn vi_repeat(&mut self, count: Option<usize>, shell: &mut Shell) -> Outcome {
+        let change = match self.vi.last_change.clone() {
+            Some(change) => change,
+            None => return Outcome::Continue,
+        };
+        let mut keys: Vec<char> = count.or(change.count).map(|count| count.to_string().chars().collect()).unwrap_or_default();
+        keys.extend(change.command.iter());
+
+        self.vi.replaying = true;
+        if let Parse::Complete(command) = parse(&keys) {
+            self.vi_execute(&command, &keys, shell);
+        }
+        if self.vi.mode == ViMode::Insert {
+            for key in change.inserted.iter() {
+                self.handle_emacs_key(*key, shell);
+            }
+            self.vi_escape();
+        }
+        self.vi.replaying = false;
+        Outcome::Continue
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    /// The line and cursor after typing `keys` in vi mode, with ESC written as `\x1b`.
+    fn vi(keys: &str) -> (String, usize) {
+        let mut shell = Shell::new(Path::new(""));
+        let mut editor = LineEditor::new();
+        editor.vi_enabled = true;
+        editor.vi_start(&shell);
+        for c in keys.chars() {
+            let key = if c == '\x1b' { Key::Escape } else { Key::Char(c) };
+            editor.handle_key(key, &mut shell);
+        }
+        (editor.line(), editor.cursor)
+    }
+
+    #[test]
+    fn motions() {
+        assert_eq!(vi("echo hello world\x1b0w"), ("echo hello world".to_string(), 5));
+        assert_eq!(vi("echo hello world\x1b02w"), ("echo hello world".to_string(), 11));
+        assert_eq!(vi("echo hello world\x1bb"), ("echo hello world".to_string(), 11));
+        assert_eq!(vi("echo hello world\x1b0fo;"), ("echo hello world".to_string(), 9));
+        assert_eq!(vi("echo hello world\x1b0$"), ("echo hello world".to_string(), 15));
+        assert_eq!(vi("echo (a b)\x1b%"), ("echo (a b)".to_string(), 5));
+    }
+
+    #[test]
+    fn operators_and_text_objects() {
+        assert_eq!(vi("echo hello world\x1b0wdw").0, "echo world");
+        assert_eq!(vi("echo hello world\x1b0d2w").0, "world");
+        assert_eq!(vi("echo hello world\x1b0wcwbye\x1b").0, "echo bye world");
+        assert_eq!(vi("echo \"hi there\" x\x1b0f\"ci\"new\x1b").0, "echo \"new\" x");
+        assert_eq!(vi("echo (a b) x\x1b0fada(").0, "echo  x");
+        assert_eq!(vi("echo hello\x1b0wD").0, "echo ");
+        assert_eq!(vi("echo hello\x1b0dd").0, "");
+    }
+
+    #[test]
+    fn yank_put_and_replace() {
+        assert_eq!(vi("one two\x1b0yiwA \x1bp").0, "one two one");
+        assert_eq!(vi("abc\x1b0xp").0, "bac");
+        assert_eq!(vi("abc\x1b0rz").0, "zbc");
+        assert_eq!(vi("abc\x1b0~~").0, "ABc");
+    }
+
+    #[test]
+    fn dot_repeats_and_u_undoes() {
+        assert_eq!(vi("a b c d\x1b0x..").0, " c d");
+        assert_eq!(vi("one two three\x1b0dw.").0, "three");
+        assert_eq!(vi("x\x1bAyz\x1b.").0, "xyzyz");
+        // a whole insert undoes at once
+        assert_eq!(vi("ls\x1bA -la\x1bu").0, "ls");
+        assert_eq!(vi("echo hi\x1b0dwu").0, "echo hi");
+    }
+}
@@ -3,7 +3,8 @@
 
 #[derive(Debug)]
 pub enum Command {
-    
+    /// Lines run one after another like a script, such as the body of a function.
+    Script(String),
 }
 
 
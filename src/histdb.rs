@@ -0,0 +1,347 @@
+use nix::fcntl::{flock, FlockArg};
+use nix::unistd::gethostname;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// First line of every database file, bumped if the record layout ever changes.
+const HEADER: &str = "#rshell-histdb 1";
+
+
+/// One command run at the prompt and what came of it.
+#[derive(Debug,Clone,PartialEq)]
+pub struct HistoryRecord {
+    /// Seconds since the epoch when the command was started.
+    pub timestamp: u64,
+    pub duration: Duration,
+    pub status: i32,
+    pub hostname: String,
+    pub session: String,
+    pub cwd: String,
+    pub command: String,
+}
+
+fn escape(field: &str) -> String {
+    let mut escaped = String::with_capacity(field.len());
+    for c in field.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape(field: &str) -> String {
+    let mut unescaped = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => unescaped.push('\t'),
+            Some('n') => unescaped.push('\n'),
+            Some(c) => unescaped.push(c),
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+impl HistoryRecord {
+    /// A record is one line of tab separated fields with tabs, newlines and
+    /// backslashes escaped, the command last.
+    fn to_line(&self) -> String {
+        format!("{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+            self.timestamp,
+            self.duration.as_millis(),
+            self.status,
+            escape(&self.hostname),
+            escape(&self.session),
+            escape(&self.cwd),
+            escape(&self.command))
+    }
+
+    fn parse(line: &str) -> Option<HistoryRecord> {
+        let fields: Vec<&str> = line.splitn(7, '\t').collect();
+        if fields.len() != 7 {
+            return None;
+        }
+        Some(HistoryRecord {
+            timestamp: fields[0].parse().ok()?,
+            duration: Duration::from_millis(fields[1].parse().ok()?),
+            status: fields[2].parse().ok()?,
+            hostname: unescape(fields[3]),
+            session: unescape(fields[4]),
+            cwd: unescape(fields[5]),
+            command: unescape(fields[6]),
+        })
+    }
+}
+
+
+/// Which records `history --cwd/--failed/--since` shows.
+#[derive(Debug,Default,Clone)]
+pub struct Query {
+    pub cwd: Option<String>,
+    pub failed: bool,
+    pub since: Option<u64>,
+}
+
+impl Query {
+    pub fn matches(&self, record: &HistoryRecord) -> bool {
+        self.cwd.as_ref().is_none_or(|cwd| *cwd == record.cwd)
+            && (!self.failed || record.status != 0)
+            && self.since.is_none_or(|since| record.timestamp >= since)
+    }
+}
+
+/// Parses a `--since` argument: seconds since the epoch, a `YYYY-MM-DD` date
+/// or an age such as `30m`, `2h`, `1d` or `1w`.
+pub fn parse_since(spec: &str) -> Option<u64> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+
+    if let Some(unit) = spec.chars().last().filter(|c| c.is_ascii_alphabetic()) {
+        let amount: u64 = spec[..spec.len() - 1].parse().ok()?;
+        let seconds = match unit {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            _ => return None,
+        };
+        return Some(now.saturating_sub(amount * seconds));
+    }
+
+    let parts: Vec<&str> = spec.split('-').collect();
+    if let [year, month, day] = parts[..] {
+        let (year, month, day): (i64, i64, i64) = (year.parse().ok()?, month.parse().ok()?, day.parse().ok()?);
+        // days since the epoch of a proleptic Gregorian date, midnight UTC
+        let (y, m) = if month <= 2 { (year - 1, month + 9) } else { (year, month - 3) };
+        let era = y.div_euclid(400);
+        let year_of_era = y - era * 400;
+        let day_of_year = (153 * m + 2) / 5 + day - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146097 + day_of_era - 719468;
+        return u64::try_from(days * 24 * 60 * 60).ok();
+    }
+
+    spec.parse().ok()
+}
+
+
+/// An append-only log of `HistoryRecord`s shared by every session writing to the same file.
+#[derive(Debug)]
+pub struct HistoryDb {
+    path: Option<PathBuf>,
+    hostname: String,
+    session: String,
+}
+
+impl HistoryDb {
+    pub fn new() -> HistoryDb {
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs());
+        HistoryDb {
+            path: None,
+            hostname: gethostname().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default(),
+            session: format!("{}-{}", std::process::id(), started),
+        }
+    }
+
+    /// Set from HISTDB, recording is off while there is no path.
+    pub fn set_path(&mut self, path: Option<&Path>) {
+        self.path = path.filter(|path| !path.as_os_str().is_empty()).map(Path::to_path_buf);
+    }
+
+    #[inline]
+    pub fn enabled(&self) -> bool {
+        self.path.is_some()
+    }
+
+    fn lock(file: &File, arg: FlockArg) -> io::Result<()> {
+        flock(file.as_raw_fd(), arg).map_err(io::Error::from)
+    }
+
+    /// Opens and locks the file, trying again if a compaction replaced it while we waited.
+    fn open_for_append(path: &Path) -> io::Result<File> {
+        loop {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            HistoryDb::lock(&file, FlockArg::LockExclusive)?;
+            match fs::metadata(path) {
+                Ok(metadata) if metadata.ino() == file.metadata()?.ino() => return Ok(file),
+                _ => HistoryDb::lock(&file, FlockArg::Unlock)?,
+            }
+        }
+    }
+
+    /// Appends a record for a command that just finished.
+    pub fn record(&self, command: &str, cwd: &str, status: i32, started: SystemTime, duration: Duration) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let record = HistoryRecord {
+            timestamp: started.duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs()),
+            duration,
+            status,
+            hostname: self.hostname.clone(),
+            session: self.session.clone(),
+            cwd: cwd.to_string(),
+            command: command.trim_end_matches('\n').to_string(),
+        };
+
+        let mut file = HistoryDb::open_for_append(path)?;
+        let mut line = String::new();
+        if file.metadata()?.len() == 0 {
+            line += HEADER;
+            line.push('\n');
+        }
+        line += &record.to_line();
+        let result = file.write_all(line.as_bytes());
+        HistoryDb::lock(&file, FlockArg::Unlock)?;
+        result
+    }
+
+    /// Every readable record in the order they were written, damaged lines are skipped.
+    pub fn records(&self) -> io::Result<Vec<HistoryRecord>> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(Vec::new()),
+        };
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        HistoryDb::lock(&file, FlockArg::LockShared)?;
+        let mut records = Vec::new();
+        for line in BufReader::new(&file).lines() {
+            let line = line?;
+            if line.starts_with('#') {
+                continue;
+            }
+            records.extend(HistoryRecord::parse(&line));
+        }
+        HistoryDb::lock(&file, FlockArg::Unlock)?;
+        Ok(records)
+    }
+
+    pub fn query(&self, query: &Query) -> io::Result<Vec<HistoryRecord>> {
+        Ok(self.records()?.into_iter().filter(|record| query.matches(record)).collect())
+    }
+
+    /// Rewrites the file without damaged lines, keeping the newest `limit` records.
+    /// The new file replaces the old one atomically. Returns how many records were dropped.
+    pub fn compact(&self, limit: Option<usize>) -> io::Result<usize> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(0),
+        };
+        let file = OpenOptions::new().read(true).open(path)?;
+        // held across the rename so no append lands in the file being replaced
+        HistoryDb::lock(&file, FlockArg::LockExclusive)?;
+
+        let mut lines = 0;
+        let mut records = Vec::new();
+        for line in BufReader::new(&file).lines() {
+            let line = line?;
+            if line.starts_with('#') {
+                continue;
+            }
+            lines += 1;
+            records.extend(HistoryRecord::parse(&line));
+        }
+        if let Some(limit) = limit {
+            if records.len() > limit {
+                records.drain(..records.len() - limit);
+            }
+        }
+
+        let mut contents = format!("{}\n", HEADER);
+        for record in records.iter() {
+            contents += &record.to_line();
+        }
+        let temporary = path.with_extension(format!("compact-{}", std::process::id()));
+        fs::write(&temporary, contents)?;
+        fs::rename(&temporary, path)?;
+
+        HistoryDb::lock(&file, FlockArg::Unlock)?;
+        Ok(lines - records.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_db(name: &str) -> HistoryDb {
+        let path = std::env::temp_dir().join(format!("rshell-histdb-{}-{}", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        let mut db = HistoryDb::new();
+        db.set_path(Some(&path));
+        db
+    }
+
+    fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    #[test]
+    fn since_takes_dates_ages_and_epoch_seconds() {
+        assert_eq!(parse_since("1970-01-01"), Some(0));
+        assert_eq!(parse_since("2000-03-01"), Some(951868800));
+        assert_eq!(parse_since("2024-02-29"), Some(1709164800));
+        assert_eq!(parse_since("1700000000"), Some(1700000000));
+        let hour_ago = parse_since("1h").unwrap();
+        assert!(now() - 3600 - hour_ago <= 1);
+        let weeks_ago = parse_since("2w").unwrap();
+        assert!(now() - 14 * 24 * 3600 - weeks_ago <= 1);
+        assert_eq!(parse_since("3y"), None);
+        assert_eq!(parse_since("h"), None);
+        assert_eq!(parse_since("2024-13"), None);
+        assert_eq!(parse_since("yesterday"), None);
+    }
+
+    #[test]
+    fn records_round_trip_through_the_file() {
+        let db = temp_db("round-trip");
+        let started = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        db.record("printf 'a\\tb'\necho \\\n", "/tmp/with\ttab", 3, started, Duration::from_millis(1500)).unwrap();
+        let records = db.records().unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].command, "printf 'a\\tb'\necho \\");
+        assert_eq!(records[0].cwd, "/tmp/with\ttab");
+        assert_eq!((records[0].timestamp, records[0].status), (1_700_000_000, 3));
+        assert_eq!(records[0].duration, Duration::from_millis(1500));
+        fs::remove_file(db.path.as_ref().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn compact_drops_damaged_lines_and_old_records() {
+        let db = temp_db("compact");
+        let started = SystemTime::now();
+        for (command, status) in [("one", 0), ("two", 1), ("three", 0), ("four", 1)] {
+            db.record(command, "/", status, started, Duration::ZERO).unwrap();
+        }
+        let path = db.path.clone().unwrap();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"not a record\n").unwrap();
+
+        assert_eq!(db.compact(Some(3)).unwrap(), 2);
+        let commands: Vec<String> = db.records().unwrap().into_iter().map(|record| record.command).collect();
+        assert_eq!(commands, ["two", "three", "four"]);
+        assert!(fs::read_to_string(&path).unwrap().starts_with(HEADER));
+
+        let failed = Query { failed: true, ..Query::default() };
+        assert_eq!(db.query(&failed).unwrap().len(), 2);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,944 @@
+use crate::glob;
+use nix::fcntl::{flock, FlockArg};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const DEFAULT_HISTSIZE: usize = 500;
+
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct HistoryEntry {
+    pub line: String,
+    /// Seconds since the epoch when the line was entered.
+    pub timestamp: u64,
+}
+
+/// The knobs bash reads from HISTSIZE, HISTFILESIZE, HISTCONTROL and HISTIGNORE.
+#[derive(Debug,Default,Clone)]
+pub struct HistorySettings {
+    /// Entries kept in memory, `None` for no limit.
+    pub size: Option<usize>,
+    /// Entries kept in the history file, `None` for no limit.
+    pub file_size: Option<usize>,
+    pub ignore_space: bool,
+    pub ignore_dups: bool,
+    pub erase_dups: bool,
+    /// Colon separated patterns a whole line is matched against, `&` is the previous line.
+    pub ignore: Vec<String>,
+}
+
+impl HistorySettings {
+    /// Builds the settings from the values of the shell variables of the same names.
+    pub fn from_vars(histsize: Option<String>, histfilesize: Option<String>, histcontrol: Option<String>, histignore: Option<String>) -> HistorySettings {
+        // like bash, a negative or non-numeric size means unlimited
+        let size = match histsize {
+            Some(size) => size.trim().parse::<usize>().ok(),
+            None => Some(DEFAULT_HISTSIZE),
+        };
+        let file_size = match histfilesize {
+            Some(size) => size.trim().parse::<usize>().ok(),
+            None => size,
+        };
+
+        let mut settings = HistorySettings {
+            size,
+            file_size,
+            ..HistorySettings::default()
+        };
+        for control in histcontrol.unwrap_or_default().split(':') {
+            match control {
+                "ignorespace" => settings.ignore_space = true,
+                "ignoredups" => settings.ignore_dups = true,
+                "ignoreboth" => {
+                    settings.ignore_space = true;
+                    settings.ignore_dups = true;
+                },
+                "erasedups" => settings.erase_dups = true,
+                _ => (),
+            }
+        }
+        settings.ignore = histignore.unwrap_or_default()
+            .split(':')
+            .filter(|pattern| !pattern.is_empty())
+            .map(|pattern| pattern.to_string())
+            .collect();
+        settings
+    }
+}
+
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs())
+}
+
+/// Holds the file locked until dropped, so sessions sharing a history file do not interleave.
+struct LockedFile(File);
+
+impl LockedFile {
+    fn lock(file: File, arg: FlockArg) -> io::Result<LockedFile> {
+        flock(file.as_raw_fd(), arg).map_err(io::Error::from)?;
+        Ok(LockedFile(file))
+    }
+}
+
+impl Drop for LockedFile {
+    fn drop(&mut self) {
+        let _ = flock(self.0.as_raw_fd(), FlockArg::Unlock);
+    }
+}
+
+/// Whether a line of the history file goes on in the next one, which it does
+/// when it ends in a backslash that is not itself escaped.
+fn continues(line: &str) -> bool {
+    (line.len() - line.trim_end_matches('\\').len()) % 2 == 1
+}
+
+/// Reads a history file written by `write_entries`, `#<seconds>` lines carry the
+/// timestamp of the entry that follows them like bash's HISTTIMEFORMAT files.
+/// A line ending in an unpaired backslash is continued by the next one.
+fn read_entries(file: &File) -> io::Result<Vec<HistoryEntry>> {
+    let mut entries: Vec<HistoryEntry> = Vec::new();
+    let mut timestamp: Option<(u64, String)> = None;
+    let mut continued = false;
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        let more = continues(&line);
+        let text = if more { &line[..line.len() - 1] } else { line.as_str() };
+        if continued {
+            if let Some(entry) = entries.last_mut() {
+                entry.line.push('\n');
+                entry.line.push_str(text);
+            }
+            continued = more;
+            continue;
+        }
+        // right after a timestamp comes a command, even one that looks like a timestamp
+        if timestamp.is_none() {
+            if let Some(seconds) = line.strip_prefix('#').and_then(|time| time.parse::<u64>().ok()) {
+                timestamp = Some((seconds, line));
+                continue;
+            }
+        }
+        entries.push(HistoryEntry {
+            line: text.to_string(),
+            timestamp: timestamp.take().map_or(0, |(seconds, _)| seconds),
+        });
+        continued = more;
+    }
+    // with no command after it, the last `#<digits>` line was a command itself
+    if let Some((_, line)) = timestamp {
+        entries.push(HistoryEntry { line, timestamp: 0 });
+    }
+    Ok(entries)
+}
+
+fn write_entries(file: &mut File, entries: &[HistoryEntry]) -> io::Result<()> {
+    let mut buffer = String::new();
+    for entry in entries {
+        buffer += &format!("#{}\n{}\n", entry.timestamp, entry.line.replace('\n', "\\\n"));
+    }
+    file.write_all(buffer.as_bytes())
+}
+
+/// Cuts the history file down to its newest `file_size` entries, returning the entries kept.
+fn trim_file(file: &mut File, file_size: Option<usize>) -> io::Result<Vec<HistoryEntry>> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut entries = read_entries(file)?;
+    if let Some(file_size) = file_size {
+        if entries.len() > file_size {
+            entries.drain(..entries.len() - file_size);
+            file.set_len(0)?;
+            file.seek(SeekFrom::Start(0))?;
+            write_entries(file, &entries)?;
+        }
+    }
+    Ok(entries)
+}
+
+
+#[derive(Debug)]
+pub struct History {
+    path: Option<PathBuf>,
+    entries: Vec<HistoryEntry>,
+    /// How many entries were dropped from the front, so numbers stay stable when trimming.
+    offset: usize,
+    /// Number of the first entry added by this session, for `history -a`.
+    session_start: usize,
+    /// The pattern and replacement of the last `:s`, reused by `:&` and `^old^new`.
+    last_substitution: Option<(String, String)>,
+    /// Whether adding to the file failed, which is reported once until it works again
+    /// or the file changes.
+    write_failed: bool,
+}
+
+impl History {
+    /// An empty history saved to `path`, an empty path keeps it in memory only.
+    pub fn new(path: &Path) -> History {
+        History {
+            path: if path.as_os_str().is_empty() { None } else { Some(path.to_path_buf()) },
+            entries: Vec::new(),
+            offset: 0,
+            session_start: 1,
+            last_substitution: None,
+            write_failed: false,
+        }
+    }
+
+    pub fn set_path(&mut self, path: &Path) {
+        let path = if path.as_os_str().is_empty() { None } else { Some(path.to_path_buf()) };
+        if path != self.path {
+            self.path = path;
+            self.write_failed = false;
+        }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The history number of the first entry still held.
+    #[inline]
+    pub fn first_number(&self) -> usize {
+        self.offset + 1
+    }
+
+    /// Looks an entry up by its history number.
+    pub fn get(&self, number: usize) -> Option<&HistoryEntry> {
+        number.checked_sub(self.first_number()).and_then(|index| self.entries.get(index))
+    }
+
+    /// Number of the newest entry, 0 when there is none.
+    #[inline]
+    pub fn last_number(&self) -> usize {
+        self.offset + self.entries.len()
+    }
+
+    /// Every entry held, paired with its history number.
+    pub fn numbered(&self) -> impl DoubleEndedIterator<Item = (usize, &HistoryEntry)> {
+        let first = self.first_number();
+        self.entries.iter().enumerate().map(move |(i, entry)| (first + i, entry))
+    }
+
+    /// Number of the newest entry up to `end` that starts with `prefix`.
+    pub fn find_prefix(&self, prefix: &str, end: usize) -> Option<usize> {
+        self.numbered().rev()
+            .find(|(number, entry)| *number <= end && entry.line.starts_with(prefix))
+            .map(|(number, _)| number)
+    }
+
+    /// Forgets every entry, numbering starts over at 1.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.offset = 0;
+        self.session_start = 1;
+    }
+
+    /// Removes one entry, the ones after it move down a number.
+    pub fn delete(&mut self, number: usize) -> bool {
+        match number.checked_sub(self.first_number()) {
+            Some(index) if index < self.entries.len() => {
+                self.entries.remove(index);
+                if number < self.session_start {
+                    self.session_start -= 1;
+                }
+                true
+            },
+            _ => false,
+        }
+    }
+
+    fn target<'a>(&'a self, path: Option<&'a Path>) -> io::Result<&'a Path> {
+        path.or(self.path.as_deref())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "HISTFILE not set"))
+    }
+
+    /// Overwrites the history file, or `path`, with the newest `settings.file_size` entries held.
+    pub fn write(&mut self, path: Option<&Path>, settings: &HistorySettings) -> io::Result<()> {
+        let file = OpenOptions::new().create(true).write(true).truncate(false).open(self.target(path)?)?;
+        let mut file = LockedFile::lock(file, FlockArg::LockExclusive)?;
+        file.0.set_len(0)?;
+        let start = settings.file_size.map_or(0, |size| self.entries.len().saturating_sub(size));
+        write_entries(&mut file.0, &self.entries[start..])?;
+        self.session_start = self.last_number() + 1;
+        Ok(())
+    }
+
+    /// Appends the entries of the history file, or `path`, to the ones held.
+    pub fn read(&mut self, path: Option<&Path>, settings: &HistorySettings) -> io::Result<()> {
+        let file = File::open(self.target(path)?)?;
+        let file = LockedFile::lock(file, FlockArg::LockShared)?;
+        let entries = read_entries(&file.0)?;
+        self.entries.extend(entries);
+        self.trim(settings.size);
+        Ok(())
+    }
+
+    /// Appends this session's entries to `path` and trims it to `settings.file_size`. The
+    /// history file itself already receives every line as it is entered, so for it there
+    /// is nothing left to do.
+    pub fn append_session(&mut self, path: Option<&Path>, settings: &HistorySettings) -> io::Result<()> {
+        let target = self.target(path)?;
+        if Some(target) != self.path.as_deref() {
+            let file = OpenOptions::new().create(true).read(true).append(true).open(target)?;
+            let mut file = LockedFile::lock(file, FlockArg::LockExclusive)?;
+            let start = self.session_start.saturating_sub(self.first_number()).min(self.entries.len());
+            write_entries(&mut file.0, &self.entries[start..])?;
+            trim_file(&mut file.0, settings.file_size)?;
+        }
+        self.session_start = self.last_number() + 1;
+        Ok(())
+    }
+
+    /// Loads the history file, trimming it to `settings.file_size` first.
+    pub fn load(&mut self, settings: &HistorySettings) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let file = match OpenOptions::new().read(true).write(true).open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let mut file = LockedFile::lock(file, FlockArg::LockExclusive)?;
+
+        self.entries = trim_file(&mut file.0, settings.file_size)?;
+        self.offset = 0;
+        self.trim(settings.size);
+        self.session_start = self.last_number() + 1;
+        Ok(())
+    }
+
+    /// Whether HISTCONTROL or HISTIGNORE keep `line` out of the history.
+    fn ignored(&self, line: &str, settings: &HistorySettings) -> bool {
+        let previous = self.entries.last().map(|entry| entry.line.as_str());
+        if settings.ignore_space && line.starts_with(' ') {
+            return true;
+        }
+        if settings.ignore_dups && previous == Some(line) {
+            return true;
+        }
+        settings.ignore.iter().any(|pattern| {
+            if pattern == "&" {
+                previous == Some(line)
+            }
+            else {
+                glob::matches(pattern, line)
+            }
+        })
+    }
+
+    /// Records a line that is about to be run and appends it to the history file.
+    /// Returns false if the settings kept it out.
+    pub fn add(&mut self, line: &str, settings: &HistorySettings) -> bool {
+        let line = line.trim_end_matches('\n');
+        if line.trim().is_empty() || self.ignored(line, settings) {
+            return false;
+        }
+
+        if settings.erase_dups {
+            self.entries.retain(|entry| entry.line != line);
+        }
+
+        let entry = HistoryEntry {
+            line: line.to_string(),
+            timestamp: now(),
+        };
+        match self.append_to_file(&entry, settings) {
+            Ok(()) => self.write_failed = false,
+            // like bash, say so once rather than after every command
+            Err(e) if !self.write_failed => {
+                eprintln!("history: {}", e);
+                self.write_failed = true;
+            },
+            Err(_) => (),
+        }
+        self.entries.push(entry);
+        self.trim(settings.size);
+        true
+    }
+
+    /// Adds an entry to the end of the history file, keeping it to `settings.file_size`.
+    fn append_to_file(&self, entry: &HistoryEntry, settings: &HistorySettings) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let file = OpenOptions::new().create(true).read(true).append(true).open(path)?;
+        let mut file = LockedFile::lock(file, FlockArg::LockExclusive)?;
+        write_entries(&mut file.0, std::slice::from_ref(entry))?;
+        trim_file(&mut file.0, settings.file_size)?;
+        Ok(())
+    }
+
+    /// Keeps only the newest `size` entries in memory.
+    pub fn trim(&mut self, size: Option<usize>) {
+        if let Some(size) = size {
+            if self.entries.len() > size {
+                let excess = self.entries.len() - size;
+                self.entries.drain(..excess);
+                self.offset += excess;
+            }
+        }
+    }
+}
+
+
+/// The result of history expansion on an entered line.
+#[derive(Debug,Clone,PartialEq)]
+pub struct Expansion {
+    pub line: String,
+    /// Something was substituted, the shell echoes the new line like csh and bash do.
+    pub changed: bool,
+    /// A `:p` modifier was given, the line is recorded and printed but not run.
+    pub print_only: bool,
+}
+
+/// A word number in a word designator, `$` being the last word.
+#[derive(Debug,Clone,Copy)]
+enum WordIndex {
+    Nth(usize),
+    Last,
+    /// `x-` stops before the last word.
+    BeforeLast,
+}
+
+/// Splits a history line into words, quoted strings count as one word.
+fn split_words(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut quote = None;
+    for c in line.chars() {
+        match quote {
+            Some(q) => {
+                word.push(c);
+                if c == q {
+                    quote = None;
+                }
+            },
+            None if c.is_whitespace() => {
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+            },
+            None => {
+                if c == '\'' || c == '"' {
+                    quote = Some(c);
+                }
+                word.push(c);
+            },
+        }
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+fn parse_number(chars: &[char], i: &mut usize) -> Option<usize> {
+    let start = *i;
+    while chars.get(*i).is_some_and(|c| c.is_ascii_digit()) {
+        *i += 1;
+    }
+    chars[start..*i].iter().collect::<String>().parse().ok()
+}
+
+/// Reads `:s/old/new/` style arguments after the delimiter at `chars[*i]`.
+fn parse_substitution(chars: &[char], i: &mut usize) -> (String, String) {
+    let delimiter = chars[*i];
+    *i += 1;
+    let mut parts = [String::new(), String::new()];
+    for part in parts.iter_mut() {
+        while let Some(&c) = chars.get(*i) {
+            *i += 1;
+            if c == delimiter {
+                break;
+            }
+            if c == '\\' && chars.get(*i) == Some(&delimiter) {
+                part.push(delimiter);
+                *i += 1;
+                continue;
+            }
+            part.push(c);
+        }
+    }
+    let [old, new] = parts;
+    (old, new)
+}
+
+fn substitute(text: &str, old: &str, new: &str, global: bool) -> Option<String> {
+    if old.is_empty() || !text.contains(old) {
+        return None;
+    }
+    let new = new.replace('&', old);
+    Some(if global { text.replace(old, &new) } else { text.replacen(old, &new, 1) })
+}
+
+impl History {
+    fn previous(&self) -> Result<&HistoryEntry, String> {
+        self.entries.last().ok_or_else(|| "!!: event not found".to_string())
+    }
+
+    /// Parses the event designator after a `!` at `chars[*i - 1]`, returning the line it refers to.
+    fn event(&self, chars: &[char], i: &mut usize, line_so_far: &str) -> Result<String, String> {
+        let start = *i;
+        let not_found = |chars: &[char], end: usize| format!("!{}: event not found", chars[start..end].iter().collect::<String>());
+
+        match chars.get(*i) {
+            Some('!') => {
+                *i += 1;
+                Ok(self.previous()?.line.clone())
+            },
+            Some('#') => {
+                *i += 1;
+                Ok(line_so_far.to_string())
+            },
+            Some(c) if c.is_ascii_digit() => {
+                let number = parse_number(chars, i).unwrap_or(0);
+                self.get(number).map(|entry| entry.line.clone()).ok_or_else(|| not_found(chars, *i))
+            },
+            Some('-') if chars.get(*i + 1).is_some_and(|c| c.is_ascii_digit()) => {
+                *i += 1;
+                let back = parse_number(chars, i).unwrap_or(0);
+                self.len().checked_sub(back)
+                    .and_then(|index| self.entries.get(index))
+                    .map(|entry| entry.line.clone())
+                    .ok_or_else(|| not_found(chars, *i))
+            },
+            Some('?') => {
+                *i += 1;
+                let mut search = String::new();
+                while let Some(&c) = chars.get(*i) {
+                    *i += 1;
+                    if c == '?' || c == '\n' {
+                        break;
+                    }
+                    search.push(c);
+                }
+                self.entries.iter().rev()
+                    .find(|entry| entry.line.contains(&search))
+                    .map(|entry| entry.line.clone())
+                    .ok_or_else(|| not_found(chars, *i))
+            },
+            // a bare word designator refers to the previous command
+            Some('^') | Some('$') | Some('*') | Some(':') => Ok(self.previous()?.line.clone()),
+            _ => {
+                while chars.get(*i).is_some_and(|c| !c.is_whitespace() && *c != ':') {
+                    *i += 1;
+                }
+                let prefix: String = chars[start..*i].iter().collect();
+                self.entries.iter().rev()
+                    .find(|entry| entry.line.starts_with(&prefix))
+                    .map(|entry| entry.line.clone())
+                    .ok_or_else(|| not_found(chars, *i))
+            },
+        }
+    }
+
+    /// Parses an optional word designator and returns the selected words of `line`.
+    fn words(chars: &[char], i: &mut usize, line: String) -> Result<String, String> {
+        let colon = chars.get(*i) == Some(&':');
+        let first = if colon { chars.get(*i + 1) } else { chars.get(*i) };
+        let designator = match first {
+            Some('^') | Some('$') | Some('*') | Some('-') => true,
+            Some(c) => colon && c.is_ascii_digit(),
+            None => false,
+        };
+        if !designator {
+            return Ok(line);
+        }
+        let start = *i;
+        if colon {
+            *i += 1;
+        }
+
+        let range = match chars[*i] {
+            '^' => {
+                *i += 1;
+                (WordIndex::Nth(1), WordIndex::Nth(1))
+            },
+            '$' => {
+                *i += 1;
+                (WordIndex::Last, WordIndex::Last)
+            },
+            '*' => {
+                *i += 1;
+                (WordIndex::Nth(1), WordIndex::Last)
+            },
+            '-' => {
+                *i += 1;
+                let end = parse_number(chars, i).map_or(WordIndex::BeforeLast, WordIndex::Nth);
+                (WordIndex::Nth(0), end)
+            },
+            _ => {
+                let start = WordIndex::Nth(parse_number(chars, i).unwrap_or(0));
+                match chars.get(*i) {
+                    Some('*') => {
+                        *i += 1;
+                        (start, WordIndex::Last)
+                    },
+                    Some('-') => {
+                        *i += 1;
+                        if chars.get(*i) == Some(&'$') {
+                            *i += 1;
+                            (start, WordIndex::Last)
+                        }
+                        else {
+                            (start, parse_number(chars, i).map_or(WordIndex::BeforeLast, WordIndex::Nth))
+                        }
+                    },
+                    _ => (start, start),
+                }
+            },
+        };
+
+        let words = split_words(&line);
+        let resolve = |index: WordIndex| match index {
+            WordIndex::Nth(n) => n as isize,
+            WordIndex::Last => words.len() as isize - 1,
+            WordIndex::BeforeLast => words.len() as isize - 2,
+        };
+        let (first, last) = (resolve(range.0), resolve(range.1));
+
+        // `*` on a one word line is allowed to select nothing
+        if matches!(range, (WordIndex::Nth(1), WordIndex::Last)) && words.len() == 1 {
+            return Ok(String::new());
+        }
+        if first < 0 || last < first || last as usize >= words.len() {
+            return Err(format!("{}: bad word specifier", chars[start..*i].iter().collect::<String>()));
+        }
+        Ok(words[first as usize..=last as usize].join(" "))
+    }
+
+    /// Applies `:h`, `:t`, `:r`, `:e`, `:p`, `:q`, `:s/old/new/`, `:gs/old/new/` and `:&`.
+    fn modifiers(&mut self, chars: &[char], i: &mut usize, mut text: String, print_only: &mut bool) -> Result<String, String> {
+        while chars.get(*i) == Some(&':') {
+            let modifier = match chars.get(*i + 1) {
+                Some(c) if "htrepqsg&".contains(*c) => *c,
+                _ => break,
+            };
+            *i += 2;
+
+            match modifier {
+                'h' => {
+                    if let Some(index) = text.rfind('/') {
+                        text.truncate(index);
+                    }
+                },
+                't' => {
+                    if let Some(index) = text.rfind('/') {
+                        text = text[index + 1..].to_string();
+                    }
+                },
+                'r' => {
+                    if let Some(index) = text.rfind('.').filter(|index| !text[*index..].contains('/')) {
+                        text.truncate(index);
+                    }
+                },
+                'e' => {
+                    text = match text.rfind('.').filter(|index| !text[*index..].contains('/')) {
+                        Some(index) => text[index..].to_string(),
+                        None => String::new(),
+                    };
+                },
+                'p' => *print_only = true,
+                'q' => text = format!("'{}'", text.replace('\'', "'\\''")),
+                's' | 'g' | '&' => {
+                    let global = modifier == 'g';
+                    let kind = if global { chars.get(*i).cloned() } else { Some(modifier) };
+                    if global {
+                        *i += 1;
+                    }
+
+                    let (old, new) = match kind {
+                        Some('s') if *i < chars.len() => {
+                            let (old, new) = parse_substitution(chars, i);
+                            match (old.is_empty(), &self.last_substitution) {
+                                (true, Some((last, _))) => (last.clone(), new),
+                                _ => (old, new),
+                            }
+                        },
+                        Some('&') => self.last_substitution.clone()
+                            .ok_or_else(|| ":&: no previous substitution".to_string())?,
+                        _ => return Err(format!(":{}: unrecognized history modifier", modifier)),
+                    };
+
+                    text = substitute(&text, &old, &new, global)
+                        .ok_or_else(|| format!(":s{}{}: substitution failed", old, new))?;
+                    self.last_substitution = Some((old, new));
+                },
+                _ => unreachable!(),
+            }
+        }
+        Ok(text)
+    }
+
+    /// csh style history expansion of a line before it is parsed: `!!`, `!n`, `!-n`,
+    /// `!prefix`, `!?sub?`, word designators and modifiers, and `^old^new^` quick
+    /// substitution. Nothing inside single quotes or after a backslash is touched.
+    pub fn expand(&mut self, line: &str) -> Result<Expansion, String> {
+        let chars: Vec<char> = line.chars().collect();
+        let mut expansion = Expansion {
+            line: String::new(),
+            changed: false,
+            print_only: false,
+        };
+
+        let mut i = 0;
+        if chars.first() == Some(&'^') {
+            let (old, new) = parse_substitution(&chars, &mut i);
+            let previous = self.previous()?.line.clone();
+            expansion.line = substitute(&previous, &old, &new, false)
+                .ok_or_else(|| format!(":s^{}^{}: substitution failed", old, new))?;
+            self.last_substitution = Some((old, new));
+            expansion.changed = true;
+        }
+
+        let mut quoted = false;
+        let mut double_quoted = false;
+        while i < chars.len() {
+            let c = chars[i];
+            i += 1;
+            match c {
+                '\'' if !double_quoted => {
+                    quoted = !quoted;
+                    expansion.line.push(c);
+                },
+                '"' if !quoted => {
+                    double_quoted = !double_quoted;
+                    expansion.line.push(c);
+                },
+                '\\' if !quoted && chars.get(i) == Some(&'!') => {
+                    expansion.line.push('!');
+                    i += 1;
+                },
+                // like bash, a `!` before a blank, `=`, `(` or a closing double quote stays as it is
+                '!' if !quoted && chars.get(i).is_some_and(|c| !c.is_whitespace() && *c != '=' && *c != '(' && !(double_quoted && *c == '"')) => {
+                    let event = self.event(&chars, &mut i, &expansion.line)?;
+                    let words = History::words(&chars, &mut i, event)?;
+                    let text = self.modifiers(&chars, &mut i, words, &mut expansion.print_only)?;
+                    expansion.line += &text;
+                    expansion.changed = true;
+                },
+                _ => expansion.line.push(c),
+            }
+        }
+
+        Ok(expansion)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rshell-history-{}-{}", std::process::id(), name))
+    }
+
+    fn entry(line: &str, timestamp: u64) -> HistoryEntry {
+        HistoryEntry { line: line.to_string(), timestamp }
+    }
+
+    fn read_file(path: &Path) -> Vec<HistoryEntry> {
+        let entries = read_entries(&File::open(path).unwrap()).unwrap();
+        fs::remove_file(path).unwrap();
+        entries
+    }
+
+    fn round_trip(name: &str, entries: &[HistoryEntry]) -> Vec<HistoryEntry> {
+        let path = temp_path(name);
+        write_entries(&mut File::create(&path).unwrap(), entries).unwrap();
+        read_file(&path)
+    }
+
+    fn file_size(size: usize) -> HistorySettings {
+        HistorySettings { file_size: Some(size), ..HistorySettings::default() }
+    }
+
+    fn history(lines: &[&str]) -> History {
+        let mut history = History::new(Path::new(""));
+        for line in lines {
+            history.add(line, &HistorySettings::default());
+        }
+        history
+    }
+
+    fn expand(lines: &[&str], line: &str) -> Result<String, String> {
+        history(lines).expand(line).map(|expansion| expansion.line)
+    }
+
+    #[test]
+    fn event_designators() {
+        let lines = ["ls /tmp", "echo one two three", "cat /etc/hosts"];
+        assert_eq!(expand(&lines, "!!").unwrap(), "cat /etc/hosts");
+        assert_eq!(expand(&lines, "!1 -l").unwrap(), "ls /tmp -l");
+        assert_eq!(expand(&lines, "!-2").unwrap(), "echo one two three");
+        assert_eq!(expand(&lines, "!ec").unwrap(), "echo one two three");
+        assert_eq!(expand(&lines, "!?tmp?").unwrap(), "ls /tmp");
+        assert_eq!(expand(&lines, "echo a !#").unwrap(), "echo a echo a ");
+        assert_eq!(expand(&lines, "!9").unwrap_err(), "!9: event not found");
+        assert_eq!(expand(&lines, "!nope").unwrap_err(), "!nope: event not found");
+        assert_eq!(expand(&[], "!!").unwrap_err(), "!!: event not found");
+    }
+
+    #[test]
+    fn word_designators() {
+        let lines = ["echo one two three"];
+        assert_eq!(expand(&lines, "!!:0").unwrap(), "echo");
+        assert_eq!(expand(&lines, "!^").unwrap(), "one");
+        assert_eq!(expand(&lines, "!$").unwrap(), "three");
+        assert_eq!(expand(&lines, "!*").unwrap(), "one two three");
+        assert_eq!(expand(&lines, "!!:1-2").unwrap(), "one two");
+        assert_eq!(expand(&lines, "!!:2*").unwrap(), "two three");
+        assert_eq!(expand(&lines, "!!:1-").unwrap(), "one two");
+        assert_eq!(expand(&lines, "!!:-2").unwrap(), "echo one two");
+        assert_eq!(expand(&lines, "!!:2-$").unwrap(), "two three");
+        assert_eq!(expand(&lines, "!!:7").unwrap_err(), ":7: bad word specifier");
+        assert_eq!(expand(&["ls"], "echo !*").unwrap(), "echo ");
+        assert_eq!(expand(&["echo 'a b' c"], "!^").unwrap(), "'a b'");
+    }
+
+    #[test]
+    fn modifiers() {
+        let lines = ["vi /usr/src/main.tar.gz"];
+        assert_eq!(expand(&lines, "!$:h").unwrap(), "/usr/src");
+        assert_eq!(expand(&lines, "!$:t").unwrap(), "main.tar.gz");
+        assert_eq!(expand(&lines, "!$:r").unwrap(), "/usr/src/main.tar");
+        assert_eq!(expand(&lines, "!$:e").unwrap(), ".gz");
+        assert_eq!(expand(&lines, "!$:t:r:r").unwrap(), "main");
+        assert_eq!(expand(&["ls /foo"], "!$:h").unwrap(), "");
+        assert_eq!(expand(&["ls foo"], "!$:h").unwrap(), "foo");
+        assert_eq!(expand(&["ls a.d/b"], "!$:r").unwrap(), "a.d/b");
+        assert_eq!(expand(&["echo it's"], "!$:q").unwrap(), "'it'\\''s'");
+    }
+
+    #[test]
+    fn substitutions() {
+        let lines = ["echo aa aa"];
+        assert_eq!(expand(&lines, "!!:s/aa/b/").unwrap(), "echo b aa");
+        assert_eq!(expand(&lines, "!!:gs/aa/b/").unwrap(), "echo b b");
+        assert_eq!(expand(&lines, "!!:s/aa/[&]/").unwrap(), "echo [aa] aa");
+        assert_eq!(expand(&lines, "!!:s/zz/b/").unwrap_err(), ":szzb: substitution failed");
+        assert_eq!(expand(&lines, "^aa^c^").unwrap(), "echo c aa");
+        assert_eq!(expand(&lines, "!!:&").unwrap_err(), ":&: no previous substitution");
+
+        let mut history = history(&lines);
+        history.expand("!!:s/aa/b/").unwrap();
+        assert_eq!(history.expand("!!:&").unwrap().line, "echo b aa");
+        assert_eq!(history.expand("!!:gs//c/").unwrap().line, "echo c c");
+    }
+
+    #[test]
+    fn print_only() {
+        let expansion = history(&["ls"]).expand("!!:p").unwrap();
+        assert_eq!(expansion.line, "ls");
+        assert!(expansion.print_only);
+        assert!(expansion.changed);
+    }
+
+    #[test]
+    fn lines_left_alone() {
+        let lines = ["ls"];
+        for line in ["echo 'hi!!'", "echo hi\\!!", "echo hi! there", "a!=b", "echo !(x)", "echo \"hi!\"", "echo hi!"] {
+            let expansion = history(&lines).expand(line).unwrap();
+            assert!(!expansion.changed, "{}", line);
+        }
+        assert_eq!(expand(&lines, "echo hi\\!!").unwrap(), "echo hi!!");
+        assert_eq!(expand(&lines, "echo \"!!\"").unwrap(), "echo \"ls\"");
+    }
+
+    #[test]
+    fn multi_line_entries_round_trip() {
+        let entries = [entry("ls |\nwc -l", 1), entry("echo 'a\n\nb'", 2), entry("pwd", 3)];
+        assert_eq!(round_trip("multi-line", &entries), entries);
+    }
+
+    #[test]
+    fn timestamp_like_commands_round_trip() {
+        let entries = [entry("#123", 1), entry("#", 2), entry("#123", 3)];
+        assert_eq!(round_trip("timestamp-like", &entries), entries);
+    }
+
+    #[test]
+    fn trailing_backslashes_round_trip() {
+        let entries = [entry("echo \\\\", 1), entry("echo \\\\\nls", 2)];
+        assert_eq!(round_trip("backslashes", &entries), entries);
+    }
+
+    #[test]
+    fn reads_files_without_timestamps() {
+        let path = temp_path("plain");
+        fs::write(&path, "ls\ncd /\n").unwrap();
+        assert_eq!(read_file(&path), [entry("ls", 0), entry("cd /", 0)]);
+    }
+
+    #[test]
+    fn digits_line_without_a_command_after_it_is_a_command() {
+        let path = temp_path("dangling");
+        fs::write(&path, "#1\nls\n#123\n").unwrap();
+        assert_eq!(read_file(&path), [entry("ls", 1), entry("#123", 0)]);
+    }
+
+    #[test]
+    fn adding_keeps_the_file_to_its_size() {
+        let path = temp_path("add");
+        let mut history = History::new(&path);
+        for line in ["one", "two", "three", "four"] {
+            history.add(line, &file_size(2));
+        }
+        let lines: Vec<String> = read_file(&path).into_iter().map(|entry| entry.line).collect();
+        assert_eq!(lines, ["three", "four"]);
+    }
+
+    #[test]
+    fn writing_and_appending_keep_the_file_to_its_size() {
+        let mut history = History::new(Path::new(""));
+        for line in ["one", "two", "three"] {
+            history.add(line, &HistorySettings::default());
+        }
+
+        let path = temp_path("write");
+        history.write(Some(&path), &file_size(2)).unwrap();
+        let lines: Vec<String> = read_file(&path).into_iter().map(|entry| entry.line).collect();
+        assert_eq!(lines, ["two", "three"]);
+
+        let path = temp_path("append");
+        fs::write(&path, "#1\nold\n#2\nolder\n").unwrap();
+        history.session_start = 1;
+        history.append_session(Some(&path), &file_size(4)).unwrap();
+        let lines: Vec<String> = read_file(&path).into_iter().map(|entry| entry.line).collect();
+        assert_eq!(lines, ["older", "one", "two", "three"]);
+    }
+
+    #[test]
+    fn a_file_that_cannot_be_written_is_reported_once() {
+        let missing = temp_path("missing").join("history");
+        let mut history = History::new(&missing);
+        history.add("one", &HistorySettings::default());
+        assert!(history.write_failed);
+        history.set_path(&missing);
+        assert!(history.write_failed);
+        history.add("two", &HistorySettings::default());
+        assert_eq!(history.len(), 2);
+
+        let path = temp_path("writable");
+        history.set_path(&path);
+        assert!(!history.write_failed);
+        history.add("three", &HistorySettings::default());
+        assert!(!history.write_failed);
+        let lines: Vec<String> = read_file(&path).into_iter().map(|entry| entry.line).collect();
+        assert_eq!(lines, ["three"]);
+    }
+}
@@ -3,59 +3,494 @@ mod shell;
 mod expr;
 mod variable;
 mod parser;
+mod builtin;
+mod timing;
+mod signals;
+mod glob;
+mod history;
+mod histdb;
+mod editor;
+mod complete;
+mod prompt;
 
-use std::process::exit;
-use shell::Shell;
-use std::path::Path;
-use std::io::{self,Write};
+use editor::{Input, LineEditor};
+use shell::{Shell,ShellOptions};
+use signals::Trap;
+use timing::{ResourceUsage, Timer};
+use variable::Value;
+use std::path::{Path,PathBuf};
+use nix::errno::Errno;
+use nix::sys::wait::waitpid;
+use nix::unistd::{self,ForkResult};
 use process::{run_in_forground,run_in_background};
-use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
 use std::env;
+use std::fs;
+use std::io::{self,Read,Write};
+use std::os::unix::io::FromRawFd;
+use std::time::{Instant,SystemTime};
+
+const USAGE: &str = "usage: rshell [-eilnsux] [-o option] [--login] [--posix] [--norc] [--rcfile file] [-c command [name [arg ...]] | script [arg ...]]";
+
+/// How the shell was started, from its command line.
+#[derive(Debug,Default)]
+struct Invocation {
+    /// The commands given with `-c`.
+    command: Option<String>,
+    /// Read commands from stdin, with the operands as positional parameters.
+    stdin: bool,
+    /// Read commands at a prompt even when stdin is not a terminal.
+    interactive: bool,
+    login: bool,
+    /// Read before the first prompt of an interactive shell, `~/.rshellrc` unless
+    /// `--norc` or `--rcfile` said otherwise.
+    rc_file: Option<PathBuf>,
+    /// What follows the options: a script and its arguments, or with `-c` the value
+    /// of `$0` and the positional parameters.
+    operands: Vec<String>,
+}
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("rshell: {}", message);
+    eprintln!("{}", USAGE);
+    std::process::exit(2);
+}
+
+fn print_help() {
+    println!("{}", USAGE);
+    println!();
+    println!("  -c command     run command, with the operands after it as $0 and $1...");
+    println!("  -i             read commands at a prompt even when stdin is not a terminal");
+    println!("  -l, --login    act as a login shell");
+    println!("  -s             read commands from stdin, the operands are $1...");
+    println!("  -e, -u, -x, -n the options errexit, nounset, xtrace and noexec of set");
+    println!("  -o option      turn on an option of set, +o turns it off");
+    println!("  --norc         do not read ~/.rshellrc");
+    println!("  --rcfile file  read file instead of ~/.rshellrc");
+    println!("  --posix        behave as POSIX asks, interactive shells read $ENV");
+    println!("  --version      print the version and exit");
+    println!("  --help         print this help and exit");
+}
+
+/// Reads the options the shell was started with, turning on the ones `set` knows.
+/// Exits for `--help`, `--version` and anything it does not understand.
+fn parse_invocation(shell: &mut Shell, args: &[String]) -> Invocation {
+    let mut invocation = Invocation {
+        login: args.first().is_some_and(|arg0| arg0.starts_with('-')),
+        rc_file: env::var("HOME").ok().map(|home| Path::new(&home).join(".rshellrc")),
+        ..Invocation::default()
+    };
+    let mut command = false;
+    let mut i = 1;
+    while let Some(arg) = args.get(i) {
+        i += 1;
+        match arg.as_str() {
+            "--" => break,
+            "--help" => {
+                print_help();
+                std::process::exit(0);
+            },
+            "--version" => {
+                println!("rshell, version {}", env!("CARGO_PKG_VERSION"));
+                std::process::exit(0);
+            },
+            "--login" => invocation.login = true,
+            "--posix" => {
+                shell.options.posix = true;
+            },
+            "--norc" => invocation.rc_file = None,
+            "--rcfile" => match args.get(i) {
+                Some(file) => {
+                    invocation.rc_file = Some(PathBuf::from(file));
+                    i += 1;
+                },
+                None => usage_error("--rcfile: option requires an argument"),
+            },
+            "-o" | "+o" => match args.get(i) {
+                Some(name) if shell.options.set(name, arg.starts_with('-')) => i += 1,
+                Some(name) => usage_error(&format!("{}: invalid option name", name)),
+                None => usage_error(&format!("{}: option requires an argument", arg)),
+            },
+            _ if arg.starts_with("--") => usage_error(&format!("{}: invalid option", arg)),
+            _ if arg.len() > 1 && (arg.starts_with('-') || arg.starts_with('+')) => {
+                let enable = arg.starts_with('-');
+                for flag in arg[1..].chars() {
+                    match flag {
+                        'c' if enable => command = true,
+                        's' if enable => invocation.stdin = true,
+                        'i' if enable => invocation.interactive = true,
+                        'l' if enable => invocation.login = true,
+                        _ => match ShellOptions::flag_name(flag) {
+                            Some(name) => {
+                                shell.options.set(name, enable);
+                            },
+                            None => usage_error(&format!("{}{}: invalid option", &arg[..1], flag)),
+                        },
+                    }
+                }
+            },
+            _ => {
+                i -= 1;
+                break;
+            },
+        }
+    }
+
+    invocation.operands = args[i.min(args.len())..].to_vec();
+    if command {
+        if invocation.operands.is_empty() {
+            usage_error("-c: option requires an argument");
+        }
+        invocation.command = Some(invocation.operands.remove(0));
+    }
+    invocation
+}
 
 fn main() {
     
-    let mut shell = Shell::new(Path::new(""));
-    let action = SigAction::new(SigHandler::SigIgn, SaFlags::empty(), SigSet::empty());
-        unsafe {
-            sigaction(Signal::SIGINT, &action).expect("failed to sigaction");
-            sigaction(Signal::SIGQUIT, &action).expect("failed to sigaction");
-            sigaction(Signal::SIGTSTP, &action).expect("failed to sigaction");
-            sigaction(Signal::SIGTTIN, &action).expect("failed to sigaction");
-            sigaction(Signal::SIGTTOU, &action).expect("failed to sigaction");
+    let history_path = match env::var("HOME") {
+        Ok(home) => Path::new(&home).join(".rshell_history"),
+        Err(_) => PathBuf::new(),
+    };
+    let mut shell = Shell::new(&history_path);
+    signals::init().expect("failed to sigaction");
+
+    let args: Vec<String> = env::args().collect();
+    let invocation = parse_invocation(&mut shell, &args);
+    shell.set_login(invocation.login);
+    if invocation.login {
+        source_startup_file(&mut shell, Path::new("/etc/profile"));
+        if let Some(home) = shell.lookup_var("HOME") {
+            source_startup_file(&mut shell, &Path::new(&home).join(".rshell_profile"));
         }
+    }
+    let arg0 = args.first().map_or("rshell", String::as_str);
+
+    if let Some(command) = &invocation.command {
+        shell.set_script_name(invocation.operands.first().map_or(arg0, String::as_str));
+        shell.set_positional_args(invocation.operands.get(1..).unwrap_or_default());
+        let name = shell.script_name().to_string();
+        run_text(&mut shell, &name, command);
+        let status = shell.last_status();
+        shell.exit(status);
+    }
+    if let (false, Some(script)) = (invocation.stdin, invocation.operands.first()) {
+        shell.set_script_name(script);
+        shell.set_positional_args(&invocation.operands[1..]);
+        let status = match fs::read_to_string(script) {
+            Ok(text) => {
+                run_text(&mut shell, script, &text);
+                shell.last_status()
+            },
+            Err(e) => {
+                eprintln!("rshell: {}: {}", script, e);
+                if e.kind() == io::ErrorKind::NotFound { 127 } else { 126 }
+            },
+        };
+        shell.exit(status);
+    }
+    shell.set_script_name(arg0);
+    shell.set_positional_args(&invocation.operands);
+
+    // commands piped in are run like a script, without prompts
+    if !invocation.interactive && !unistd::isatty(0).unwrap_or(false) {
+        let name = shell.script_name().to_string();
+        run_script(&mut shell, &name, || loop {
+            match editor::read_plain_line() {
+                // the last line may have no newline, which the parser expects
+                Ok(Input::Line(line)) => return Some(format!("{}\n", line.trim_end_matches('\n'))),
+                Err(Errno::EINTR) => continue,
+                _ => return None,
+            }
+        });
+        let status = shell.last_status();
+        shell.exit(status);
+    }
+    match shell.take_terminal() {
+        Ok(()) => shell.set_interactive(true),
+        Err(e) => eprintln!("rshell: cannot set terminal process group: {}\nrshell: no job control in this shell", e),
+    }
 
+    // POSIX has interactive shells read the file ENV names, which is expanded like a prompt
+    let rc_file = if shell.options.posix {
+        shell.lookup_var("ENV").map(|env| PathBuf::from(prompt::expand(&shell, &env)))
+    }
+    else {
+        invocation.rc_file.clone()
+    };
+    if let Some(rc_file) = rc_file {
+        source_startup_file(&mut shell, &rc_file);
+    }
+    shell.load_history();
 
+    let mut editor = LineEditor::new();
     loop {
-        let mut buffer = String::new();
-        print!("tsh> ");
-        io::stdout().flush().unwrap();
-        io::stdin().read_line(&mut buffer)
-            .expect("Failed to read line");
+        signals::run_pending_traps(&mut shell);
+
+        let pending = prompt::AsyncPrompt::start(&shell);
+        let prompts = pending.prompts();
+        editor.set_right_prompt(prompts.right_prompt);
+        editor.set_transient_prompt(prompts.transient_prompt);
+        editor.set_pending_prompt(pending);
+        match editor.read_line(&prompts.prompt, &mut shell) {
+            Ok(Input::Eof) => {
+                let status = shell.last_status();
+                shell.exit(status);
+            },
+            Ok(Input::Interrupted) => shell.set_last_status(130),
+            Ok(Input::Edited(lines)) => builtin::fc_run(&mut shell, &lines),
+            Ok(Input::Line(buffer)) => {
+                let buffer = match read_continuation(&mut editor, &mut shell, buffer) {
+                    Some(buffer) => buffer,
+                    None => continue,
+                };
+                shell.next_command();
+                let line = match expand_history(&mut shell, &buffer) {
+                    Some(line) => line,
+                    None => continue,
+                };
+                shell.add_history(&line);
 
-        eval(&buffer,&mut shell);
+                let cwd = env::current_dir().map(|cwd| cwd.to_string_lossy().into_owned()).unwrap_or_default();
+                let started = SystemTime::now();
+                let timer = Instant::now();
+                eval(&line,&mut shell);
+                shell.record_command(&line, &cwd, started, timer.elapsed());
+            },
+            Err(Errno::EINTR) => println!(),
+            Err(e) => {
+                eprintln!("Failed to read line: {}", e);
+                shell.exit(1);
+            },
+        }
+    }
+
+
+}
+
+/// Runs the commands in a file in this shell, as `source` does.
+pub fn source_file(shell: &mut Shell, path: &Path) -> io::Result<()> {
+    let text = fs::read_to_string(path)?;
+    shell.enter_source();
+    run_text(shell, &path.to_string_lossy(), &text);
+    // bash runs the RETURN trap as a sourced file finishes, however it got there
+    signals::run_trap(shell, Trap::Return);
+    shell.leave_source();
+    Ok(())
+}
+
+/// Sources a startup file if there is one.
+pub fn source_startup_file(shell: &mut Shell, path: &Path) {
+    match source_file(shell, path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => eprintln!("rshell: {}: {}", path.display(), e),
+        _ => (),
     }
+}
 
+/// Runs a script held in a string.
+fn run_text(shell: &mut Shell, name: &str, text: &str) {
+    // the last line may have no newline, which the parser expects
+    let mut lines = text.split_inclusive('\n').map(|line| format!("{}\n", line.trim_end_matches('\n')));
+    run_script(shell, name, || lines.next());
+}
 
+/// Runs the lines of a script as they come, reading on while a line is unfinished.
+/// Errors while it runs name the script and the line the command starts on.
+fn run_script(shell: &mut Shell, name: &str, mut next_line: impl FnMut() -> Option<String>) {
+    let outer = shell.location().cloned();
+    let mut number = 0;
+    while let Some(mut line) = next_line() {
+        number += 1;
+        // the #! line is for the kernel
+        if number == 1 && line.starts_with("#!") {
+            continue;
+        }
+        shell.set_location(Some((name.to_string(), number)));
+        while parser::is_incomplete(&line) {
+            if line.ends_with("\\\n") {
+                line.truncate(line.len() - 2);
+            }
+            match next_line() {
+                Some(more) => {
+                    number += 1;
+                    line += &more;
+                },
+                None => {
+                    eprintln!("{}: line {}: syntax error: unexpected end of file", name, number);
+                    shell.set_last_status(2);
+                    shell.set_location(outer);
+                    return;
+                },
+            }
+        }
+        signals::run_pending_traps(shell);
+        if shell.options.noexec {
+            // parse the line without running it, so that -n still finds its syntax errors
+            check_syntax(&parser::parseline(&line).1, shell);
+        } else {
+            eval(&line, shell);
+        }
+        if shell.returning() {
+            break;
+        }
+    }
+    shell.set_location(outer);
+}
+
+/// Reads more lines with the PS2 prompt while the line is unfinished, None if that is cut
+/// short. A backslash before the end of a line joins it to the next one.
+fn read_continuation(editor: &mut LineEditor, shell: &mut Shell, mut buffer: String) -> Option<String> {
+    while parser::is_incomplete(&buffer) {
+        if buffer.ends_with("\\\n") {
+            buffer.truncate(buffer.len() - 2);
+        }
+        let ps2 = prompt::prompt(shell, "PS2", prompt::DEFAULT_PS2);
+        match editor.read_line(&ps2, shell) {
+            Ok(Input::Line(more)) => buffer += &more,
+            Ok(Input::Eof) => {
+                eprintln!("rshell: syntax error: unexpected end of file");
+                shell.set_last_status(2);
+                return None;
+            },
+            Ok(Input::Interrupted) => {
+                shell.set_last_status(130);
+                return None;
+            },
+            Ok(Input::Edited(_)) => return None,
+            Err(_) => return None,
+        }
+    }
+    Some(buffer)
+}
+
+/// Applies history expansion to an entered line, None when there is nothing to run.
+fn expand_history(shell: &mut Shell, line: &str) -> Option<String> {
+    if !shell.options.histexpand {
+        return Some(line.to_string());
+    }
+
+    match shell.history_mut().expand(line.trim_end_matches('\n')) {
+        Ok(expansion) => {
+            let line = format!("{}\n", expansion.line);
+            if expansion.changed {
+                print!("{}", line);
+            }
+            if expansion.print_only {
+                shell.add_history(&line);
+                return None;
+            }
+            Some(line)
+        },
+        Err(e) => {
+            eprintln!("rshell: {}", e);
+            shell.set_last_status(1);
+            None
+        },
+    }
 }
 
+/// Whether a line split by the parser can run, reporting why not if it cannot.
+fn check_syntax(argv: &[String], shell: &mut Shell) -> bool {
+    match parser::syntax_error(argv) {
+        Some(token) => {
+            let prefix = shell.error_prefix().unwrap_or_else(|| "rshell: ".to_string());
+            eprintln!("{}syntax error near unexpected token `{}'", prefix, token);
+            shell.set_last_status(2);
+            false
+        },
+        None => true,
+    }
+}
 
+/// Runs one line with the DEBUG trap before it and the ERR trap after a failure.
 fn eval(cmdline: &str, shell: &mut Shell) {
-    let argv: Vec<String>;
+    if parser::strip_comment(cmdline).trim().is_empty() {
+        return;
+    }
+
+    signals::run_trap(shell, Trap::Debug);
+    eval_line(cmdline, shell);
+    if shell.last_status() != 0 {
+        signals::run_trap(shell, Trap::Err);
+        if shell.options.errexit {
+            let status = shell.last_status();
+            shell.exit(status);
+        }
+    }
+}
+
+fn eval_line(cmdline: &str, shell: &mut Shell) {
+    if let Some((name, body)) = parser::function_definition(cmdline) {
+        shell.define_function(&name, &body);
+        shell.set_last_status(0);
+        return;
+    }
+
+    // blanks before the command would come out of the parser as an empty one
+    let cmdline = &substitute_commands(shell, cmdline.trim_start_matches([' ', '\t']));
+    let mut argv: Vec<String>;
     let bg: bool;
     let pair = parser::parseline(&cmdline);
     bg = pair.0;
     argv = pair.1;
+    if !check_syntax(&argv, shell) {
+        return;
+    }
+
+    let timer = Timer::from_argv(&mut argv);
+
+    if shell.options.nounset {
+        let unset = argv.iter()
+            .filter_map(|arg| arg.strip_prefix('$'))
+            .find(|name| !name.is_empty() && shell.lookup_var(name).is_none());
+        if let Some(name) = unset {
+            let prefix = shell.error_prefix().unwrap_or_else(|| "rshell: ".to_string());
+            eprintln!("{}{}: unbound variable", prefix, name);
+            shell.set_last_status(1);
+            return;
+        }
+    }
+    if shell.options.xtrace {
+        trace(shell, &argv);
+    }
+
+    if let Some((name, words)) = parser::array_assignment(&argv) {
+        let mut values = Vec::new();
+        for word in builtin::words(&parser::parseline(&words).1) {
+            match word.strip_prefix('$') {
+                Some(var) => values.extend(shell.lookup_var(var).unwrap_or_default().split_whitespace().map(str::to_string)),
+                None => values.push(parser::unquote(&word)),
+            }
+        }
+        shell.set_var(&name, Value::Array(values));
+        shell.set_last_status(0);
+        return;
+    }
     
-    if builtin_cmd(&argv) == 1 {
+    if builtin_cmd(&argv, shell) == 1 {
+        if let Some(timer) = timer {
+            timer.report(shell, ResourceUsage::default());
+        }
         return;
     }
-    let set = parser::parseargs(&argv);
+    let parser::ParsedArgs { cmds, args, env, stdin_redir, stdout_redir } = parser::parseargs(&argv, shell);
 
-    let cmds = set.0;
-    let args = set.1;
-    let env = set.2;
-    let stdin_redir = set.3;
-    let stdout_redir = set.4;
+    // a line made only of assignments sets shell variables
+    if cmds.len() == 1 && cmds[0].is_empty() {
+        for (key, value) in env {
+            shell.set_var(&key, Value::String(value));
+        }
+        shell.set_last_status(0);
+        return;
+    }
+
+    if let Some(body) = shell.function(&cmds[0]).filter(|_| cmds.len() == 1) {
+        let status = run_function(shell, &cmds[0], &body, &args[0]);
+        shell.set_last_status(status);
+        if let Some(timer) = timer {
+            timer.report(shell, ResourceUsage::default());
+        }
+        return;
+    }
 
     let job = shell.create_job(cmdline, cmds, args, stdin_redir, stdout_redir);
     
@@ -65,30 +500,128 @@ fn eval(cmdline: &str, shell: &mut Shell) {
     (*job).borrow_mut().exec(shell);
 
     if !bg {
-        run_in_forground(shell, &job, false);
+        let status = run_in_forground(shell, &job, false);
+        shell.set_last_status(status.code());
+
+        if let Some(timer) = timer {
+            timer.report(shell, job.borrow().usage());
+        }
     }
     else {
         run_in_background(shell, &job, false);
+        shell.set_last_status(0);
     }
 
 }
 
 
-fn builtin_cmd(argv: &Vec<String>) -> i32 {
+/// Runs a function with `args` as its `$1`, `$2` and so on, and returns its status.
+fn run_function(shell: &mut Shell, name: &str, body: &str, args: &[String]) -> i32 {
+    shell.enter_function(args);
+    run_text(shell, name, body);
+    shell.leave_function();
+    shell.last_status()
+}
+
+/// Replaces each `$(command)` with the words the command prints, quoted so they are
+/// taken as they are.
+fn substitute_commands(shell: &mut Shell, line: &str) -> String {
+    let mut line = line.to_string();
+    while let Some((start, end)) = parser::find_substitution(&line) {
+        let output = command_output(shell, &line[start + 2..end - 1]);
+        let words: Vec<String> = output.split_whitespace().map(complete::quote).collect();
+        line.replace_range(start..end, &words.join(" "));
+    }
+    line
+}
+
+/// Runs a command in a copy of the shell and returns what it prints.
+fn command_output(shell: &mut Shell, command: &str) -> String {
+    let (read, write) = match unistd::pipe() {
+        Ok(pipe) => pipe,
+        Err(e) => {
+            eprintln!("rshell: command substitution: {}", e);
+            return String::new();
+        },
+    };
+    match unsafe { unistd::fork() } {
+        Ok(ForkResult::Child) => {
+            let _ = unistd::close(read);
+            let _ = unistd::dup2(write, nix::libc::STDOUT_FILENO);
+            let _ = unistd::close(write);
+            // the copy does not own the terminal and has no jobs to control
+            shell.set_interactive(false);
+            eval_line(&format!("{}\n", command), shell);
+            let _ = io::stdout().flush();
+            std::process::exit(shell.last_status());
+        },
+        Ok(ForkResult::Parent { child }) => {
+            let _ = unistd::close(write);
+            let mut output = Vec::new();
+            let _ = unsafe { fs::File::from_raw_fd(read) }.read_to_end(&mut output);
+            let _ = waitpid(child, None);
+            String::from_utf8_lossy(&output).into_owned()
+        },
+        Err(e) => {
+            let _ = unistd::close(read);
+            let _ = unistd::close(write);
+            eprintln!("rshell: command substitution: {}", e);
+            String::new()
+        },
+    }
+}
+
+/// Prints a command as `set -x` shows it, after PS4 and with its variables expanded.
+fn trace(shell: &Shell, argv: &[String]) {
+    let words: Vec<String> = builtin::words(argv).into_iter()
+        .map(|word| match word.strip_prefix('$').and_then(|name| shell.lookup_var(name)) {
+            Some(value) => value,
+            None => word,
+        })
+        .collect();
+    let ps4 = prompt::prompt(shell, "PS4", prompt::DEFAULT_PS4);
+    eprintln!("{}{}", ps4.replace(['\x01', '\x02'], ""), words.join(" "));
+}
+
+fn builtin_cmd(argv: &Vec<String>, shell: &mut Shell) -> i32 {
     if argv.len() == 0 {
         return 1;
     } 
-    match argv[0].as_str() {
+    if argv[0] != "exit" && argv[0] != "quit" {
+        shell.set_exit_warned(false);
+    }
+    let words: Vec<String> = builtin::words(argv).into_iter()
+        .enumerate()
+        .map(|(i, word)| match word.strip_prefix('$') {
+            Some(name) if i > 0 => shell.lookup_var(name).unwrap_or_default(),
+            _ => word,
+        })
+        .collect();
+    let words: Vec<&str> = words.iter().map(String::as_str).collect();
+    let status = match argv[0].as_str() {
         " " => return 1,
         "" => return 1,
-        "quit" => exit(0),
-        "exit" => exit(0),
+        "quit" | "exit" => builtin::exit(shell, &words),
+        "logout" => builtin::logout(shell, &words),
+        "source" | "." => builtin::source(shell, &words),
+        "return" => builtin::r#return(shell, &words),
+        "local" => builtin::local(shell, &words),
+        "history" => builtin::history(shell, &words),
+        "fc" => builtin::fc(shell, &words),
+        "trap" => builtin::trap(shell, &words),
+        "disown" => builtin::disown(shell, &words),
         "cd" => {
             change_dir(argv);
-            return 1;
+            0
         },
+        "times" => timing::times(),
+        "set" => builtin::set(shell, &words),
+        "complete" => builtin::complete(shell, &words),
+        "compgen" => builtin::compgen(shell, &words),
         _ => return 0,
-    }
+    };
+    shell.set_last_status(status);
+    1
 }
 
 pub fn change_dir(argv: &Vec<String>) {
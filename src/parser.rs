@@ -1,17 +1,13 @@
 use crate::process::Redirection;
-use std::env;
+use crate::shell::Shell;
 
 
 
 pub fn parseline(cmdline: &str) -> (bool,Vec<String>) {
+    let cmdline = strip_comment(cmdline);
+    let cmdline = cmdline.as_str();
     let mut argv: Vec<String> = Vec::new();
     let bg: bool;
-    let mut append: bool = false;
-    let mut array = cmdline.to_string(); 
-    /*if array.contains("\n") {
-        array.pop();
-    }*/ 
-    //array.push(' ');
     let result = cmdline.rfind("&");
     if result != None && cmdline.get(result.unwrap()-1..=result.unwrap()) != Some("&&"){ 
         bg = true;
@@ -20,84 +16,96 @@ pub fn parseline(cmdline: &str) -> (bool,Vec<String>) {
         bg = false;
     }
 
-    while array.len() != 0 {
-        //println!("{}",array);
-        //println!("array len: {}", array.len());
-        match array.get(..1).unwrap() {
-            "'" => {
-                        let mut temp: String = array.drain(..1).collect();
-                        //println!("{}!", array);
-                        let temp2: String = array.drain(..array.find('\'').unwrap()+1).collect();
-                        temp += &temp2;
+    for (start, end) in tokens(cmdline) {
+        match &cmdline[start..end] {
+            // a lone `&` only says the job runs in the background
+            "&" | "\n" => (),
+            token => argv.push(token.to_string()),
+        }
+    }
 
-                        argv.push(temp);
-                   },
-            " " => {
-                        //println!("Space");
-                        argv.push(array.drain(..1).collect());
-                   },
-            "|" => {
-                        //println!("Space");
-                        match array.get(..2) {
-                            Some("||") => {
-                                argv.push(array.drain(..2).collect());
-                            },
-                            _ => {
-                                argv.push(array.drain(..1).collect());
-                            },
-                        }
-                   },
-            "<" => {
-                        //println!("Space");
-                        argv.push(array.drain(..1).collect());
-                   },
-            ">" => {
-                        //println!("Space");
-                        match array.get(..2) {
-                            Some(">>") => {
-                                append = true;
-                                argv.push(array.drain(..2).collect());
-                            },
-                            _ => {
-                                argv.push(array.drain(..1).collect());
-                            },
-                        }
-                   },
-            "=" => {
-                        //println!("Space");
-                        argv.push(array.drain(..1).collect());
-                   },
-            "&" => {
-                        //println!("Space");
-                        match array.get(..2) {
-                            Some("&&") => {
-                                argv.push(array.drain(..2).collect());
-                            },
-                            _ => {
-                                array.drain(..1);
-                            },
-                        }
-                   },
-            "\n" => {
-                        //println!("Space");
-                        array.drain(..1);
-                   },
-            _ => {
-                        //println!("Default");
-                        argv.push(array.drain(0..array.find(|c: char| c == '>' || c == '|' || c == '<' || c == ' ' || c == '=' || c == '\n').unwrap()).collect());
 
-                 } 
-        }
+    return (bg,argv);
+}
 
-        //println!("{:?}",argv);
+/// A line split into the commands of its pipeline.
+pub struct ParsedArgs {
+    /// The command of each stage.
+    pub cmds: Vec<String>,
+    /// The arguments of each stage.
+    pub args: Vec<Vec<String>>,
+    /// Assignments in front of the commands, as name and value.
+    pub env: Vec<(String,String)>,
+    /// Where each stage reads from.
+    pub stdin_redir: Vec<Redirection>,
+    /// Where each stage writes to.
+    pub stdout_redir: Vec<Redirection>,
+}
 
+/// Where the word at the start of `text` ends: at the first space, newline, `>`, `|`,
+/// `<` or `=` that is outside single quotes and `$(...)` and not escaped by a backslash.
+fn word_end(text: &str) -> usize {
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut depth = 0;
+    let mut previous = ' ';
+    for (i, c) in text.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\'' => quoted = !quoted,
+            _ if quoted => (),
+            '\\' => escaped = true,
+            '(' if depth > 0 || previous == '$' => depth += 1,
+            ')' if depth > 0 => depth -= 1,
+            '>' | '|' | '<' | ' ' | '=' | '\n' if depth == 0 => return i,
+            _ => (),
+        }
+        previous = c;
     }
+    text.len()
+}
 
+/// The tokens `parseline` splits a line into, as byte ranges: words, each space between
+/// them, `|`, `||`, `&&`, `<`, `>`, `>>` and `=`, and the `&` and newlines it drops.
+pub fn tokens(cmdline: &str) -> Vec<(usize, usize)> {
+    let mut tokens = Vec::new();
+    let mut start = 0;
+    while start < cmdline.len() {
+        let rest = &cmdline[start..];
+        let len = match rest.as_bytes()[0] {
+            b'|' if rest.starts_with("||") => 2,
+            b'>' if rest.starts_with(">>") => 2,
+            b'&' if rest.starts_with("&&") => 2,
+            b' ' | b'|' | b'<' | b'>' | b'=' | b'&' | b'\n' => 1,
+            _ => word_end(rest),
+        };
+        tokens.push((start, start + len));
+        start += len;
+    }
+    tokens
+}
 
-    return (bg,argv);
+/// The text a word stands for. Single quotes are taken away and what they enclose
+/// is kept as it is; outside them a backslash keeps the character after it.
+pub fn unquote(word: &str) -> String {
+    let mut text = String::new();
+    let mut quoted = false;
+    let mut escaped = false;
+    for c in word.chars() {
+        match c {
+            _ if escaped => {
+                text.push(c);
+                escaped = false;
+            },
+            '\'' => quoted = !quoted,
+            '\\' if !quoted => escaped = true,
+            _ => text.push(c),
+        }
+    }
+    text
 }
 
-pub fn parseargs(argv: &Vec<String>) -> (Vec<String>,Vec<Vec<String>>,Vec<(String,String)>,Vec<Redirection>,Vec<Redirection>) {
+pub fn parseargs(argv: &[String], shell: &Shell) -> ParsedArgs {
     let mut cmds: Vec<String> = Vec::new();
     let mut args: Vec<Vec<String>> = Vec::new();
     let mut env: Vec<(String,String)> = Vec::new();
@@ -123,17 +131,17 @@ pub fn parseargs(argv: &Vec<String>) -> (Vec<String>,Vec<Vec<String>>,Vec<(Strin
                 },
             "<" => {
                     //stdin_redir[curr_cmd] = args[curr_cmd].len();
-                    stdin_redir[curr_cmd] = Redirection::File((argv[i+2].clone(),false));
+                    stdin_redir[curr_cmd] = Redirection::File((unquote(redirect_target(argv, i)),false));
                     skip = true;
                 },
             ">" => {
                     //stdout_redir[curr_cmd] = args[curr_cmd].len();
-                    stdout_redir[curr_cmd] = Redirection::File((argv[i+2].clone(),false));
+                    stdout_redir[curr_cmd] = Redirection::File((unquote(redirect_target(argv, i)),false));
                     skip = true;
                 },
             ">>" => {
                     //stdout_redir[curr_cmd] = args[curr_cmd].len();
-                    stdout_redir[curr_cmd] = Redirection::File((argv[i + 2].clone(), false));
+                    stdout_redir[curr_cmd] = Redirection::File((unquote(redirect_target(argv, i)), false));
                     skip = true;
                 },
             "=" => {
@@ -172,16 +180,15 @@ pub fn parseargs(argv: &Vec<String>) -> (Vec<String>,Vec<Vec<String>>,Vec<(Strin
                         continue;
                     }
 
-                    if i + 2 < argv.len() && argv[i+1].as_str() == "=" {
-                        let val;
-                        if argv[i+2].contains("'") {
-                            val = argv[i+2].clone().drain(1..argv[i].len()-1).collect();
-                        }
-                        else {
-                            val = argv[i+2].clone();
-                        }
+                    if i + 1 < argv.len() && argv[i+1].as_str() == "=" {
+                        let raw = argv.get(i+2).map_or("", |val| val.as_str());
+                        let val = match raw.strip_prefix('$') {
+                            Some(name) => shell.lookup_var(name).unwrap_or_default(),
+                            None => unquote(raw.trim()),
+                        };
                         env.push((argv[i].clone(),val));
-                        skip = true;
+                        // an empty value leaves nothing after the '=' to skip
+                        skip = !raw.trim().is_empty();
                         continue;
                     }
 
@@ -189,8 +196,8 @@ pub fn parseargs(argv: &Vec<String>) -> (Vec<String>,Vec<Vec<String>>,Vec<(Strin
                         let cmd;
 
                         if argv[i].get(..1) == Some("$") {
-                            match env::var(argv[i].clone().drain(1..).collect::<String>()) {
-                                Ok(val) => {
+                            match shell.lookup_var(&argv[i][1..]) {
+                                Some(val) => {
                                     if val.contains(" ") {
                                         let mut var:Vec<&str> = val.split(" ").collect();
                                         cmd = var[0].to_string();
@@ -203,14 +210,14 @@ pub fn parseargs(argv: &Vec<String>) -> (Vec<String>,Vec<Vec<String>>,Vec<(Strin
                                         cmd = val;
                                     }
                                 }
-                                Err(_) =>  cmd = argv[i].as_str().to_string(),
+                                None =>  cmd = argv[i].as_str().to_string(),
                                     
 
                                 //cmd = argv[i].as_str().to_string(),
                             }
                         }
                         else {
-                            cmd = argv[i].as_str().to_string();
+                            cmd = unquote(&argv[i]);
                         }
 
                             
@@ -220,8 +227,8 @@ pub fn parseargs(argv: &Vec<String>) -> (Vec<String>,Vec<Vec<String>>,Vec<(Strin
                     else {
                         if argv[i].get(..1) == Some("$") {
 
-                            match env::var(argv[i].clone().drain(1..).collect::<String>()) {
-                                Ok(val) => {
+                            match shell.lookup_var(&argv[i][1..]) {
+                                Some(val) => {
                                     if val.contains(" ") {
                                         let var:Vec<&str> = val.split(" ").collect();
                                         for arg in var.iter() {
@@ -232,14 +239,14 @@ pub fn parseargs(argv: &Vec<String>) -> (Vec<String>,Vec<Vec<String>>,Vec<(Strin
                                         args[curr_cmd].push(val);
                                     }
                                 }
-                                Err(_) =>  args[curr_cmd].push(argv[i].as_str().to_string()),
+                                None =>  args[curr_cmd].push(argv[i].as_str().to_string()),
                                     
                             }//args[curr_cmd].push(argv[i].as_str().to_string()),
                             
 
                         }
                         else {
-                            args[curr_cmd].push(argv[i].as_str().to_string());
+                            args[curr_cmd].push(unquote(&argv[i]));
                         }
                     }
                 }
@@ -248,5 +255,205 @@ pub fn parseargs(argv: &Vec<String>) -> (Vec<String>,Vec<Vec<String>>,Vec<(Strin
     }
     
 
-    return (cmds,args,env,stdin_redir,stdout_redir);
+    ParsedArgs { cmds, args, env, stdin_redir, stdout_redir }
+}
+
+/// The word a redirection at `argv[i]` sends to or reads from, the first one after it.
+fn redirect_target(argv: &[String], i: usize) -> &str {
+    argv[i + 1..].iter().find(|arg| arg.as_str() != " ").map_or("", String::as_str)
+}
+
+/// The token a line split by `parseline` cannot run with, or `newline` when it ends
+/// too soon: a pipe, `&&` or `||` with no command before it, or a redirection with
+/// no file after it.
+pub fn syntax_error(argv: &[String]) -> Option<String> {
+    let mut argv = argv.iter().map(String::as_str).filter(|arg| *arg != " ");
+    let mut empty = true;
+    while let Some(arg) = argv.next() {
+        match arg {
+            "|" | "||" | "&&" if empty => return Some(arg.to_string()),
+            "|" | "||" | "&&" => empty = true,
+            "<" | ">" | ">>" => match argv.next() {
+                None => return Some("newline".to_string()),
+                Some(next @ ("|" | "||" | "&&" | "<" | ">" | ">>" | "=")) => return Some(next.to_string()),
+                Some(_) => empty = false,
+            },
+            _ => empty = false,
+        }
+    }
+    None
+}
+
+/// Whether each char of the line belongs to a comment, which starts at a `#` beginning
+/// a word outside quotes and runs to the end of its line.
+pub fn comment_chars(line: &str) -> Vec<bool> {
+    let mut chars = Vec::new();
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut comment = false;
+    let mut previous = ' ';
+    for c in line.chars() {
+        match c {
+            '\n' if comment => comment = false,
+            _ if comment => (),
+            _ if escaped => escaped = false,
+            '\'' => quoted = !quoted,
+            '\\' if !quoted => escaped = true,
+            '#' if !quoted && (previous.is_whitespace() || "|&;<>(".contains(previous)) => comment = true,
+            _ => (),
+        }
+        chars.push(comment);
+        if !comment {
+            previous = c;
+        }
+    }
+    chars
+}
+
+/// The line without its comments.
+pub fn strip_comment(line: &str) -> String {
+    let comments = comment_chars(line);
+    let mut text: String = line.chars().zip(&comments).filter(|(_, comment)| !**comment).map(|(c, _)| c).collect();
+    if comments.last() == Some(&true) {
+        text.push('\n');
+    }
+    text
+}
+
+/// Whether a line needs more input before it can run: a quote or function body left
+/// open, a backslash at the end or a pipe, `&&` or `||` with nothing after it.
+pub fn is_incomplete(line: &str) -> bool {
+    let line = strip_comment(line);
+    let line = line.trim_end_matches('\n');
+    let mut quoted = false;
+    let mut escaped = false;
+    for c in line.chars() {
+        match c {
+            _ if escaped => escaped = false,
+            '\'' => quoted = !quoted,
+            '\\' if !quoted => escaped = true,
+            _ => (),
+        }
+    }
+    if quoted || escaped {
+        return true;
+    }
+    if function_header(line).is_some_and(|(_, body)| open_braces(body) >= 0) {
+        return true;
+    }
+    let line = line.trim_end();
+    line.ends_with('|') || line.ends_with("&&")
+}
+
+/// The name of the function a line starts to define with `name() {` or
+/// `function name {`, and what follows the `{`.
+fn function_header(line: &str) -> Option<(&str, &str)> {
+    let line = line.trim_start();
+    let (name, rest) = match line.strip_prefix("function ") {
+        Some(rest) => {
+            let rest = rest.trim_start();
+            let (name, rest) = rest.split_at(rest.find(|c: char| c.is_whitespace() || c == '(').unwrap_or(rest.len()));
+            let rest = rest.trim_start();
+            (name, rest.strip_prefix("()").unwrap_or(rest))
+        },
+        None => {
+            let (name, rest) = line.split_at(line.find('(')?);
+            (name.trim_end(), rest.strip_prefix("()")?)
+        },
+    };
+    if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || "_-.:".contains(c)) {
+        return None;
+    }
+    let rest = rest.trim_start().strip_prefix('{')?;
+    // like a reserved word, the `{` has to be a word of its own
+    if rest.starts_with(|c: char| !c.is_whitespace()) {
+        return None;
+    }
+    Some((name, rest))
+}
+
+/// How many `{` words outside quotes are still open, below zero once more are closed.
+fn open_braces(text: &str) -> i32 {
+    let mut depth = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut word = String::new();
+    for c in text.chars().chain([' ']) {
+        match c {
+            _ if escaped => escaped = false,
+            '\'' => quoted = !quoted,
+            '\\' if !quoted => escaped = true,
+            c if c.is_whitespace() && !quoted => {
+                match word.as_str() {
+                    "{" => depth += 1,
+                    "}" => depth -= 1,
+                    _ => (),
+                }
+                word.clear();
+                continue;
+            },
+            _ => (),
+        }
+        word.push(c);
+    }
+    depth
+}
+
+/// The name and body of a function defined with `name() { body }` or
+/// `function name { body }`. The body may run over several lines.
+pub fn function_definition(line: &str) -> Option<(String, String)> {
+    let line = strip_comment(line);
+    let (name, rest) = function_header(&line)?;
+    let body = rest.trim_end().strip_suffix('}')?;
+    if open_braces(body) != 0 || body.ends_with(|c: char| !c.is_whitespace()) {
+        return None;
+    }
+    Some((name.to_string(), body.trim_start_matches([' ', '\t']).to_string()))
+}
+
+/// The name and the text between the parentheses of an array assignment, `name=(words)`.
+pub fn array_assignment(argv: &[String]) -> Option<(String, String)> {
+    let mut argv = argv.iter().skip_while(|arg| arg.trim().is_empty());
+    let name = argv.next()?;
+    if argv.next().map(String::as_str) != Some("=") || !name.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        return None;
+    }
+    let value = argv.map(String::as_str).collect::<String>();
+    let words = value.trim().strip_prefix('(')?.strip_suffix(')')?;
+    Some((name.clone(), words.to_string()))
+}
+
+/// Where the first `$(command)` outside quotes starts, and where it ends just past its `)`.
+pub fn find_substitution(line: &str) -> Option<(usize, usize)> {
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut start = None;
+    let mut depth = 0;
+    let mut previous = ' ';
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => {
+                escaped = false;
+                previous = ' ';
+                continue;
+            },
+            '\'' => quoted = !quoted,
+            _ if quoted => (),
+            '\\' => escaped = true,
+            '(' if start.is_some() => depth += 1,
+            '(' if previous == '$' => {
+                start = Some(i - 1);
+                depth = 1;
+            },
+            ')' if start.is_some() => {
+                depth -= 1;
+                if depth == 0 {
+                    return start.map(|start| (start, i + 1));
+                }
+            },
+            _ => (),
+        }
+        previous = c;
+    }
+    None
 }
@@ -1,11 +1,17 @@
-use nix::unistd::Pid;
+use nix::unistd::{self,Pid};
 use nix::sys::signal::{kill, Signal};
-use nix::sys::wait::{waitpid,WaitPidFlag,WaitStatus};
+use nix::errno::Errno;
+use nix::libc;
+use nix::sys::wait::{WaitPidFlag,WaitStatus};
 use std::borrow::BorrowMut;
 use std::process::{self,Command, Stdio, Child};
 use std::os::unix::process::CommandExt;
 use crate::shell::Shell;
+use crate::signals;
+use crate::timing::ResourceUsage;
+use crate::variable::Value;
 use std::fs::{File,OpenOptions};
+use std::io;
 use std::cell::RefCell;
 use std::rc::Rc;
 use std::hash::{Hasher,Hash};
@@ -19,10 +25,77 @@ fn kill_process_group(pgid: Pid, signal: Signal) -> Result<(), nix::errno::Errno
 pub enum ProcessStatus {
     Running,
     Exited(i32),
-    Stopped,
+    Signaled(Signal, bool),
+    Stopped(Signal),
     Undef
 }
 
+impl ProcessStatus {
+    /// True once the process has exited or been killed by a signal.
+    #[inline]
+    pub fn terminated(&self) -> bool {
+        matches!(self, ProcessStatus::Exited(_) | ProcessStatus::Signaled(..))
+    }
+
+    /// The value `$?` takes for this status, signals are reported as 128 + signo.
+    pub fn code(&self) -> i32 {
+        match self {
+            ProcessStatus::Exited(status) => *status,
+            ProcessStatus::Signaled(signal, _) | ProcessStatus::Stopped(signal) => 128 + *signal as i32,
+            _ => 0,
+        }
+    }
+
+    /// Human readable form used in job notifications, e.g. "Segmentation fault (core dumped)".
+    pub fn description(&self) -> String {
+        match self {
+            ProcessStatus::Running => "Running".to_string(),
+            ProcessStatus::Exited(0) => "Done".to_string(),
+            ProcessStatus::Exited(status) => format!("Exit {}", status),
+            ProcessStatus::Signaled(signal, true) => format!("{} (core dumped)", signal_description(*signal)),
+            ProcessStatus::Signaled(signal, false) => signal_description(*signal).to_string(),
+            ProcessStatus::Stopped(signal) => signal_description(*signal).to_string(),
+            ProcessStatus::Undef => "Undefined".to_string(),
+        }
+    }
+}
+
+/// The same wording bash uses when reporting a job that was killed or stopped by `signal`.
+pub fn signal_description(signal: Signal) -> &'static str {
+    match signal {
+        Signal::SIGHUP => "Hangup",
+        Signal::SIGINT => "Interrupt",
+        Signal::SIGQUIT => "Quit",
+        Signal::SIGILL => "Illegal instruction",
+        Signal::SIGTRAP => "Trace/breakpoint trap",
+        Signal::SIGABRT => "Aborted",
+        Signal::SIGBUS => "Bus error",
+        Signal::SIGFPE => "Floating point exception",
+        Signal::SIGKILL => "Killed",
+        Signal::SIGUSR1 => "User defined signal 1",
+        Signal::SIGSEGV => "Segmentation fault",
+        Signal::SIGUSR2 => "User defined signal 2",
+        Signal::SIGPIPE => "Broken pipe",
+        Signal::SIGALRM => "Alarm clock",
+        Signal::SIGTERM => "Terminated",
+        Signal::SIGCHLD => "Child exited",
+        Signal::SIGCONT => "Continued",
+        Signal::SIGSTOP => "Stopped (signal)",
+        Signal::SIGTSTP => "Stopped",
+        Signal::SIGTTIN => "Stopped (tty input)",
+        Signal::SIGTTOU => "Stopped (tty output)",
+        Signal::SIGURG => "Urgent I/O condition",
+        Signal::SIGXCPU => "CPU time limit exceeded",
+        Signal::SIGXFSZ => "File size limit exceeded",
+        Signal::SIGVTALRM => "Virtual timer expired",
+        Signal::SIGPROF => "Profiling timer expired",
+        Signal::SIGWINCH => "Window changed",
+        Signal::SIGIO => "I/O possible",
+        Signal::SIGSYS => "Bad system call",
+        _ => signal.as_str(),
+    }
+}
+
 #[derive(Debug,PartialEq,Clone,Hash)]
 pub enum Redirection {
     Normal,
@@ -39,6 +112,7 @@ pub struct Process {
     pub stdout_redir: Redirection,
     pub status: ProcessStatus,
     pub process: Option<Child>,
+    pub usage: ResourceUsage,
 }
 
 impl Process {
@@ -49,7 +123,8 @@ impl Process {
         stdin_redir: stdin_redir,
         stdout_redir: stdout_redir,
         status: ProcessStatus::Undef,
-        process: None
+        process: None,
+        usage: ResourceUsage::default(),
         }
     }
 
@@ -90,7 +165,7 @@ pub struct Job {
     pipeline: String,
     pub processes: Vec<Process>,
     pgid: Pid,
-
+    pipefail: bool,
 }
 
 impl Job {
@@ -101,9 +176,40 @@ impl Job {
             pipeline: pipeline.to_string(),
             processes: Vec::new(),
             pgid: Pid::from_raw(0),
+            pipefail: false,
         }
     }
 
+    /// With pipefail the job reports the rightmost failing stage instead of the last one.
+    #[inline]
+    pub fn set_pipefail(&mut self, pipefail: bool) {
+        self.pipefail = pipefail;
+    }
+
+    /// The status of every stage of the pipeline, in order, as `$PIPESTATUS` reports them.
+    pub fn pipe_status(&self) -> Vec<i32> {
+        self.processes.iter().map(|process| process.status.code()).collect()
+    }
+
+    /// Combined resource usage of every process of the job that has been reaped.
+    pub fn usage(&self) -> ResourceUsage {
+        let mut usage = ResourceUsage::default();
+        for process in self.processes.iter() {
+            usage.add(&process.usage);
+        }
+        usage
+    }
+
+    fn completed_state(&self) -> ProcessStatus {
+        let last = self.processes.last().map_or(ProcessStatus::Exited(0), |process| process.status);
+        if self.pipefail {
+            if let Some(process) = self.processes.iter().rev().find(|process| process.status.code() != 0) {
+                return process.status;
+            }
+        }
+        last
+    }
+
     #[inline]
     pub fn id(&self) -> u32 {
         self.id
@@ -121,17 +227,25 @@ impl Job {
 
     #[inline]
     pub fn completed(&self) -> bool {
-        match self.state {
-            ProcessStatus::Exited(_) => return true,
-            _ => return false,
-        }
+        self.state.terminated()
     }
     
     #[inline]
     pub fn stopped(&self) -> bool {
-        match self.state {
-            ProcessStatus::Stopped => return true,
-            _ => return false,
+        matches!(self.state, ProcessStatus::Stopped(_))
+    }
+
+    /// Sends `signal` to the job's process group, or to each live process when
+    /// the job was not given a group of its own.
+    pub fn signal(&self, signal: Signal) {
+        if self.pgid.as_raw() > 0 {
+            let _ = kill_process_group(self.pgid, signal);
+            return;
+        }
+        for process in self.processes.iter() {
+            if process.process.is_some() && !process.status.terminated() {
+                let _ = kill(process.pid(), signal);
+            }
         }
     }
 
@@ -140,10 +254,11 @@ impl Job {
     }
     
 
-    pub fn update_process_state(&mut self, pid: Pid, state: ProcessStatus) {
+    pub fn update_process_state(&mut self, pid: Pid, state: ProcessStatus, usage: ResourceUsage) {
         for process in self.processes.iter_mut() {
-            if process.pid() == pid {
+            if process.process.is_some() && process.pid() == pid {
                 process.status = state;
+                process.usage.add(&usage);
                 break;
             }
         }
@@ -152,15 +267,15 @@ impl Job {
         println!("state {:?}\n",state);
         
         match &state {
-            ProcessStatus::Stopped => {
-                if self.processes.iter().all(|process| matches!(process.status,ProcessStatus::Stopped )) {
+            ProcessStatus::Stopped(_) => {
+                if self.processes.iter().all(|process| matches!(process.status,ProcessStatus::Stopped(_)) || process.status.terminated()) {
                     self.state = state;
                 }
 
             },
-            ProcessStatus::Exited(_) => {
-                if self.processes.iter().all(|process| matches!(process.status,ProcessStatus::Exited(_))) {
-                    self.state = state;
+            ProcessStatus::Exited(_) | ProcessStatus::Signaled(..) => {
+                if self.processes.iter().all(|process| process.status.terminated()) {
+                    self.state = self.completed_state();
                 }
                 
             },
@@ -197,6 +312,7 @@ impl Job {
             if shell.interactive() {
                 command = command.process_group(group_id);
             }
+            command = signals::reset_in_child(command, shell);
             command = command.args(self.processes[i].args.as_slice());
 
             match &self.processes[i].stdout_redir {
@@ -223,7 +339,11 @@ impl Job {
 
             match &self.processes[i].stdin_redir {
                 Redirection::Pipe => {
-                    command = command.stdin(self.processes[i-1].process.as_mut().unwrap().stdout.take().unwrap()); 
+                    // a stage that could not be started leaves the next one nothing to read
+                    match self.processes[i-1].process.as_mut().and_then(|process| process.stdout.take()) {
+                        Some(stdout) => command = command.stdin(stdout),
+                        None => command = command.stdin(Stdio::null()),
+                    }
                 },
                 Redirection::File((file_name,_)) => {
                     match File::open(file_name.as_str()) {
@@ -241,17 +361,34 @@ impl Job {
                     self.processes[i].set_process(proc);
                     self.processes[i].status = ProcessStatus::Running;
                 },
-                Err(_) => {
-                    eprintln!("{}: Command not found", self.processes[i].cmd);
+                Err(e) => {
+                    // like bash, 127 when there is no such command and 126 when it cannot run
+                    let prefix = shell.error_prefix().unwrap_or_default();
+                    let status = match e.kind() {
+                        io::ErrorKind::NotFound => {
+                            eprintln!("{}{}: Command not found", prefix, self.processes[i].cmd);
+                            127
+                        },
+                        _ => {
+                            eprintln!("{}{}: {}", prefix, self.processes[i].cmd, e);
+                            126
+                        },
+                    };
+                    self.processes[i].status = ProcessStatus::Exited(status);
                 }
             }
             
-            if i == 0 && shell.interactive() {
-                self.pgid = self.processes[0].pid();
-                group_id = self.processes[0].process.as_ref().expect("Child not yet initialized").id().try_into().unwrap();
+            // the first stage that started leads the group the rest join
+            if group_id == 0 && shell.interactive() && self.processes[i].process.is_some() {
+                self.pgid = self.processes[i].pid();
+                group_id = self.pgid.as_raw();
             }
         }
         self.state = ProcessStatus::Running;
+        // nothing is left to wait for when no stage could be started
+        if self.processes.iter().all(|process| process.status.terminated()) {
+            self.state = self.completed_state();
+        }
     }
     
 }
@@ -288,7 +425,7 @@ pub fn continue_job(shell: &mut Shell, job: &Rc<RefCell<Job>>, background: bool)
 
     for proc in (**job).borrow_mut().processes.iter_mut() {
         match proc.status {
-            ProcessStatus::Stopped => {
+            ProcessStatus::Stopped(_) => {
                 proc.status = ProcessStatus::Running;
             },
             _ => {},
@@ -304,17 +441,31 @@ pub fn continue_job(shell: &mut Shell, job: &Rc<RefCell<Job>>, background: bool)
     }
 }
 
+/// Makes `pgid` the foreground process group of the terminal.
+fn give_terminal_to(pgid: Pid) {
+    let _ = unistd::tcsetpgrp(libc::STDIN_FILENO, pgid);
+}
+
 pub fn run_in_forground(shell: &mut Shell, job: &Rc<RefCell<Job>>, sigcont: bool) -> ProcessStatus {
     shell.remove_background_job(job);
 
+    // with job control the job has the terminal while it runs, so it can read
+    // from it and the keys that send signals reach it rather than the shell
+    let pgid = job.borrow().pgid();
+    let job_control = shell.interactive() && pgid.as_raw() > 0;
+    if job_control {
+        give_terminal_to(pgid);
+    }
 
     if sigcont {
-        kill_process_group(job.borrow().pgid(), Signal::SIGCONT).expect("failed to kill(SIGCONT)");
-        
+        job.borrow().signal(Signal::SIGCONT);
     }
     
     let status = wait_for_job(shell, job);
 
+    if job_control {
+        give_terminal_to(shell.pgid);
+    }
     status    
 }
 
@@ -323,7 +474,7 @@ pub fn run_in_background(shell: &mut Shell, job: &Rc<RefCell<Job>>, sigcont: boo
     shell.add_background_job(job);
 
     if sigcont {
-        kill_process_group(job.borrow().pgid(), Signal::SIGCONT).expect("failed to kill(SIGCONT)");
+        job.borrow().signal(Signal::SIGCONT);
     }
 }
 
@@ -332,7 +483,7 @@ pub fn delete_job(shell: &mut Shell, job: &Rc<RefCell<Job>>) {
     println!("Delete Job");
 
     if shell.remove_background_job(job) {
-        println!("[{}] ({}) Done: {}",job.borrow().id(),job.borrow().pgid(),job.borrow().pipeline());
+        println!("[{}] ({}) {}: {}",job.borrow().id(),job.borrow().pgid(),job.borrow().state.description(),job.borrow().pipeline());
     }
     
     #[cfg(debug_assertions)]
@@ -351,22 +502,50 @@ pub fn wait_for_job(shell: &mut Shell, job: &Rc<RefCell<Job>>) -> ProcessStatus
             break;
         }
 
+        if signals::hangup_pending(shell) {
+            shell.hangup();
+        }
+
         wait_for_process(job,true);
     }
 
     let state: ProcessStatus = job.borrow().state;
 
+    if job.borrow().completed() {
+        let pipe_status = job.borrow().pipe_status().iter().map(|status| status.to_string()).collect();
+        shell.set_var("PIPESTATUS", Value::Array(pipe_status));
+    }
+
     match state {
-        ProcessStatus::Exited(_) => {
-            delete_job(shell, &job);
-            return state;
+        ProcessStatus::Exited(_) => delete_job(shell, job),
+        ProcessStatus::Signaled(signal, _) => {
+            // like bash, an interrupted or broken pipeline is not worth reporting
+            if signal != Signal::SIGINT && signal != Signal::SIGPIPE {
+                println!("{}", state.description());
+            }
+            delete_job(shell, job);
         }
-        ProcessStatus::Stopped => {
-            println!("Job [{}] ({}) stopped {}",job.borrow().id(),job.borrow().pgid, job.borrow().pipeline());
-            return state;
+        ProcessStatus::Stopped(_) => {
+            println!("Job [{}] ({}) {} {}",job.borrow().id(),job.borrow().pgid, state.description(), job.borrow().pipeline());
         },
         _ => unreachable!(),
     }
+    state
+}
+
+/// `waitpid` for any child that also returns the resource usage of a terminated child.
+fn wait4(options: WaitPidFlag) -> nix::Result<(WaitStatus, ResourceUsage)> {
+    let mut status: libc::c_int = 0;
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::zeroed();
+
+    let pid = unsafe { libc::wait4(-1, &mut status, options.bits(), usage.as_mut_ptr()) };
+    let pid = Errno::result(pid)?;
+    if pid == 0 {
+        return Ok((WaitStatus::StillAlive, ResourceUsage::default()));
+    }
+
+    let usage = unsafe { usage.assume_init() };
+    Ok((WaitStatus::from_raw(Pid::from_raw(pid), status)?, ResourceUsage::from_rusage(&usage)))
 }
 
 pub fn wait_for_process(job: &Rc<RefCell<Job>>, block: bool) -> Option<Pid> {
@@ -377,19 +556,22 @@ pub fn wait_for_process(job: &Rc<RefCell<Job>>, block: bool) -> Option<Pid> {
         WaitPidFlag::WUNTRACED | WaitPidFlag::WNOHANG
     };
 
-    let result = waitpid(None,Some(options));
+    let (result, usage) = match wait4(options) {
+        Ok((status, usage)) => (Ok(status), usage),
+        Err(errno) => (Err(errno), ResourceUsage::default()),
+    };
 
     let (pid, state) = match result {
         Ok(WaitStatus::Exited(pid, status)) => {
             (pid,ProcessStatus::Exited(status))
         },
-        Ok(WaitStatus::Signaled(pid, signal, _)) => {
-            (pid, ProcessStatus::Exited(-1))
+        Ok(WaitStatus::Signaled(pid, signal, core_dumped)) => {
+            (pid, ProcessStatus::Signaled(signal, core_dumped))
         }
         Ok(WaitStatus::Stopped(pid,signal)) => {
-            (pid, ProcessStatus::Stopped)
+            (pid, ProcessStatus::Stopped(signal))
         },
-        Err(nix::errno::Errno::ECHILD) | Ok(WaitStatus::StillAlive) => {
+        Err(Errno::ECHILD) | Err(Errno::EINTR) | Ok(WaitStatus::StillAlive) => {
             return None;
         }
         status => {
@@ -398,7 +580,29 @@ pub fn wait_for_process(job: &Rc<RefCell<Job>>, block: bool) -> Option<Pid> {
     };
     let job = &**job;
     let mut job = job.borrow_mut();
-    job.update_process_state(pid, state);
+    job.update_process_state(pid, state, usage);
 
     Some(pid)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signals_give_128_plus_their_number() {
+        assert_eq!(ProcessStatus::Exited(3).code(), 3);
+        assert_eq!(ProcessStatus::Signaled(Signal::SIGINT, false).code(), 130);
+        assert_eq!(ProcessStatus::Signaled(Signal::SIGSEGV, true).code(), 139);
+        assert_eq!(ProcessStatus::Stopped(Signal::SIGTSTP).code(), 148);
+    }
+
+    #[test]
+    fn descriptions_read_like_bash() {
+        assert_eq!(ProcessStatus::Exited(0).description(), "Done");
+        assert_eq!(ProcessStatus::Exited(2).description(), "Exit 2");
+        assert_eq!(ProcessStatus::Signaled(Signal::SIGSEGV, true).description(), "Segmentation fault (core dumped)");
+        assert_eq!(ProcessStatus::Signaled(Signal::SIGKILL, false).description(), "Killed");
+        assert_eq!(ProcessStatus::Stopped(Signal::SIGTSTP).description(), "Stopped");
+    }
+}
@@ -0,0 +1,577 @@
+mod git;
+
+use crate::timing;
+use crate::shell::Shell;
+use nix::fcntl::OFlag;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::unistd::{self,Uid,User};
+use std::env;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+use std::process::{Command,Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool,Ordering::Relaxed};
+use std::sync::mpsc::{self,Receiver,TryRecvError};
+use std::thread;
+use std::time::{Duration,SystemTime,UNIX_EPOCH};
+
+/// What PS1 is while unset.
+pub const DEFAULT_PS1: &str = "tsh> ";
+pub const DEFAULT_PS2: &str = "> ";
+/// What PS4, which `set -x` puts before each command it prints, is while unset.
+pub const DEFAULT_PS4: &str = "+ ";
+/// Segments slow enough to be worked out on a worker thread while the line is read.
+const SLOW_SEGMENTS: &[&str] = &["git"];
+/// Marks where a slow segment goes, around its number. It is a noncharacter, so no
+/// prompt has one of its own.
+const SLOT: char = '\u{fdd0}';
+/// How long to wait for slow segments before drawing placeholders, which is enough for
+/// a small repository and too short to notice.
+const ASYNC_WAIT_MS: i32 = 30;
+
+fn user_name() -> String {
+    match User::from_uid(unistd::getuid()) {
+        Ok(Some(user)) => user.name,
+        _ => env::var("USER").unwrap_or_default(),
+    }
+}
+
+fn host_name() -> String {
+    unistd::gethostname()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// The working directory with the home directory shown as `~`.
+pub fn working_dir(shell: &Shell) -> String {
+    let cwd = env::current_dir().map(|cwd| cwd.to_string_lossy().into_owned()).unwrap_or_default();
+    match shell.lookup_var("HOME").filter(|home| !home.is_empty() && home != "/") {
+        Some(home) if cwd == home => "~".to_string(),
+        Some(home) if cwd.starts_with(&format!("{}/", home)) => format!("~{}", &cwd[home.len()..]),
+        _ => cwd,
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs())
+}
+
+/// What a command prints, without the newlines at the end, as `$(command)` gives it.
+fn command_output(command: &str) -> String {
+    let output = Command::new("/bin/sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output();
+    match output {
+        Ok(output) => String::from_utf8_lossy(&output.stdout).trim_end_matches('\n').to_string(),
+        Err(e) => {
+            eprintln!("rshell: {}: {}", command, e);
+            String::new()
+        },
+    }
+}
+
+/// A duration the way the duration segment shows it: `3.2s`, `1m04s` or `1h02m`.
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..=59 => format!("{:.1}s", duration.as_secs_f64()),
+        60..=3599 => format!("{}m{:02}s", secs / 60, secs % 60),
+        _ => format!("{}h{:02}m", secs / 3600, secs / 60 % 60),
+    }
+}
+
+/// The git segment: the branch, `*` when a tracked file changed, and `↑n` and `↓n`
+/// commits ahead of and behind the upstream.
+fn git_segment(dir: &Path, cancelled: &AtomicBool) -> String {
+    let status = match git::status(dir, cancelled) {
+        Some(status) => status,
+        None => return String::new(),
+    };
+    let mut text = status.head;
+    if status.dirty {
+        text.push('*');
+    }
+    if status.ahead > 0 {
+        text += &format!("↑{}", status.ahead);
+    }
+    if status.behind > 0 {
+        text += &format!("↓{}", status.behind);
+    }
+    text
+}
+
+/// What the template of a segment makes of its text, nothing when that is empty.
+fn fill_template(template: &str, text: &str) -> String {
+    match text {
+        "" => String::new(),
+        text => template.replace("%s", text),
+    }
+}
+
+/// What a built-in segment shows, empty when it has nothing to say.
+///
+/// - `git`: the branch, `*` when a tracked file changed, `↑n` and `↓n` commits ahead of
+///   and behind the upstream
+/// - `status`: the last status when it is not 0
+/// - `duration`: how long the last command ran, once that is PROMPT_DURATION_MIN seconds
+///   (2 by default) or more
+/// - `jobs`: the number of jobs when there are any
+/// - `venv`: the name of the active virtualenv or conda environment
+/// - `time`: the time formatted with PROMPT_TIME_FORMAT, `%H:%M:%S` by default
+fn segment(shell: &Shell, name: &str) -> Option<String> {
+    let text = match name {
+        "git" => git_segment(&env::current_dir().ok()?, &AtomicBool::new(false)),
+        "status" => match shell.last_status() {
+            0 => String::new(),
+            status => status.to_string(),
+        },
+        "duration" => {
+            let min = shell.lookup_var("PROMPT_DURATION_MIN").and_then(|min| min.parse::<f64>().ok()).unwrap_or(2.0);
+            match shell.last_duration() {
+                Some(duration) if duration.as_secs_f64() >= min => format_duration(duration),
+                _ => String::new(),
+            }
+        },
+        "jobs" => match shell.job_specs().len() {
+            0 => String::new(),
+            jobs => jobs.to_string(),
+        },
+        "venv" => shell.lookup_var("VIRTUAL_ENV")
+            .filter(|venv| !venv.is_empty())
+            .map(|venv| venv.trim_end_matches('/').rsplit('/').next().unwrap_or("").to_string())
+            .or_else(|| shell.lookup_var("CONDA_DEFAULT_ENV"))
+            .unwrap_or_default(),
+        "time" => {
+            let format = shell.lookup_var("PROMPT_TIME_FORMAT").filter(|format| !format.is_empty());
+            timing::strftime(format.as_deref().unwrap_or("%H:%M:%S"), now())
+        },
+        _ => return None,
+    };
+    Some(text)
+}
+
+/// Where the `)` closing the `$(` before `start` is, counting nested parentheses.
+fn closing_paren(chars: &[char], start: usize) -> Option<usize> {
+    let mut depth = 1;
+    for (i, c) in chars.iter().enumerate().skip(start) {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            _ => (),
+        }
+        if depth == 0 {
+            return Some(i);
+        }
+    }
+    None
+}
+
+/// Replaces the backslash escapes of a prompt string, for the escape that follows the
+/// backslash at `chars[i]`. Returns what it stands for and how many chars it took.
+/// Slow segments are left to `slots` when it is given, marked by their number in it.
+fn escape(shell: &Shell, chars: &[char], i: usize, slots: Option<&mut Vec<(String, String)>>) -> (String, usize) {
+    let c = match chars.get(i + 1) {
+        Some(c) => *c,
+        None => return ("\\".to_string(), 1),
+    };
+    let text = match c {
+        'u' => user_name(),
+        'h' => host_name().split('.').next().unwrap_or("").to_string(),
+        'H' => host_name(),
+        'w' => working_dir(shell),
+        'W' => {
+            let dir = working_dir(shell);
+            match dir.as_str() {
+                "/" | "~" => dir,
+                _ => dir.rsplit('/').next().unwrap_or("").to_string(),
+            }
+        },
+        '$' => if Uid::effective().is_root() { "#" } else { "$" }.to_string(),
+        't' => timing::strftime("%H:%M:%S", now()),
+        'T' => timing::strftime("%I:%M:%S", now()),
+        '@' => timing::strftime("%I:%M %p", now()),
+        'A' => timing::strftime("%H:%M", now()),
+        'd' => timing::strftime("%a %b %d", now()),
+        'D' if chars.get(i + 2) == Some(&'{') => {
+            let end = chars[i + 3..].iter().position(|c| *c == '}').map(|end| i + 3 + end);
+            if let Some(end) = end {
+                let format: String = chars[i + 3..end].iter().collect();
+                let format = if format.is_empty() { "%X".to_string() } else { format };
+                return (timing::strftime(&format, now()), end + 1 - i);
+            }
+            return ("\\D".to_string(), 2);
+        },
+        '{' => {
+            let end = match chars[i + 2..].iter().position(|c| *c == '}') {
+                Some(end) => i + 2 + end,
+                None => return ("\\{".to_string(), 2),
+            };
+            let spec: String = chars[i + 2..end].iter().collect();
+            let (name, template) = spec.split_once(':').unwrap_or((&spec, "%s"));
+            if let Some(slots) = slots.filter(|_| SLOW_SEGMENTS.contains(&name)) {
+                slots.push((name.to_string(), template.to_string()));
+                return (format!("{}{}{}", SLOT, slots.len() - 1, SLOT), end + 1 - i);
+            }
+            return match segment(shell, name) {
+                Some(text) => (fill_template(template, &text), end + 1 - i),
+                None => ("\\{".to_string(), 2),
+            };
+        },
+        'j' => shell.job_specs().len().to_string(),
+        '?' => shell.last_status().to_string(),
+        '!' => (shell.history().last_number() + 1).to_string(),
+        '#' => shell.command_number().to_string(),
+        's' => "rshell".to_string(),
+        'v' | 'V' => env!("CARGO_PKG_VERSION").to_string(),
+        'n' => "\n".to_string(),
+        'r' => "\r".to_string(),
+        'a' => "\x07".to_string(),
+        'e' => "\x1b".to_string(),
+        '[' => "\x01".to_string(),
+        ']' => "\x02".to_string(),
+        '\\' => "\\".to_string(),
+        '0'..='7' => {
+            let digits: String = chars[i + 1..].iter().take(3).take_while(|c| ('0'..='7').contains(*c)).collect();
+            let code = u32::from_str_radix(&digits, 8).ok().and_then(char::from_u32).unwrap_or('\0');
+            return (code.to_string(), digits.len() + 1);
+        },
+        _ => format!("\\{}", c),
+    };
+    (text, 2)
+}
+
+/// Expands a prompt string the way bash does PS1: backslash escapes such as `\u`, `\w`
+/// and `\$`, built-in segments written `\{name}` or `\{name:template}` where `%s` in the
+/// template stands for what the segment shows and nothing is shown when that is empty,
+/// then `$name`, `${name}` and `$(command)` or `` `command` ``. Whatever an escape stands
+/// for is not expanded again. Text between `\[` and `\]` comes back between
+/// `\x01` and `\x02` so the line editor leaves it out of the prompt's width.
+pub fn expand(shell: &Shell, prompt: &str) -> String {
+    render(shell, prompt, None)
+}
+
+fn render(shell: &Shell, prompt: &str, mut slots: Option<&mut Vec<(String, String)>>) -> String {
+    let chars: Vec<char> = prompt.chars().collect();
+    let mut expanded = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => {
+                let (text, len) = escape(shell, &chars, i, slots.as_deref_mut());
+                expanded += &text;
+                i += len;
+            },
+            '$' if chars.get(i + 1) == Some(&'(') => match closing_paren(&chars, i + 2) {
+                Some(end) => {
+                    let command: String = chars[i + 2..end].iter().collect();
+                    expanded += &command_output(&command);
+                    i = end + 1;
+                },
+                None => {
+                    expanded.push('$');
+                    i += 1;
+                },
+            },
+            '$' if chars.get(i + 1) == Some(&'{') => match chars[i..].iter().position(|c| *c == '}') {
+                Some(end) => {
+                    let name: String = chars[i + 1..i + end + 1].iter().collect();
+                    expanded += &shell.lookup_var(&name).unwrap_or_default();
+                    i += end + 1;
+                },
+                None => {
+                    expanded.push('$');
+                    i += 1;
+                },
+            },
+            '$' => {
+                let name: String = match chars.get(i + 1) {
+                    Some(c) if "?$!#0123456789".contains(*c) => c.to_string(),
+                    _ => chars[i + 1..].iter().take_while(|c| c.is_ascii_alphanumeric() || **c == '_').collect(),
+                };
+                if name.is_empty() {
+                    expanded.push('$');
+                }
+                else {
+                    expanded += &shell.lookup_var(&name).unwrap_or_default();
+                }
+                i += name.chars().count() + 1;
+            },
+            '`' => match chars[i + 1..].iter().position(|c| *c == '`') {
+                Some(end) => {
+                    let command: String = chars[i + 1..i + 1 + end].iter().collect();
+                    expanded += &command_output(&command);
+                    i += end + 2;
+                },
+                None => {
+                    expanded.push('`');
+                    i += 1;
+                },
+            },
+            c => {
+                expanded.push(c);
+                i += 1;
+            },
+        }
+    }
+    expanded
+}
+
+/// The expanded value of a prompt variable such as RPROMPT, None while it is unset.
+pub fn expand_var(shell: &Shell, name: &str) -> Option<String> {
+    shell.lookup_var(name).map(|value| expand(shell, &value))
+}
+
+/// The expanded value of a prompt variable such as PS1, or of its default while unset.
+pub fn prompt(shell: &Shell, name: &str, default: &str) -> String {
+    expand_var(shell, name).unwrap_or_else(|| expand(shell, default))
+}
+
+/// The prompts read with a line: PS1, RPROMPT and TRANSIENT_PROMPT.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Prompts {
+    pub prompt: String,
+    pub right_prompt: String,
+    pub transient_prompt: Option<String>,
+}
+
+/// The prompts read with a line, drawn at once while a worker thread works out their
+/// slow segments, such as git in a large repository or on a network filesystem.
+/// Dropping it cancels the worker.
+#[derive(Debug)]
+pub struct AsyncPrompt {
+    /// The prompts with a marked number where each slow segment goes.
+    prompts: Prompts,
+    /// The name and template of each slow segment.
+    slots: Vec<(String, String)>,
+    /// What a slow segment shows until it is worked out, PROMPT_PLACEHOLDER or `…`.
+    placeholder: String,
+    /// What the slow segments show, once they are worked out.
+    values: Option<Vec<String>>,
+    receiver: Option<Receiver<Vec<String>>>,
+    /// The read end of a pipe the worker closes when it is done, for poll to wait on.
+    done: Option<RawFd>,
+    cancelled: Arc<AtomicBool>,
+}
+
+/// What each of the slow segments named shows in `dir`.
+fn slow_values(names: &[String], dir: &Path, cancelled: &AtomicBool) -> Vec<String> {
+    names.iter()
+        .map(|name| match name.as_str() {
+            "git" => git_segment(dir, cancelled),
+            _ => String::new(),
+        })
+        .collect()
+}
+
+impl AsyncPrompt {
+    /// Expands the prompts and starts working out their slow segments.
+    pub fn start(shell: &Shell) -> AsyncPrompt {
+        let mut slots = Vec::new();
+        let mut render_var = |name: &str| shell.lookup_var(name).map(|value| render(shell, &value, Some(&mut slots)));
+        let prompt = render_var("PS1");
+        let right_prompt = render_var("RPROMPT").unwrap_or_default();
+        let transient_prompt = render_var("TRANSIENT_PROMPT");
+        let prompt = prompt.unwrap_or_else(|| expand(shell, DEFAULT_PS1));
+
+        let mut pending = AsyncPrompt {
+            prompts: Prompts { prompt, right_prompt, transient_prompt },
+            slots,
+            placeholder: shell.lookup_var("PROMPT_PLACEHOLDER").unwrap_or_else(|| "…".to_string()),
+            values: None,
+            receiver: None,
+            done: None,
+            cancelled: Arc::new(AtomicBool::new(false)),
+        };
+        let names: Vec<String> = pending.slots.iter().map(|(name, _)| name.clone()).collect();
+        let dir = env::current_dir().unwrap_or_default();
+        if names.is_empty() {
+            pending.values = Some(Vec::new());
+            return pending;
+        }
+        // commands started while the line is read must not keep the pipe open
+        let (read, write) = match unistd::pipe2(OFlag::O_CLOEXEC) {
+            Ok(pipe) => pipe,
+            Err(e) => {
+                eprintln!("rshell: prompt: {}", e);
+                pending.values = Some(slow_values(&names, &dir, &pending.cancelled));
+                return pending;
+            },
+        };
+        let (sender, receiver) = mpsc::channel();
+        let cancelled = Arc::clone(&pending.cancelled);
+        thread::spawn(move || {
+            let _ = sender.send(slow_values(&names, &dir, &cancelled));
+            let _ = unistd::close(write);
+        });
+        pending.receiver = Some(receiver);
+        pending.done = Some(read);
+
+        let mut fds = [PollFd::new(read, PollFlags::POLLIN)];
+        let _ = poll(&mut fds, ASYNC_WAIT_MS);
+        pending.update();
+        pending
+    }
+
+    /// What becomes readable once the slow segments are worked out, None when they are.
+    #[inline]
+    pub fn fd(&self) -> Option<RawFd> {
+        self.done
+    }
+
+    /// Takes the slow segments if the worker is done, true when that changed the prompts.
+    pub fn update(&mut self) -> bool {
+        let values = match self.receiver.as_ref().map(Receiver::try_recv) {
+            Some(Ok(values)) => values,
+            // the worker died before it was done
+            Some(Err(TryRecvError::Disconnected)) => vec![String::new(); self.slots.len()],
+            _ => return false,
+        };
+        self.values = Some(values);
+        self.receiver = None;
+        if let Some(fd) = self.done.take() {
+            let _ = unistd::close(fd);
+        }
+        true
+    }
+
+    /// Waits for the slow segments, for when the prompt cannot be drawn again later.
+    pub fn wait(&mut self) {
+        while let Some(fd) = self.done {
+            let mut fds = [PollFd::new(fd, PollFlags::POLLIN)];
+            match poll(&mut fds, -1) {
+                Ok(_) | Err(nix::errno::Errno::EINTR) => {
+                    self.update();
+                },
+                Err(_) => return,
+            }
+        }
+    }
+
+    /// The prompts as they are drawn now, with placeholders for the slow segments
+    /// still being worked out.
+    pub fn prompts(&self) -> Prompts {
+        let fill = |text: &str| {
+            let mut filled = String::new();
+            for (i, part) in text.split(SLOT).enumerate() {
+                if i % 2 == 0 {
+                    filled += part;
+                    continue;
+                }
+                let slot = part.parse::<usize>().unwrap_or(0);
+                let value = match &self.values {
+                    Some(values) => values.get(slot).map_or("", String::as_str),
+                    None => &self.placeholder,
+                };
+                filled += &fill_template(self.slots.get(slot).map_or("%s", |(_, template)| template), value);
+            }
+            filled
+        };
+        Prompts {
+            prompt: fill(&self.prompts.prompt),
+            right_prompt: fill(&self.prompts.right_prompt),
+            transient_prompt: self.prompts.transient_prompt.as_deref().map(fill),
+        }
+    }
+}
+
+impl Drop for AsyncPrompt {
+    fn drop(&mut self) {
+        self.cancelled.store(true, Relaxed);
+        if let Some(fd) = self.done.take() {
+            let _ = unistd::close(fd);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::variable::Value;
+
+    fn shell_with(vars: &[(&str, &str)]) -> Shell {
+        let mut shell = Shell::new(Path::new(""));
+        for (name, value) in vars {
+            shell.set_var(name, Value::String(value.to_string()));
+        }
+        shell
+    }
+
+    #[test]
+    fn backslash_escapes() {
+        let shell = shell_with(&[]);
+        let sign = if Uid::effective().is_root() { "#" } else { "$" };
+        assert_eq!(expand(&shell, "\\u@\\h\\$ "), format!("{}@{}{} ", user_name(), host_name().split('.').next().unwrap(), sign));
+        assert_eq!(expand(&shell, "\\s\\101\\n\\\\"), "rshellA\n\\");
+        assert_eq!(expand(&shell, "\\[\\e[1m\\]>"), "\x01\x1b[1m\x02>");
+        // what is not an escape stays as it was
+        assert_eq!(expand(&shell, "\\q \\{nope} 100%\\"), "\\q \\{nope} 100%\\");
+    }
+
+    #[test]
+    fn working_directory() {
+        let cwd = env::current_dir().unwrap();
+        let name = cwd.file_name().unwrap().to_string_lossy().into_owned();
+        let shell = shell_with(&[("HOME", &cwd.parent().unwrap().to_string_lossy())]);
+        assert_eq!(expand(&shell, "\\w \\W"), format!("~/{} {}", name, name));
+        let shell = shell_with(&[("HOME", &cwd.to_string_lossy())]);
+        assert_eq!(expand(&shell, "\\w \\W"), "~ ~");
+    }
+
+    #[test]
+    fn variables_and_commands() {
+        let shell = shell_with(&[("X", "val"), ("ESC", "\\u")]);
+        assert_eq!(expand(&shell, "$X ${X} $(echo hi) `echo ho` $"), "val val hi ho $");
+        // what a variable holds is not expanded again
+        assert_eq!(expand(&shell, "$ESC"), "\\u");
+    }
+
+    #[test]
+    fn segments() {
+        let mut shell = shell_with(&[("VIRTUAL_ENV", "/home/me/proj/")]);
+        assert_eq!(expand(&shell, "\\{status:[%s] }\\{venv:(%s) }>"), "(proj) >");
+        shell.set_last_status(3);
+        assert_eq!(expand(&shell, "\\{status:[%s] }>"), "[3] >");
+        assert_eq!(expand(&shell, "\\{jobs}\\{duration}"), "");
+    }
+
+    #[test]
+    fn prompts_without_slow_segments_are_ready_at_once() {
+        let shell = shell_with(&[("PS1", "\\s> "), ("RPROMPT", "$X"), ("X", "right")]);
+        let pending = AsyncPrompt::start(&shell);
+        assert_eq!(pending.fd(), None);
+        assert_eq!(pending.prompts(), Prompts {
+            prompt: "rshell> ".to_string(),
+            right_prompt: "right".to_string(),
+            transient_prompt: None,
+        });
+    }
+
+    #[test]
+    fn slow_segments_show_placeholders_until_worked_out() {
+        let shell = shell_with(&[("PS1", "\\{git:(%s) }$ "), ("TRANSIENT_PROMPT", "\\{git}> "), ("PROMPT_PLACEHOLDER", "?")]);
+        let mut pending = AsyncPrompt::start(&shell);
+        assert_eq!(pending.slots.len(), 2);
+
+        // as drawn before the worker is done
+        let waiting = AsyncPrompt {
+            prompts: pending.prompts.clone(),
+            slots: pending.slots.clone(),
+            placeholder: pending.placeholder.clone(),
+            values: None,
+            receiver: None,
+            done: None,
+            cancelled: Arc::new(AtomicBool::new(false)),
+        };
+        assert_eq!(waiting.prompts().prompt, "(?) $ ");
+        assert_eq!(waiting.prompts().transient_prompt.as_deref(), Some("?> "));
+
+        pending.wait();
+        assert_eq!(pending.fd(), None);
+        let git = git_segment(&env::current_dir().unwrap(), &AtomicBool::new(false));
+        assert_eq!(pending.prompts().prompt, fill_template("(%s) ", &git) + "$ ");
+        assert_eq!(pending.prompts().transient_prompt, Some(format!("{}> ", git)));
+    }
+}
@@ -0,0 +1,604 @@
+//! The state of the git repository around a directory, read from its files
+//! rather than by running git for every prompt.
+
+mod inflate;
+mod sha1;
+
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap,HashMap};
+use std::fs::{self,File};
+use std::io::{Read,Seek,SeekFrom};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path,PathBuf};
+use std::sync::atomic::{AtomicBool,Ordering::Relaxed};
+
+type Oid = [u8; 20];
+
+const COMMIT: u8 = 1;
+const OFS_DELTA: u8 = 6;
+const REF_DELTA: u8 = 7;
+/// Most commits walked to count how far a branch and its upstream have gone apart.
+const WALK_LIMIT: usize = 10000;
+/// Most deltas followed to rebuild one packed object.
+const DELTA_DEPTH: usize = 64;
+const GITLINK: u32 = 0o160000;
+const SYMLINK: u32 = 0o120000;
+
+/// What the git segment of the prompt shows.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Status {
+    /// The branch, or the abbreviated commit when HEAD is detached.
+    pub head: String,
+    /// Whether a tracked file differs from the index or a merge left conflicts.
+    pub dirty: bool,
+    /// Commits on the branch that its upstream does not have.
+    pub ahead: usize,
+    /// Commits on the upstream that the branch does not have.
+    pub behind: usize,
+}
+
+struct Repo {
+    git_dir: PathBuf,
+    /// Where refs and objects live, which differs from `git_dir` in a linked worktree.
+    common_dir: PathBuf,
+    work_tree: PathBuf,
+}
+
+fn parse_oid(hex: &str) -> Option<Oid> {
+    let hex = hex.trim();
+    if hex.len() != 40 {
+        return None;
+    }
+    let mut oid = [0u8; 20];
+    for (i, byte) in oid.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(oid)
+}
+
+fn to_hex(oid: &Oid) -> String {
+    oid.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[inline]
+fn be32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// The repository `start` is in, found through a `.git` directory or a `.git` file
+/// pointing elsewhere as worktrees and submodules have.
+fn find_repo(start: &Path) -> Option<Repo> {
+    for dir in start.ancestors() {
+        let dot_git = dir.join(".git");
+        let git_dir = if dot_git.is_dir() {
+            dot_git
+        }
+        else if dot_git.is_file() {
+            let text = fs::read_to_string(&dot_git).ok()?;
+            dir.join(text.trim().strip_prefix("gitdir:")?.trim())
+        }
+        else {
+            continue;
+        };
+        let common_dir = match fs::read_to_string(git_dir.join("commondir")) {
+            Ok(common) => git_dir.join(common.trim()),
+            Err(_) => git_dir.clone(),
+        };
+        return Some(Repo { git_dir, common_dir, work_tree: dir.to_path_buf() });
+    }
+    None
+}
+
+impl Repo {
+    /// The commit a full ref name such as `refs/heads/main` points at, following symbolic refs.
+    fn resolve(&self, name: &str) -> Option<Oid> {
+        let mut name = name.to_string();
+        for _ in 0..5 {
+            let dir = if name == "HEAD" { &self.git_dir } else { &self.common_dir };
+            match fs::read_to_string(dir.join(&name)) {
+                Ok(text) => match text.trim().strip_prefix("ref:") {
+                    Some(target) => name = target.trim().to_string(),
+                    None => return parse_oid(&text),
+                },
+                Err(_) => {
+                    let packed = fs::read_to_string(self.common_dir.join("packed-refs")).ok()?;
+                    return packed.lines()
+                        .filter(|line| !line.starts_with('#') && !line.starts_with('^'))
+                        .filter_map(|line| line.split_once(' '))
+                        .find(|(_, refname)| *refname == name)
+                        .and_then(|(oid, _)| parse_oid(oid));
+                },
+            }
+        }
+        None
+    }
+
+    /// The ref a branch tracks, from `branch.<name>.remote` and `branch.<name>.merge`.
+    fn upstream(&self, branch: &str) -> Option<String> {
+        let config = fs::read_to_string(self.common_dir.join("config")).ok()?;
+        let header = format!("[branch \"{}\"]", branch);
+        let mut in_section = false;
+        let (mut remote, mut merge) = (None, None);
+        for line in config.lines().map(str::trim) {
+            if line.starts_with('[') {
+                in_section = line == header;
+                continue;
+            }
+            match line.split_once('=') {
+                Some((key, value)) if in_section => match key.trim() {
+                    "remote" => remote = Some(value.trim().to_string()),
+                    "merge" => merge = Some(value.trim().to_string()),
+                    _ => (),
+                },
+                _ => (),
+            }
+        }
+        let (remote, merge) = (remote?, merge?);
+        let branch = merge.strip_prefix("refs/heads/")?;
+        match remote.as_str() {
+            "." => Some(format!("refs/heads/{}", branch)),
+            _ => Some(format!("refs/remotes/{}/{}", remote, branch)),
+        }
+    }
+
+    /// Whether a tracked file differs from what the index holds. Files whose size and
+    /// modification time match the index are taken to be unchanged, the rest are hashed.
+    fn is_dirty(&self, cancelled: &AtomicBool) -> bool {
+        let index = match fs::read(self.git_dir.join("index")) {
+            Ok(index) => index,
+            Err(_) => return false,
+        };
+        if index.len() < 12 || &index[..4] != b"DIRC" {
+            return false;
+        }
+        let version = be32(&index[4..8]);
+        let count = be32(&index[8..12]);
+        if !(2..=4).contains(&version) {
+            return false;
+        }
+
+        let mut i = 12;
+        let mut path: Vec<u8> = Vec::new();
+        for _ in 0..count {
+            if cancelled.load(Relaxed) {
+                return false;
+            }
+            let entry = match index.get(i..i + 62) {
+                Some(entry) => entry,
+                None => return false,
+            };
+            let flags = u16::from_be_bytes([entry[60], entry[61]]);
+            let mut name = i + 62;
+            let mut extended = 0;
+            if version >= 3 && flags & 0x4000 != 0 {
+                extended = index.get(name..name + 2).map_or(0, |bytes| u16::from_be_bytes([bytes[0], bytes[1]]));
+                name += 2;
+            }
+            // a damaged index must not take the shell down, so every read is checked
+            let nul = |start: usize| index.get(start..)?.iter().position(|byte| *byte == 0).map(|nul| start + nul);
+            if version == 4 {
+                // the path drops the end of the one before it and adds what follows
+                let (strip, len) = match index.get(name..).and_then(varint) {
+                    Some(varint) => varint,
+                    None => return false,
+                };
+                let end = match nul(name + len) {
+                    Some(end) => end,
+                    None => return false,
+                };
+                path.truncate(path.len().saturating_sub(strip as usize));
+                path.extend_from_slice(&index[name + len..end]);
+                i = end + 1;
+            }
+            else {
+                let end = match nul(name) {
+                    Some(end) => end,
+                    None => return false,
+                };
+                path = index[name..end].to_vec();
+                i += (name - i + path.len() + 8) & !7;
+            }
+
+            // a merge in progress leaves the conflicted files in stages 1 to 3
+            if flags & 0x3000 != 0 {
+                return true;
+            }
+            let mode = be32(&entry[24..28]);
+            // submodules and sparse checkouts have nothing to compare with
+            if mode == GITLINK || extended & 0x4000 != 0 {
+                continue;
+            }
+            if self.file_changed(&path, entry, mode) {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn file_changed(&self, path: &[u8], entry: &[u8], mode: u32) -> bool {
+        let file = self.work_tree.join(Path::new(std::ffi::OsStr::from_bytes(path)));
+        let metadata = match fs::symlink_metadata(&file) {
+            Ok(metadata) => metadata,
+            Err(_) => return true,
+        };
+        let (mtime, mtime_nsec, size) = (be32(&entry[8..12]), be32(&entry[12..16]), be32(&entry[36..40]));
+        if metadata.mtime() as u32 == mtime && metadata.mtime_nsec() as u32 == mtime_nsec && metadata.size() as u32 == size {
+            return false;
+        }
+        let content = if mode == SYMLINK {
+            fs::read_link(&file).map(|target| target.as_os_str().as_bytes().to_vec())
+        }
+        else {
+            fs::read(&file)
+        };
+        let content = match content {
+            Ok(content) => content,
+            Err(_) => return true,
+        };
+        let mut blob = format!("blob {}\0", content.len()).into_bytes();
+        blob.extend_from_slice(&content);
+        sha1::sha1(&blob)[..] != entry[40..60]
+    }
+}
+
+/// The variable length offset git uses in ofs-delta headers and version 4 indexes,
+/// with how many bytes it took.
+fn varint(bytes: &[u8]) -> Option<(u64, usize)> {
+    let mut i = 0;
+    let mut byte = *bytes.first()?;
+    let mut value = (byte & 0x7f) as u64;
+    while byte & 0x80 != 0 {
+        i += 1;
+        byte = *bytes.get(i)?;
+        value = value.checked_add(1)?.checked_mul(1 << 7)? | (byte & 0x7f) as u64;
+    }
+    Some((value, i + 1))
+}
+
+/// The size varint at the start of a delta, little end first.
+fn delta_size(delta: &[u8], i: &mut usize) -> Option<usize> {
+    let mut size = 0;
+    let mut shift = 0;
+    loop {
+        let byte = *delta.get(*i)?;
+        *i += 1;
+        size |= ((byte & 0x7f) as usize).checked_shl(shift)?;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Some(size);
+        }
+    }
+}
+
+/// Rebuilds an object from its base and a delta of copy and insert instructions.
+fn apply_delta(base: &[u8], delta: &[u8]) -> Option<Vec<u8>> {
+    let mut i = 0;
+    if delta_size(delta, &mut i)? != base.len() {
+        return None;
+    }
+    let size = delta_size(delta, &mut i)?;
+    // the size comes from the pack, which may be damaged, so it only guides the allocation
+    let mut out = Vec::with_capacity(size.min(base.len() + delta.len()));
+    while i < delta.len() {
+        let op = delta[i];
+        i += 1;
+        if op & 0x80 != 0 {
+            let (mut offset, mut len) = (0usize, 0usize);
+            for bit in 0..4 {
+                if op & (1 << bit) != 0 {
+                    offset |= (*delta.get(i)? as usize) << (8 * bit);
+                    i += 1;
+                }
+            }
+            for bit in 0..3 {
+                if op & (0x10 << bit) != 0 {
+                    len |= (*delta.get(i)? as usize) << (8 * bit);
+                    i += 1;
+                }
+            }
+            if len == 0 {
+                len = 0x10000;
+            }
+            out.extend_from_slice(base.get(offset..offset + len)?);
+        }
+        else if op != 0 {
+            out.extend_from_slice(delta.get(i..i + op as usize)?);
+            i += op as usize;
+        }
+        else {
+            return None;
+        }
+    }
+    Some(out).filter(|out| out.len() == size)
+}
+
+fn read_at(file: &mut File, offset: u64, len: usize) -> Option<Vec<u8>> {
+    file.seek(SeekFrom::Start(offset)).ok()?;
+    let mut buffer = Vec::with_capacity(len);
+    file.take(len as u64).read_to_end(&mut buffer).ok()?;
+    Some(buffer)
+}
+
+/// Where an object is in the pack, looked up in its version 2 index one name at a time
+/// so that large indexes are never read whole.
+fn find_in_index(path: &Path, oid: &Oid) -> Option<u64> {
+    let mut file = File::open(path).ok()?;
+    let header = read_at(&mut file, 0, 8 + 256 * 4)?;
+    if header.len() < 1032 || header[..4] != [0xff, b't', b'O', b'c'] || be32(&header[4..8]) != 2 {
+        return None;
+    }
+    let fanout = |byte: usize| be32(&header[8 + byte * 4..]) as u64;
+    let total = fanout(255);
+    let mut low = if oid[0] == 0 { 0 } else { fanout(oid[0] as usize - 1) };
+    let mut high = fanout(oid[0] as usize);
+    while low < high {
+        let middle = (low + high) / 2;
+        let name = read_at(&mut file, 1032 + middle * 20, 20)?;
+        match name.as_slice().cmp(&oid[..]) {
+            Ordering::Less => low = middle + 1,
+            Ordering::Greater => high = middle,
+            Ordering::Equal => {
+                // after the names come their checksums, then the offsets
+                let offsets = 1032 + total * 24;
+                let offset = be32(&read_at(&mut file, offsets + middle * 4, 4)?);
+                if offset & 0x8000_0000 == 0 {
+                    return Some(offset as u64);
+                }
+                let large = read_at(&mut file, offsets + total * 4 + (offset & 0x7fff_ffff) as u64 * 8, 8)?;
+                return Some(u64::from_be_bytes(large.try_into().ok()?));
+            },
+        }
+    }
+    None
+}
+
+/// The loose and packed objects of a repository.
+struct Objects {
+    dir: PathBuf,
+    indexes: Vec<PathBuf>,
+}
+
+impl Objects {
+    fn new(repo: &Repo) -> Objects {
+        let dir = repo.common_dir.join("objects");
+        let indexes = fs::read_dir(dir.join("pack"))
+            .map(|entries| entries.flatten()
+                .map(|entry| entry.path())
+                .filter(|path| path.extension().is_some_and(|extension| extension == "idx"))
+                .collect())
+            .unwrap_or_default();
+        Objects { dir, indexes }
+    }
+
+    /// The type and content of an object.
+    fn read(&self, oid: &Oid, depth: usize) -> Option<(u8, Vec<u8>)> {
+        let hex = to_hex(oid);
+        if let Ok(data) = fs::read(self.dir.join(&hex[..2]).join(&hex[2..])) {
+            let raw = inflate::decompress(&data)?;
+            let nul = raw.iter().position(|byte| *byte == 0)?;
+            let kind = match raw[..nul].split(|byte| *byte == b' ').next()? {
+                b"commit" => COMMIT,
+                b"tree" => 2,
+                b"blob" => 3,
+                b"tag" => 4,
+                _ => return None,
+            };
+            return Some((kind, raw[nul + 1..].to_vec()));
+        }
+        self.indexes.iter().find_map(|index| {
+            let offset = find_in_index(index, oid)?;
+            self.read_packed(&index.with_extension("pack"), offset, depth)
+        })
+    }
+
+    fn read_packed(&self, pack: &Path, offset: u64, depth: usize) -> Option<(u8, Vec<u8>)> {
+        if depth > DELTA_DEPTH {
+            return None;
+        }
+        let mut file = File::open(pack).ok()?;
+        // how long the compressed data is only shows once it is inflated
+        let mut chunk = 4096;
+        loop {
+            let data = read_at(&mut file, offset, chunk)?;
+            let mut i = 0;
+            let mut byte = *data.first()?;
+            let kind = (byte >> 4) & 7;
+            while byte & 0x80 != 0 {
+                i += 1;
+                byte = *data.get(i)?;
+            }
+            i += 1;
+            let base = match kind {
+                OFS_DELTA => {
+                    let (back, len) = varint(data.get(i..)?)?;
+                    i += len;
+                    Some(self.read_packed(pack, offset.checked_sub(back)?, depth + 1)?)
+                },
+                REF_DELTA => {
+                    let oid: Oid = data.get(i..i + 20)?.try_into().ok()?;
+                    i += 20;
+                    Some(self.read(&oid, depth + 1)?)
+                },
+                _ => None,
+            };
+            match inflate::decompress(data.get(i..)?) {
+                Some(body) => return match base {
+                    Some((base_kind, base)) => Some((base_kind, apply_delta(&base, &body)?)),
+                    None => Some((kind, body)),
+                },
+                None if data.len() == chunk => chunk *= 8,
+                None => return None,
+            }
+        }
+    }
+
+    /// The parents and commit time of a commit.
+    fn commit(&self, oid: &Oid) -> Option<(Vec<Oid>, i64)> {
+        let (kind, body) = self.read(oid, 0)?;
+        if kind != COMMIT {
+            return None;
+        }
+        let text = String::from_utf8_lossy(&body);
+        let mut parents = Vec::new();
+        let mut time = 0;
+        for line in text.lines().take_while(|line| !line.is_empty()) {
+            if let Some(parent) = line.strip_prefix("parent ") {
+                parents.extend(parse_oid(parent));
+            }
+            else if let Some(committer) = line.strip_prefix("committer ") {
+                time = committer.rsplit(' ').nth(1).and_then(|time| time.parse().ok()).unwrap_or(0);
+            }
+        }
+        Some((parents, time))
+    }
+
+    /// How many commits each side has that the other does not, walking both histories
+    /// newest first until only shared commits are left. Commit times can tie or go
+    /// backwards, so a commit reached again from the other side passes that on to its
+    /// parents once more, and the counting waits until the walk is over.
+    fn ahead_behind(&self, local: Oid, upstream: Oid, cancelled: &AtomicBool) -> (usize, usize) {
+        const LOCAL: u8 = 1;
+        const UPSTREAM: u8 = 2;
+        let mut flags: HashMap<Oid, u8> = HashMap::new();
+        let mut commits: HashMap<Oid, (Vec<Oid>, i64)> = HashMap::new();
+        let mut queue: BinaryHeap<(i64, Oid)> = BinaryHeap::new();
+
+        for (oid, flag) in [(local, LOCAL), (upstream, UPSTREAM)] {
+            *flags.entry(oid).or_insert(0) |= flag;
+            if let Some(commit) = self.commit(&oid) {
+                queue.push((commit.1, oid));
+                commits.insert(oid, commit);
+            }
+        }
+
+        let mut walked = 0;
+        while let Some((_, oid)) = queue.pop() {
+            let flag = flags[&oid];
+            let parents = commits.get(&oid).map(|(parents, _)| parents.clone()).unwrap_or_default();
+            for parent in parents {
+                let parent_flag = flags.entry(parent).or_insert(0);
+                if *parent_flag | flag == *parent_flag {
+                    continue;
+                }
+                *parent_flag |= flag;
+                if let Some(commit) = commits.get(&parent).cloned().or_else(|| self.commit(&parent)) {
+                    queue.push((commit.1, parent));
+                    commits.insert(parent, commit);
+                }
+            }
+            walked += 1;
+            if walked >= WALK_LIMIT || cancelled.load(Relaxed) || queue.iter().all(|(_, oid)| flags[oid] == LOCAL | UPSTREAM) {
+                break;
+            }
+        }
+        let count = |side| flags.values().filter(|flag| **flag == side).count();
+        (count(LOCAL), count(UPSTREAM))
+    }
+}
+
+/// The branch and state of the repository `dir` is in, None outside one or once
+/// `cancelled` is set.
+pub fn status(dir: &Path, cancelled: &AtomicBool) -> Option<Status> {
+    let repo = find_repo(dir)?;
+    let head = fs::read_to_string(repo.git_dir.join("HEAD")).ok()?;
+    let (name, local) = match head.trim().strip_prefix("ref:").map(str::trim) {
+        Some(target) => (target.strip_prefix("refs/heads/").unwrap_or(target).to_string(), repo.resolve(target)),
+        None => {
+            let oid = parse_oid(&head)?;
+            (to_hex(&oid)[..7].to_string(), Some(oid))
+        },
+    };
+
+    let upstream = match head.trim().strip_prefix("ref:") {
+        Some(_) => repo.upstream(&name).and_then(|upstream| repo.resolve(&upstream)),
+        None => None,
+    };
+    let (ahead, behind) = match (local, upstream) {
+        (Some(local), Some(upstream)) if local != upstream => Objects::new(&repo).ahead_behind(local, upstream, cancelled),
+        _ => (0, 0),
+    };
+    let dirty = repo.is_dirty(cancelled);
+    if cancelled.load(Relaxed) {
+        return None;
+    }
+    Some(Status { head: name, dirty, ahead, behind })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A work tree holding `a.txt` and an index of the given version that tracks it unchanged
+    /// apart from the modification time, so deciding that needs the path read right.
+    fn repo_with_index(name: &str, version: u32) -> (Repo, Vec<u8>) {
+        let work_tree = std::env::temp_dir().join(format!("rshell-git-{}-{}", std::process::id(), name));
+        let git_dir = work_tree.join(".git");
+        fs::create_dir_all(&git_dir).unwrap();
+        fs::write(work_tree.join("a.txt"), "hello\n").unwrap();
+
+        let mut index = b"DIRC".to_vec();
+        index.extend_from_slice(&version.to_be_bytes());
+        index.extend_from_slice(&1u32.to_be_bytes());
+        let mut entry = [0u8; 62];
+        entry[24..28].copy_from_slice(&0o100644u32.to_be_bytes());
+        entry[36..40].copy_from_slice(&6u32.to_be_bytes());
+        entry[40..60].copy_from_slice(&sha1::sha1(b"blob 6\0hello\n"));
+        entry[60..62].copy_from_slice(&5u16.to_be_bytes());
+        index.extend_from_slice(&entry);
+        if version == 4 {
+            // nothing taken from the previous path, which is a zero byte before the name
+            index.push(0);
+            index.extend_from_slice(b"a.txt\0");
+        }
+        else {
+            index.extend_from_slice(b"a.txt\0\0\0\0\0");
+        }
+        (Repo { common_dir: git_dir.clone(), git_dir, work_tree }, index)
+    }
+
+    fn is_dirty(repo: &Repo, index: &[u8]) -> bool {
+        fs::write(repo.git_dir.join("index"), index).unwrap();
+        repo.is_dirty(&AtomicBool::new(false))
+    }
+
+    #[test]
+    fn reads_index_paths() {
+        for version in [2, 4] {
+            let (repo, index) = repo_with_index(&format!("v{}", version), version);
+            assert!(!is_dirty(&repo, &index));
+            fs::write(repo.work_tree.join("a.txt"), "changed\n").unwrap();
+            assert!(is_dirty(&repo, &index));
+            fs::remove_dir_all(&repo.work_tree).unwrap();
+        }
+    }
+
+    #[test]
+    fn damaged_indexes_are_not_dirty() {
+        for version in [2, 4] {
+            let (repo, index) = repo_with_index(&format!("damaged-v{}", version), version);
+            for len in 0..index.len() - 1 {
+                assert!(!is_dirty(&repo, &index[..len]), "version {} cut at {}", version, len);
+            }
+            let mut index = index[..74].to_vec();
+            index.extend_from_slice(&[0xff; 16]);
+            assert!(!is_dirty(&repo, &index));
+            if version == 4 {
+                // a zero byte inside the varint is not where the path ends
+                index.truncate(74);
+                index.extend_from_slice(b"\x80\x00a.txt\0");
+                assert!(!is_dirty(&repo, &index));
+            }
+            fs::remove_dir_all(&repo.work_tree).unwrap();
+        }
+    }
+
+    #[test]
+    fn varints() {
+        assert_eq!(varint(&[0x00]), Some((0, 1)));
+        assert_eq!(varint(&[0x7f]), Some((127, 1)));
+        assert_eq!(varint(&[0x80, 0x00]), Some((128, 2)));
+        assert_eq!(varint(&[0x80]), None);
+        assert_eq!(varint(&[0xff; 16]), None);
+    }
+}
@@ -0,0 +1,241 @@
+//! A small DEFLATE decoder (RFC 1951) for the zlib streams git stores objects in.
+
+const MAX_BITS: usize = 15;
+const LENGTH_BASE: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASE: [u16; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577];
+const DISTANCE_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+/// The order the lengths of the code length code come in.
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+/// A canonical Huffman code, as the number of codes of each length and the symbols in code order.
+struct Huffman {
+    counts: [u16; MAX_BITS + 1],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Huffman {
+        let mut counts = [0u16; MAX_BITS + 1];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+        let mut offsets = [0u16; MAX_BITS + 2];
+        for length in 1..=MAX_BITS {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+        let mut symbols = vec![0u16; lengths.len()];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                symbols[offsets[length as usize] as usize] = symbol as u16;
+                offsets[length as usize] += 1;
+            }
+        }
+        Huffman { counts, symbols }
+    }
+}
+
+struct Bits<'a> {
+    data: &'a [u8],
+    position: usize,
+    buffer: u32,
+    count: u32,
+}
+
+impl Bits<'_> {
+    fn bits(&mut self, need: u32) -> Option<u32> {
+        while self.count < need {
+            let byte = *self.data.get(self.position)?;
+            self.position += 1;
+            self.buffer |= (byte as u32) << self.count;
+            self.count += 8;
+        }
+        let value = self.buffer & ((1u64 << need) - 1) as u32;
+        self.buffer >>= need;
+        self.count -= need;
+        Some(value)
+    }
+
+    fn decode(&mut self, huffman: &Huffman) -> Option<u16> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for length in 1..=MAX_BITS {
+            code |= self.bits(1)? as i32;
+            let count = huffman.counts[length] as i32;
+            if code - count < first {
+                return huffman.symbols.get((index + code - first) as usize).copied();
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        None
+    }
+}
+
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; 288];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    (Huffman::new(&lengths), Huffman::new(&[5; 30]))
+}
+
+fn dynamic_codes(bits: &mut Bits) -> Option<(Huffman, Huffman)> {
+    let literals = bits.bits(5)? as usize + 257;
+    let distances = bits.bits(5)? as usize + 1;
+    let code_lengths = bits.bits(4)? as usize + 4;
+
+    let mut lengths = [0u8; 19];
+    for &i in CODE_LENGTH_ORDER.iter().take(code_lengths) {
+        lengths[i] = bits.bits(3)? as u8;
+    }
+    let code_length_code = Huffman::new(&lengths);
+
+    let mut lengths = vec![0u8; literals + distances];
+    let mut i = 0;
+    while i < lengths.len() {
+        let symbol = bits.decode(&code_length_code)?;
+        let (value, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => (*lengths.get(i.checked_sub(1)?)?, 3 + bits.bits(2)? as usize),
+            17 => (0, 3 + bits.bits(3)? as usize),
+            _ => (0, 11 + bits.bits(7)? as usize),
+        };
+        if i + repeat > lengths.len() {
+            return None;
+        }
+        lengths[i..i + repeat].fill(value);
+        i += repeat;
+    }
+    Some((Huffman::new(&lengths[..literals]), Huffman::new(&lengths[literals..])))
+}
+
+fn inflate_block(bits: &mut Bits, out: &mut Vec<u8>, literal: &Huffman, distance: &Huffman) -> Option<()> {
+    loop {
+        let symbol = bits.decode(literal)? as usize;
+        match symbol {
+            0..=255 => out.push(symbol as u8),
+            256 => return Some(()),
+            _ => {
+                let symbol = symbol - 257;
+                let length = *LENGTH_BASE.get(symbol)? as usize + bits.bits(LENGTH_EXTRA[symbol] as u32)? as usize;
+                let symbol = bits.decode(distance)? as usize;
+                let back = *DISTANCE_BASE.get(symbol)? as usize + bits.bits(DISTANCE_EXTRA[symbol] as u32)? as usize;
+                let start = out.len().checked_sub(back)?;
+                // the copy may overlap what it produces, so it goes a byte at a time
+                for i in 0..length {
+                    out.push(out[start + i]);
+                }
+            },
+        }
+    }
+}
+
+/// Decompresses a zlib stream, None if it is damaged or cut short.
+pub fn decompress(data: &[u8]) -> Option<Vec<u8>> {
+    if data.len() < 2 || data[0] & 0x0f != 8 || !((data[0] as u16) << 8 | data[1] as u16).is_multiple_of(31) {
+        return None;
+    }
+    let mut bits = Bits { data: &data[2..], position: 0, buffer: 0, count: 0 };
+    let mut out = Vec::new();
+    loop {
+        let last = bits.bits(1)? == 1;
+        match bits.bits(2)? {
+            0 => {
+                // stored blocks start at a byte boundary
+                bits.buffer = 0;
+                bits.count = 0;
+                let header = bits.data.get(bits.position..bits.position + 4)?;
+                let len = u16::from_le_bytes([header[0], header[1]]) as usize;
+                bits.position += 4;
+                out.extend_from_slice(bits.data.get(bits.position..bits.position + len)?);
+                bits.position += len;
+            },
+            1 => {
+                let (literal, distance) = fixed_codes();
+                inflate_block(&mut bits, &mut out, &literal, &distance)?;
+            },
+            2 => {
+                let (literal, distance) = dynamic_codes(&mut bits)?;
+                inflate_block(&mut bits, &mut out, &literal, &distance)?;
+            },
+            _ => return None,
+        }
+        if last {
+            return Some(out);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decompress;
+
+    #[test]
+    fn stored_block() {
+        let data = &[
+            0x78, 0x01, 0x01, 0x14, 0x00, 0xeb, 0xff, 0x68, 0x65, 0x6c, 0x6c, 0x6f, 0x2c, 0x20, 0x73, 0x74,
+            0x6f, 0x72, 0x65, 0x64, 0x20, 0x62, 0x6c, 0x6f, 0x63, 0x6b, 0x0a, 0x4d, 0xf5, 0x07, 0x27,
+        ];
+        assert_eq!(decompress(data).unwrap(), b"hello, stored block\n");
+    }
+
+    #[test]
+    fn fixed_block() {
+        let data = &[
+            0x78, 0xda, 0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0x57, 0xc8, 0x40, 0x90, 0x3a, 0x0a, 0x69, 0x99, 0x15,
+            0xa9, 0x29, 0x0a, 0x49, 0x39, 0xf9, 0xc9, 0xd9, 0x5c, 0x00, 0xb6, 0xc1, 0x0b, 0x0e,
+        ];
+        assert_eq!(decompress(data).unwrap(), b"hello hello hello, fixed block\n");
+    }
+
+    #[test]
+    fn dynamic_block() {
+        let data = &[
+            0x78, 0xda, 0xb5, 0xcc, 0xd1, 0x11, 0x82, 0x30, 0x10, 0x45, 0xd1, 0x7f, 0xab, 0x78, 0x25, 0x88,
+            0x22, 0xcc, 0xd8, 0x4d, 0x94, 0x8d, 0x04, 0x63, 0x36, 0x86, 0x0d, 0x89, 0x56, 0x2f, 0x52, 0x03,
+            0xfb, 0x79, 0xe7, 0xce, 0x1c, 0xef, 0x02, 0xe1, 0x08, 0xb6, 0x30, 0x10, 0xaa, 0x82, 0xe2, 0x64,
+            0x44, 0xe1, 0x34, 0xcc, 0x90, 0xd1, 0x08, 0x12, 0x45, 0x32, 0x72, 0x5d, 0x83, 0xf0, 0xce, 0xee,
+            0xfe, 0xc4, 0x2d, 0x71, 0x09, 0xb0, 0x5c, 0x31, 0xe5, 0x57, 0x9c, 0xc1, 0x0b, 0xa5, 0x6d, 0x7b,
+            0xf3, 0xfd, 0x60, 0xe0, 0xc7, 0xc1, 0xff, 0xd1, 0x46, 0x03, 0x3d, 0x69, 0xa0, 0x67, 0x0d, 0xb4,
+            0xd5, 0x40, 0x2f, 0x1a, 0x68, 0xa7, 0x81, 0xf6, 0x3b, 0xa3, 0x3f, 0x51, 0x5a, 0xf2, 0x85,
+        ];
+        let text: String = (0..8)
+            .map(|i| format!("line {} of a text with words that repeat: the quick brown fox jumps over the lazy dog\n", i))
+            .collect();
+        assert_eq!(decompress(data).unwrap(), text.as_bytes());
+    }
+
+    #[test]
+    fn several_blocks_with_overlapping_copies() {
+        let data = &[
+            0x78, 0xda, 0x4a, 0xcb, 0x2c, 0x2a, 0x2e, 0x51, 0x48, 0xca, 0xc9, 0x4f, 0xce, 0xd6, 0x51, 0x00,
+            0x00, 0x00, 0x00, 0xff, 0xff, 0x4b, 0x4c, 0x24, 0x0e, 0x00, 0x00, 0x13, 0x17, 0x13, 0xc8,
+        ];
+        let mut text = b"first block, ".to_vec();
+        text.extend_from_slice(&[b'a'; 40]);
+        assert_eq!(decompress(data).unwrap(), text);
+    }
+
+    #[test]
+    fn damaged_streams() {
+        let data = &[
+            0x78, 0xda, 0xb5, 0xcc, 0xd1, 0x11, 0x82, 0x30, 0x10, 0x45, 0xd1, 0x7f, 0xab, 0x78, 0x25, 0x88,
+            0x22, 0xcc, 0xd8, 0x4d, 0x94, 0x8d, 0x04, 0x63, 0x36, 0x86, 0x0d, 0x89, 0x56, 0x2f, 0x52, 0x03,
+            0xfb, 0x79, 0xe7, 0xce, 0x1c, 0xef, 0x02, 0xe1, 0x08, 0xb6, 0x30, 0x10, 0xaa, 0x82, 0xe2, 0x64,
+            0x44, 0xe1, 0x34, 0xcc, 0x90, 0xd1, 0x08, 0x12, 0x45, 0x32, 0x72, 0x5d, 0x83, 0xf0, 0xce, 0xee,
+            0xfe, 0xc4, 0x2d, 0x71, 0x09, 0xb0, 0x5c, 0x31, 0xe5, 0x57, 0x9c, 0xc1, 0x0b, 0xa5, 0x6d, 0x7b,
+            0xf3, 0xfd, 0x60, 0xe0, 0xc7, 0xc1, 0xff, 0xd1, 0x46, 0x03, 0x3d, 0x69, 0xa0, 0x67, 0x0d, 0xb4,
+            0xd5, 0x40, 0x2f, 0x1a, 0x68, 0xa7, 0x81, 0xf6, 0x3b, 0xa3, 0x3f, 0x51, 0x5a, 0xf2, 0x85,
+        ];
+        for len in 0..data.len() - 4 {
+            assert_eq!(decompress(&data[..len]), None);
+        }
+        let mut bad_header = data.to_vec();
+        bad_header[1] ^= 1;
+        assert_eq!(decompress(&bad_header), None);
+        // a block type of 3 is reserved
+        assert_eq!(decompress(&[0x78, 0x01, 0x07]), None);
+    }
+}
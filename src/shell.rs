@@ -1,11 +1,19 @@
-use crate::process::{Job,Redirection,Process};
-use crate::variable::Variables;
+use crate::complete::CompletionSpec;
+use crate::expr;
+use crate::histdb::HistoryDb;
+use crate::history::{History,HistorySettings};
+use crate::process::{Job,Redirection,Process,ProcessStatus};
+use crate::signals::{self,Trap};
+use crate::variable::{Value,Variable,Variables};
 use std::collections::{HashMap,HashSet};
 use std::rc::Rc;
 use std::cell::RefCell;
-use nix::unistd::{getpid,Pid};
-use std::path::Path;
+use nix::sys::signal::Signal;
+use nix::libc::STDIN_FILENO;
+use nix::unistd::{self,getpid,Pid};
+use std::path::{Path,PathBuf};
 use std::env;
+use std::time::{Duration,SystemTime};
 use core::hash::{Hasher, Hash};
 
 
@@ -37,12 +45,109 @@ impl Eq for JobWrapper {}
 
 
 
+/// Options toggled with `set -o name` and `set +o name`.
+#[derive(Debug)]
+pub struct ShellOptions {
+    pub pipefail: bool,
+    pub huponexit: bool,
+    pub histexpand: bool,
+    /// Complete the options of commands without a completion spec from their man page or `--help`.
+    pub helpcomplete: bool,
+    /// vi rather than emacs key bindings in the line editor.
+    pub vi: bool,
+    /// Exit as soon as a command fails, `set -e`.
+    pub errexit: bool,
+    /// Treat expanding an unset variable as an error, `set -u`.
+    pub nounset: bool,
+    /// Print each command with PS4 before running it, `set -x`.
+    pub xtrace: bool,
+    /// Read commands without running them, `set -n`. Interactive shells ignore it.
+    pub noexec: bool,
+    /// Behave as POSIX asks where that differs, such as reading the file ENV names at startup.
+    pub posix: bool,
+}
+
+impl Default for ShellOptions {
+    fn default() -> ShellOptions {
+        ShellOptions {
+            pipefail: false,
+            huponexit: false,
+            histexpand: true,
+            helpcomplete: false,
+            vi: false,
+            errexit: false,
+            nounset: false,
+            xtrace: false,
+            noexec: false,
+            posix: false,
+        }
+    }
+}
+
+impl ShellOptions {
+    pub const NAMES: &'static [&'static str] = &[
+        "emacs", "errexit", "helpcomplete", "histexpand", "huponexit", "noexec", "nounset", "pipefail", "posix", "vi", "xtrace",
+    ];
+
+    /// The option a single letter flag such as the `e` of `set -e` stands for.
+    pub fn flag_name(flag: char) -> Option<&'static str> {
+        match flag {
+            'e' => Some("errexit"),
+            'u' => Some("nounset"),
+            'x' => Some("xtrace"),
+            'n' => Some("noexec"),
+            _ => None,
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<bool> {
+        match name {
+            "pipefail" => Some(self.pipefail),
+            "huponexit" => Some(self.huponexit),
+            "histexpand" => Some(self.histexpand),
+            "helpcomplete" => Some(self.helpcomplete),
+            "emacs" => Some(!self.vi),
+            "vi" => Some(self.vi),
+            "errexit" => Some(self.errexit),
+            "nounset" => Some(self.nounset),
+            "xtrace" => Some(self.xtrace),
+            "noexec" => Some(self.noexec),
+            "posix" => Some(self.posix),
+            _ => None,
+        }
+    }
+
+    /// Returns false if `name` is not a known option.
+    pub fn set(&mut self, name: &str, value: bool) -> bool {
+        match name {
+            "pipefail" => self.pipefail = value,
+            "huponexit" => self.huponexit = value,
+            "histexpand" => self.histexpand = value,
+            "helpcomplete" => self.helpcomplete = value,
+            "emacs" => self.vi = !value,
+            "vi" => self.vi = value,
+            "errexit" => self.errexit = value,
+            "nounset" => self.nounset = value,
+            "xtrace" => self.xtrace = value,
+            "noexec" => self.noexec = value,
+            "posix" => self.posix = value,
+            _ => return false,
+        }
+        true
+    }
+}
+
 #[derive(Debug)]
 pub struct Shell {
     pub pgid: Pid,
     script_name: String,
+    /// Runs jobs in process groups of their own and hands them the terminal.
     interactive: bool,
-    history: Vec<String>,//vec for now
+    /// The process group that had the terminal before the shell took it.
+    terminal_owner: Option<Pid>,
+    /// Started as a login shell, with `-l` or a `-` before its name.
+    login: bool,
+    history: History,
     last_status: i32,
     last_job: Option<Rc<Job>>,
     global_values: Variables,
@@ -51,6 +156,23 @@ pub struct Shell {
     jobs: HashMap<u32, Rc<RefCell<Job>>>,
     bg_jobs: HashSet<JobWrapper>,
     next_job_id: u32,
+    pub options: ShellOptions,
+    exit_warned: bool,
+    traps: HashMap<Trap, String>,
+    running_trap: bool,
+    line_in_history: bool,
+    /// Number of the next command read, which `\#` shows in the prompt.
+    command_number: usize,
+    /// How long the last command entered at the prompt ran.
+    last_duration: Option<Duration>,
+    /// The script and line being run, which error messages start with.
+    location: Option<(String, usize)>,
+    /// How many `source` commands are running, which `return` needs one of.
+    source_depth: usize,
+    /// Set by `return` to stop the sourced file.
+    returning: bool,
+    history_db: HistoryDb,
+    completions: HashMap<String, CompletionSpec>,
 }
 
 
@@ -60,7 +182,9 @@ impl Shell {
             pgid: getpid(),
             script_name: "".to_owned(),
             interactive: false,
-            history: Vec::new(),//for now
+            terminal_owner: None,
+            login: false,
+            history: History::new(history_path),
             last_status: 0,
             last_job: None,
             global_values: Variables::new(),
@@ -69,13 +193,219 @@ impl Shell {
             jobs: HashMap::new(),
             bg_jobs: HashSet::new(),
             next_job_id: 1,
+            options: ShellOptions::default(),
+            exit_warned: false,
+            traps: HashMap::new(),
+            running_trap: false,
+            line_in_history: false,
+            command_number: 1,
+            last_duration: None,
+            location: None,
+            source_depth: 0,
+            returning: false,
+            history_db: HistoryDb::new(),
+            completions: HashMap::new(),
+        }
+    }
+
+    #[inline]
+    pub fn history_mut(&mut self) -> &mut History {
+        &mut self.history
+    }
+
+    pub fn history_settings(&self) -> HistorySettings {
+        HistorySettings::from_vars(
+            self.lookup_var("HISTSIZE"),
+            self.lookup_var("HISTFILESIZE"),
+            self.lookup_var("HISTCONTROL"),
+            self.lookup_var("HISTIGNORE"),
+        )
+    }
+
+    /// HISTFILE overrides the path the shell was created with.
+    fn update_history_path(&mut self) {
+        if let Some(path) = self.lookup_var("HISTFILE") {
+            self.history.set_path(&PathBuf::from(path));
+        }
+    }
+
+    /// Reads the history file, done once the startup files had a chance to set HISTFILE.
+    pub fn load_history(&mut self) {
+        self.update_history_path();
+        let settings = self.history_settings();
+        if let Err(e) = self.history.load(&settings) {
+            eprintln!("history: {}", e);
+        }
+    }
+
+    /// Records a line entered at the prompt.
+    pub fn add_history(&mut self, line: &str) {
+        self.update_history_path();
+        let settings = self.history_settings();
+        self.line_in_history = self.history.add(line, &settings);
+    }
+
+    #[inline]
+    pub fn command_number(&self) -> usize {
+        self.command_number
+    }
+
+    #[inline]
+    pub fn last_duration(&self) -> Option<Duration> {
+        self.last_duration
+    }
+
+    pub fn next_command(&mut self) {
+        self.command_number += 1;
+    }
+
+    #[inline]
+    pub fn history(&self) -> &History {
+        &self.history
+    }
+
+    /// The structured history named by HISTDB.
+    pub fn history_db(&mut self) -> &HistoryDb {
+        let path = self.lookup_var("HISTDB").map(PathBuf::from);
+        self.history_db.set_path(path.as_deref());
+        &self.history_db
+    }
+
+    /// Remembers how long the line just run took for the prompt, and adds what came of it
+    /// to the structured history if it went into the plain history and HISTDB is set.
+    pub fn record_command(&mut self, line: &str, cwd: &str, started: SystemTime, duration: Duration) {
+        self.last_duration = Some(duration);
+        if !self.line_in_history {
+            return;
+        }
+        let status = self.last_status;
+        let db = self.history_db();
+        if db.enabled() {
+            if let Err(e) = db.record(line, cwd, status, started, duration) {
+                eprintln!("history: {}", e);
+            }
         }
     }
 
+    /// Number of the newest history entry that is not the line being run right now.
+    pub fn history_end(&self) -> usize {
+        let last = self.history.last_number();
+        if self.line_in_history { last.saturating_sub(1) } else { last }
+    }
+
     pub fn find_next_job_id(&mut self) {
         
     }
 
+    /// Sets what `$0` expands to, the script being run or the shell itself.
+    pub fn set_script_name(&mut self, name: &str) {
+        self.script_name = name.to_string();
+    }
+
+    #[inline]
+    pub fn script_name(&self) -> &str {
+        &self.script_name
+    }
+
+    /// The innermost variable frame, which holds `$1`, `$2` and so on.
+    fn current_frame(&self) -> &Variables {
+        self.local_values.last().unwrap_or(&self.global_values)
+    }
+
+    fn current_frame_mut(&mut self) -> &mut Variables {
+        self.local_values.last_mut().unwrap_or(&mut self.global_values)
+    }
+
+    /// Sets `$1`, `$2` and so on, dropping the ones there were before.
+    pub fn set_positional_args(&mut self, args: &[String]) {
+        let frame = self.current_frame_mut();
+        for i in 1..=frame.get_func_args().len() {
+            frame.remove_nth_func_arg(i);
+        }
+        frame.set_func_args(args);
+    }
+
+    /// `$1`, `$2` and so on.
+    pub fn positional_args(&self) -> Vec<String> {
+        self.current_frame().get_func_args_string()
+    }
+
+    /// Sets the script and line being run, None back at the prompt.
+    pub fn set_location(&mut self, location: Option<(String, usize)>) {
+        self.location = location;
+    }
+
+    #[inline]
+    pub fn location(&self) -> Option<&(String, usize)> {
+        self.location.as_ref()
+    }
+
+    /// What error messages start with while a script runs, like `file: line 3: `.
+    pub fn error_prefix(&self) -> Option<String> {
+        self.location.as_ref().map(|(name, line)| format!("{}: line {}: ", name, line))
+    }
+
+    /// Starts a function call, with a frame of its own for its `$1`, `$2` and local variables.
+    pub fn enter_function(&mut self, args: &[String]) {
+        let mut frame = Variables::new();
+        frame.set_func_args(args);
+        self.local_values.push(frame);
+    }
+
+    /// Ends a function call, forgetting a `return` that ended it.
+    pub fn leave_function(&mut self) {
+        self.local_values.pop();
+        self.returning = false;
+    }
+
+    #[inline]
+    pub fn in_function(&self) -> bool {
+        !self.local_values.is_empty()
+    }
+
+    /// Gives the function being run a variable of its own, unset when there is no value.
+    pub fn set_local_var(&mut self, key: &str, value: Option<Value>) {
+        let frame = self.current_frame_mut();
+        match value {
+            Some(value) => frame.set(key, value),
+            None => frame.define(key),
+        }
+    }
+
+    pub fn enter_source(&mut self) {
+        self.source_depth += 1;
+    }
+
+    /// Leaves a sourced file, forgetting a `return` that ended it.
+    pub fn leave_source(&mut self) {
+        self.source_depth -= 1;
+        self.returning = false;
+    }
+
+    #[inline]
+    pub fn sourcing(&self) -> bool {
+        self.source_depth > 0
+    }
+
+    /// Stops the function or sourced file after the command running now.
+    pub fn set_returning(&mut self) {
+        self.returning = true;
+    }
+
+    #[inline]
+    pub fn returning(&self) -> bool {
+        self.returning
+    }
+
+    pub fn set_login(&mut self, login: bool) {
+        self.login = login;
+    }
+
+    #[inline]
+    pub fn login(&self) -> bool {
+        self.login
+    }
+
     pub fn set_interactive(&mut self,interactive: bool) {
         self.interactive = interactive;
     }
@@ -85,6 +415,85 @@ impl Shell {
         self.interactive
     }
 
+    /// Gives the shell a process group of its own and makes that the foreground group of
+    /// the terminal, which job control needs before jobs can have groups and the terminal.
+    pub fn take_terminal(&mut self) -> nix::Result<()> {
+        let owner = unistd::tcgetpgrp(STDIN_FILENO)?;
+        let pid = getpid();
+        // a session leader already leads its group and cannot move
+        if unistd::getpgrp() != pid {
+            unistd::setpgid(pid, pid)?;
+        }
+        unistd::tcsetpgrp(STDIN_FILENO, pid)?;
+        self.pgid = pid;
+        self.terminal_owner = Some(owner);
+        Ok(())
+    }
+
+    /// Gives the terminal back to the group that had it before `take_terminal`.
+    fn release_terminal(&self) {
+        if let Some(owner) = self.terminal_owner.filter(|owner| *owner != self.pgid) {
+            let _ = unistd::tcsetpgrp(STDIN_FILENO, owner);
+        }
+    }
+
+    #[inline]
+    pub fn last_status(&self) -> i32 {
+        self.last_status
+    }
+
+    #[inline]
+    pub fn set_last_status(&mut self, status: i32) {
+        self.last_status = status;
+    }
+
+    /// Finds a shell variable, searching the innermost local frame first.
+    pub fn get_var(&self, key: &str) -> Option<Rc<Variable>> {
+        self.local_values.iter().rev()
+            .find_map(|frame| frame.get(key))
+            .or_else(|| self.global_values.get(key))
+    }
+
+    pub fn set_var(&mut self, key: &str, value: Value) {
+        match self.local_values.last_mut() {
+            Some(frame) if frame.get(key).is_some() => frame.set(key, value),
+            _ => self.global_values.set(key, value),
+        }
+    }
+
+    /// Looks up `$key`, accepting the `{name}` and `{name[index]}` forms as well.
+    /// Special parameters such as `$?` are resolved by the shell itself.
+    pub fn lookup_var(&self, key: &str) -> Option<String> {
+        let key = key.strip_prefix('{').and_then(|key| key.strip_suffix('}')).unwrap_or(key);
+
+        if let Some((name, index)) = key.strip_suffix(']').and_then(|key| key.split_once('[')) {
+            let var = self.get_var(name)?;
+            return match (var.value(), index) {
+                (Some(Value::Array(elems)), "@" | "*") => Some(elems.join(" ")),
+                (Some(Value::Array(elems)), index) => {
+                    // bash takes a name as the index for the value of that variable
+                    let index = index.parse::<usize>().ok().or_else(|| self.lookup_var(index)?.parse().ok())?;
+                    elems.get(index).cloned()
+                },
+                (_, "0" | "@" | "*") => Some(var.as_str().to_string()),
+                _ => None,
+            };
+        }
+
+        match key {
+            "?" => Some(self.last_status.to_string()),
+            "0" => Some(self.script_name.clone()),
+            "#" => Some(self.positional_args().len().to_string()),
+            "@" | "*" => Some(self.positional_args().join(" ")),
+            // a function's arguments hide the script's, even the ones it was not given
+            _ if key.bytes().all(|b| b.is_ascii_digit()) => self.current_frame().get(key).map(|var| var.as_str().to_string()),
+            _ => match self.get_var(key) {
+                Some(var) => Some(var.as_str().to_string()),
+                None => env::var(key).ok(),
+            },
+        }
+    }
+
     #[inline]
     pub fn add_background_job(&mut self,job: &Rc<RefCell<Job>>) {
         self.bg_jobs.insert(JobWrapper(job.clone()));
@@ -107,6 +516,190 @@ impl Shell {
         self.aliases.get(&alias.to_string()).cloned()
     } 
 
+    pub fn alias_names(&self) -> Vec<String> {
+        self.aliases.keys().cloned().collect()
+    }
+
+    /// Names of the shell variables in every scope, functions included.
+    pub fn var_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.local_values.iter()
+            .flat_map(|frame| frame.names())
+            .chain(self.global_values.names())
+            .map(str::to_string)
+            .collect();
+        names.sort_unstable();
+        names.dedup();
+        names
+    }
+
+    pub fn function_names(&self) -> Vec<String> {
+        self.var_names().into_iter()
+            .filter(|name| matches!(self.get_var(name).as_deref().map(Variable::value), Some(Some(Value::Function(_)))))
+            .collect()
+    }
+
+    /// Defines a function, which like in bash is seen from every scope.
+    pub fn define_function(&mut self, name: &str, body: &str) {
+        self.global_values.set(name, Value::Function(Box::new(expr::Command::Script(body.to_string()))));
+    }
+
+    /// The body of the function called `name`.
+    pub fn function(&self, name: &str) -> Option<String> {
+        match self.get_var(name)?.value() {
+            Some(Value::Function(command)) => match command.as_ref() {
+                expr::Command::Script(body) => Some(body.clone()),
+            },
+            _ => None,
+        }
+    }
+
+    /// Id, pipeline and whether it is stopped for every job, oldest first.
+    pub fn job_specs(&self) -> Vec<(u32, String, bool)> {
+        let mut specs: Vec<(u32, String, bool)> = self.jobs.iter()
+            .map(|(id, job)| {
+                let job = job.borrow();
+                (*id, job.pipeline().to_string(), matches!(job.state, ProcessStatus::Stopped(_)))
+            })
+            .collect();
+        specs.sort_unstable();
+        specs
+    }
+
+    pub fn completion(&self, name: &str) -> Option<&CompletionSpec> {
+        self.completions.get(name)
+    }
+
+    pub fn set_completion(&mut self, name: &str, spec: CompletionSpec) {
+        self.completions.insert(name.to_string(), spec);
+    }
+
+    /// Returns false if there was no spec for `name`.
+    pub fn remove_completion(&mut self, name: &str) -> bool {
+        self.completions.remove(name).is_some()
+    }
+
+    pub fn clear_completions(&mut self) {
+        self.completions.clear();
+    }
+
+    /// Every completion spec, sorted by command name.
+    pub fn completions(&self) -> Vec<(&str, &CompletionSpec)> {
+        let mut completions: Vec<(&str, &CompletionSpec)> = self.completions.iter()
+            .map(|(name, spec)| (name.as_str(), spec))
+            .collect();
+        completions.sort_unstable_by_key(|(name, _)| *name);
+        completions
+    }
+
+    /// Set after `exit` refused to leave because of stopped jobs, a second `exit` goes through.
+    #[inline]
+    pub fn exit_warned(&self) -> bool {
+        self.exit_warned
+    }
+
+    #[inline]
+    pub fn set_exit_warned(&mut self, warned: bool) {
+        self.exit_warned = warned;
+    }
+
+    pub fn has_stopped_jobs(&self) -> bool {
+        self.jobs.values().any(|job| job.borrow().stopped())
+    }
+
+    /// Resolves a job spec: `%n` by number, `%%`/`%+`/`%` for the most recent job,
+    /// `%-` for the one before it and `%string` for a job whose command starts with string.
+    pub fn find_job(&self, spec: &str) -> Option<Rc<RefCell<Job>>> {
+        let spec = spec.strip_prefix('%').unwrap_or(spec);
+        let mut ids: Vec<u32> = self.jobs.keys().cloned().collect();
+        ids.sort_unstable();
+
+        let id = match spec {
+            "" | "%" | "+" => ids.last().cloned(),
+            "-" => ids.iter().rev().nth(1).cloned(),
+            _ => match spec.parse::<u32>() {
+                Ok(id) => Some(id),
+                Err(_) => ids.iter().rev()
+                    .find(|id| self.jobs[id].borrow().pipeline().starts_with(spec))
+                    .cloned(),
+            },
+        };
+        id.and_then(|id| self.jobs.get(&id).cloned())
+    }
+
+    /// Forgets about a job so it is left alone when the shell exits.
+    pub fn disown(&mut self, job: &Rc<RefCell<Job>>) {
+        self.remove_background_job(job);
+        self.jobs.remove(&job.borrow().id());
+    }
+
+    /// Sends SIGHUP to the jobs the shell is leaving behind. Stopped jobs are also
+    /// continued so they see it, running ones are only hung up if `all` is set.
+    fn hangup_jobs(&self, all: bool) {
+        for job in self.jobs.values() {
+            let job = job.borrow();
+            if job.stopped() {
+                job.signal(Signal::SIGHUP);
+                job.signal(Signal::SIGCONT);
+            }
+            else if all && !job.completed() {
+                job.signal(Signal::SIGHUP);
+            }
+        }
+    }
+
+    /// Leaves the shell with `status`, running the EXIT trap and hanging up its jobs first.
+    /// A login shell reads `~/.rshell_logout` on the way out.
+    pub fn exit(&mut self, status: i32) -> ! {
+        signals::run_trap(self, Trap::Exit);
+        // taken first so an `exit` in the file does not read it again
+        if std::mem::take(&mut self.login) {
+            if let Some(home) = self.lookup_var("HOME") {
+                crate::source_startup_file(self, &Path::new(&home).join(".rshell_logout"));
+            }
+        }
+        self.hangup_jobs(self.options.huponexit);
+        self.release_terminal();
+        std::process::exit(status);
+    }
+
+    /// The shell itself received SIGHUP, pass it on to every job and exit.
+    pub fn hangup(&mut self) -> ! {
+        signals::run_trap(self, Trap::Exit);
+        self.hangup_jobs(true);
+        self.release_terminal();
+        std::process::exit(128 + Signal::SIGHUP as i32);
+    }
+
+    #[inline]
+    pub fn trap(&self, trap: Trap) -> Option<&String> {
+        self.traps.get(&trap)
+    }
+
+    /// Every installed trap in signal order.
+    pub fn traps(&self) -> Vec<(Trap, &String)> {
+        let mut traps: Vec<(Trap, &String)> = self.traps.iter().map(|(trap, action)| (*trap, action)).collect();
+        traps.sort();
+        traps
+    }
+
+    pub fn set_trap(&mut self, trap: Trap, action: &str) {
+        self.traps.insert(trap, action.to_owned());
+    }
+
+    pub fn remove_trap(&mut self, trap: Trap) -> Option<String> {
+        self.traps.remove(&trap)
+    }
+
+    #[inline]
+    pub fn running_trap(&self) -> bool {
+        self.running_trap
+    }
+
+    #[inline]
+    pub fn set_running_trap(&mut self, running: bool) {
+        self.running_trap = running;
+    }
+
     pub fn remove_job(&mut self, job_id: u32) -> Option<Rc<RefCell<Job>>> {
         #[cfg(debug_assertions)]
         println!("shell jobs: {:?}\n",self.jobs);
@@ -115,6 +708,7 @@ impl Shell {
 
     pub fn create_job(&mut self, cmdline: &str, cmds: Vec<String>, args: Vec<Vec<String>>,stdin_redir: Vec<Redirection>, stdout_redir: Vec<Redirection>) -> Rc<RefCell<Job>> {
         let job = Rc::new(RefCell::new(Job::new(self.next_job_id,cmdline)));
+        job.borrow_mut().set_pipefail(self.options.pipefail);
 
         self.jobs.insert(self.next_job_id,job.clone());
         self.next_job_id += 1;
@@ -0,0 +1,197 @@
+use crate::shell::Shell;
+use nix::libc::c_int;
+use nix::sys::signal::{self, sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const NSIG: usize = 32;
+
+/// Ignored by the shell itself so job control works, children get them back at their default.
+const SHELL_IGNORED: [Signal; 5] = [
+    Signal::SIGINT,
+    Signal::SIGQUIT,
+    Signal::SIGTSTP,
+    Signal::SIGTTIN,
+    Signal::SIGTTOU,
+];
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NOT_SET: AtomicBool = AtomicBool::new(false);
+static PENDING: [AtomicBool; NSIG] = [NOT_SET; NSIG];
+static IGNORED_ON_ENTRY: [AtomicBool; NSIG] = [NOT_SET; NSIG];
+
+extern "C" fn record_signal(signo: c_int) {
+    if let Some(pending) = PENDING.get(signo as usize) {
+        pending.store(true, Ordering::SeqCst);
+    }
+}
+
+
+/// What `trap` can be set on, real signals plus the shell's pseudo-signals.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash,PartialOrd,Ord)]
+pub enum Trap {
+    Exit,
+    Signal(Signal),
+    Err,
+    Debug,
+    Return,
+}
+
+impl Trap {
+    /// Accepts `EXIT`, `ERR`, `DEBUG`, `RETURN`, signal names with or without
+    /// the SIG prefix in any case, and signal numbers with 0 meaning EXIT.
+    pub fn parse(name: &str) -> Option<Trap> {
+        let upper = name.to_uppercase();
+        match upper.as_str() {
+            "EXIT" | "0" => return Some(Trap::Exit),
+            "ERR" => return Some(Trap::Err),
+            "DEBUG" => return Some(Trap::Debug),
+            "RETURN" => return Some(Trap::Return),
+            _ => (),
+        }
+
+        if let Ok(signo) = upper.parse::<i32>() {
+            return Signal::try_from(signo).ok().map(Trap::Signal);
+        }
+        let upper = if upper.starts_with("SIG") { upper } else { format!("SIG{}", upper) };
+        Signal::from_str(&upper).ok().map(Trap::Signal)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Trap::Exit => "EXIT",
+            Trap::Signal(signal) => signal.as_str(),
+            Trap::Err => "ERR",
+            Trap::Debug => "DEBUG",
+            Trap::Return => "RETURN",
+        }
+    }
+}
+
+
+fn set_handler(signal: Signal, handler: SigHandler) -> nix::Result<()> {
+    let action = SigAction::new(handler, SaFlags::empty(), SigSet::empty());
+    unsafe { sigaction(signal, &action) }.map(|_| ())
+}
+
+/// Records what was ignored when the shell started and sets up the shell's own dispositions.
+pub fn init() -> nix::Result<()> {
+    let ignore = SigAction::new(SigHandler::SigIgn, SaFlags::empty(), SigSet::empty());
+    for signal in Signal::iterator() {
+        if signal == Signal::SIGKILL || signal == Signal::SIGSTOP {
+            continue;
+        }
+        if let Ok(old) = unsafe { sigaction(signal, &ignore) } {
+            IGNORED_ON_ENTRY[signal as usize].store(old.handler() == SigHandler::SigIgn, Ordering::SeqCst);
+            let _ = unsafe { sigaction(signal, &old) };
+        }
+    }
+
+    for signal in SHELL_IGNORED {
+        set_handler(signal, SigHandler::SigIgn)?;
+    }
+    if !ignored_on_entry(Signal::SIGHUP) {
+        catch(Signal::SIGHUP)?;
+    }
+    Ok(())
+}
+
+/// POSIX leaves signals that were ignored on entry ignored, they cannot be trapped.
+#[inline]
+pub fn ignored_on_entry(signal: Signal) -> bool {
+    IGNORED_ON_ENTRY[signal as usize].load(Ordering::SeqCst)
+}
+
+/// Catches `signal` so it can be acted on from the main loop.
+/// No SA_RESTART, a blocking read or wait returns EINTR instead of resuming.
+pub fn catch(signal: Signal) -> nix::Result<()> {
+    set_handler(signal, SigHandler::Handler(record_signal))
+}
+
+pub fn ignore(signal: Signal) -> nix::Result<()> {
+    set_handler(signal, SigHandler::SigIgn)
+}
+
+/// Puts back the disposition the shell runs with when nothing is trapped.
+pub fn restore(signal: Signal) -> nix::Result<()> {
+    if signal == Signal::SIGHUP {
+        catch(signal)
+    }
+    else if SHELL_IGNORED.contains(&signal) {
+        ignore(signal)
+    }
+    else {
+        set_handler(signal, SigHandler::SigDfl)
+    }
+}
+
+/// Signals a child must have reset to SIG_DFL before exec. Caught signals are reset
+/// by exec itself, so only the shell's own ignores that the user did not ask for remain.
+pub fn child_defaults(shell: &Shell) -> Vec<Signal> {
+    SHELL_IGNORED.iter()
+        .filter(|signal| !ignored_on_entry(**signal))
+        .filter(|signal| shell.trap(Trap::Signal(**signal)).is_none_or(|action| !action.is_empty()))
+        .cloned()
+        .collect()
+}
+
+/// Arranges for `command` to start with the dispositions given by `child_defaults`.
+pub fn reset_in_child<'a>(command: &'a mut Command, shell: &Shell) -> &'a mut Command {
+    let defaults = child_defaults(shell);
+    unsafe {
+        command.pre_exec(move || {
+            for signal in defaults.iter() {
+                let _ = signal::signal(*signal, SigHandler::SigDfl);
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Clears and returns whether `signal` arrived since the last call.
+pub fn take_pending(signal: Signal) -> bool {
+    PENDING[signal as usize].swap(false, Ordering::SeqCst)
+}
+
+/// True if an untrapped SIGHUP arrived and the shell should hang up.
+pub fn hangup_pending(shell: &Shell) -> bool {
+    shell.trap(Trap::Signal(Signal::SIGHUP)).is_none() && take_pending(Signal::SIGHUP)
+}
+
+/// Runs the action installed for `trap`. `$?` is left as it was before the trap ran.
+/// The EXIT trap only ever runs once and the others do not fire from inside a trap.
+pub fn run_trap(shell: &mut Shell, trap: Trap) {
+    let action = match trap {
+        Trap::Exit => shell.remove_trap(Trap::Exit),
+        _ if shell.running_trap() => None,
+        _ => shell.trap(trap).cloned(),
+    };
+    let action = match action {
+        Some(action) if !action.is_empty() => action,
+        _ => return,
+    };
+
+    let status = shell.last_status();
+    let running = shell.running_trap();
+    shell.set_running_trap(true);
+    for line in action.lines() {
+        crate::eval(&format!("{}\n", line), shell);
+    }
+    shell.set_running_trap(running);
+    shell.set_last_status(status);
+}
+
+/// Acts on every signal caught since the last call: its trap is run or,
+/// for an untrapped SIGHUP, the shell hangs up.
+pub fn run_pending_traps(shell: &mut Shell) {
+    if hangup_pending(shell) {
+        shell.hangup();
+    }
+    for signal in Signal::iterator() {
+        if (signal as usize) < NSIG && take_pending(signal) {
+            run_trap(shell, Trap::Signal(signal));
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use crate::builtin::words;
+use crate::shell::Shell;
+use nix::libc;
+use nix::sys::resource::{getrusage, UsageWho};
+use std::ffi::CString;
+use std::mem::MaybeUninit;
+use std::time::{Duration, Instant};
+
+const DEFAULT_TIMEFORMAT: &str = "\\nreal\\t%3lR\\nuser\\t%3lU\\nsys\\t%3lS";
+const POSIX_TIMEFORMAT: &str = "real %2R\\nuser %2U\\nsys %2S";
+
+
+/// The parts of `struct rusage` the shell reports on.
+#[derive(Debug,Default,Clone,Copy,PartialEq,Hash)]
+pub struct ResourceUsage {
+    pub user: Duration,
+    pub system: Duration,
+    /// Maximum resident set size in kilobytes.
+    pub max_rss: i64,
+    pub voluntary_switches: i64,
+    pub involuntary_switches: i64,
+}
+
+fn timeval_duration(time: &libc::timeval) -> Duration {
+    Duration::new(time.tv_sec as u64, time.tv_usec as u32 * 1000)
+}
+
+impl ResourceUsage {
+    pub fn from_rusage(usage: &libc::rusage) -> ResourceUsage {
+        ResourceUsage {
+            user: timeval_duration(&usage.ru_utime),
+            system: timeval_duration(&usage.ru_stime),
+            max_rss: usage.ru_maxrss,
+            voluntary_switches: usage.ru_nvcsw,
+            involuntary_switches: usage.ru_nivcsw,
+        }
+    }
+
+    /// Usage of the shell itself or of all its waited for children.
+    pub fn of(who: UsageWho) -> ResourceUsage {
+        match getrusage(who) {
+            Ok(usage) => ResourceUsage::from_rusage(usage.as_ref()),
+            Err(_) => ResourceUsage::default(),
+        }
+    }
+
+    /// Accumulates another process into this one, the peak RSS is the largest of the two.
+    pub fn add(&mut self, other: &ResourceUsage) {
+        self.user += other.user;
+        self.system += other.system;
+        self.max_rss = self.max_rss.max(other.max_rss);
+        self.voluntary_switches += other.voluntary_switches;
+        self.involuntary_switches += other.involuntary_switches;
+    }
+
+    /// What has been used since `earlier` was sampled.
+    pub fn since(&self, earlier: &ResourceUsage) -> ResourceUsage {
+        ResourceUsage {
+            user: self.user.saturating_sub(earlier.user),
+            system: self.system.saturating_sub(earlier.system),
+            max_rss: self.max_rss,
+            voluntary_switches: self.voluntary_switches - earlier.voluntary_switches,
+            involuntary_switches: self.involuntary_switches - earlier.involuntary_switches,
+        }
+    }
+}
+
+
+/// Started by the `time` reserved word in front of a pipeline.
+#[derive(Debug)]
+pub struct Timer {
+    posix: bool,
+    start: Instant,
+    shell_usage: ResourceUsage,
+}
+
+impl Timer {
+    /// Strips a leading `time` (and `-p`) from `argv`, returning a running timer if it was there.
+    pub fn from_argv(argv: &mut Vec<String>) -> Option<Timer> {
+        let words = words(argv);
+        let mut leading = words.iter().map(|word| &word[..]);
+        if leading.next() != Some("time") {
+            return None;
+        }
+        let posix = leading.next() == Some("-p");
+
+        let keyword_words = if posix { 2 } else { 1 };
+        let mut seen = 0;
+        let end = argv.iter()
+            .position(|arg| {
+                if arg.trim().is_empty() {
+                    return false;
+                }
+                seen += 1;
+                seen > keyword_words
+            })
+            .unwrap_or(argv.len());
+        argv.drain(..end);
+
+        Some(Timer {
+            posix,
+            start: Instant::now(),
+            shell_usage: ResourceUsage::of(UsageWho::RUSAGE_SELF),
+        })
+    }
+
+    /// Prints the elapsed times on stderr according to `TIMEFORMAT`.
+    /// `job_usage` is what the pipeline's own processes reported when they were reaped.
+    pub fn report(&self, shell: &Shell, job_usage: ResourceUsage) {
+        let real = self.start.elapsed();
+        let mut usage = ResourceUsage::of(UsageWho::RUSAGE_SELF).since(&self.shell_usage);
+        usage.add(&job_usage);
+
+        let format = if self.posix {
+            POSIX_TIMEFORMAT.to_string()
+        }
+        else {
+            shell.lookup_var("TIMEFORMAT").unwrap_or_else(|| DEFAULT_TIMEFORMAT.to_string())
+        };
+        if format.is_empty() {
+            return;
+        }
+
+        eprintln!("{}", format_times(&format, real, &usage));
+    }
+}
+
+fn format_duration(duration: Duration, precision: usize, long: bool) -> String {
+    let seconds = duration.as_secs_f64();
+    if long {
+        let minutes = (seconds / 60.0).floor();
+        format!("{}m{:.*}s", minutes, precision, seconds - minutes * 60.0)
+    }
+    else {
+        format!("{:.*}", precision, seconds)
+    }
+}
+
+/// Expands a bash style `TIMEFORMAT`.
+///
+/// `%[p][l]R`, `%[p][l]U` and `%[p][l]S` give the real, user and system time with `p`
+/// decimal places in seconds or, with `l`, as MMmSS.FFs. `%P` is the CPU percentage.
+/// In addition `%M` is the peak resident set size in kilobytes and `%w`/`%c` are the
+/// voluntary and involuntary context switches. `\n` and `\t` are understood as well.
+pub fn format_times(format: &str, real: Duration, usage: &ResourceUsage) -> String {
+    let mut output = String::new();
+    let mut chars = format.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('n') => output.push('\n'),
+                Some('t') => output.push('\t'),
+                Some(c) => output.push(c),
+                None => output.push('\\'),
+            },
+            '%' => {
+                let mut precision = 3;
+                if let Some(digit) = chars.peek().and_then(|c| c.to_digit(10)) {
+                    precision = (digit as usize).min(3);
+                    chars.next();
+                }
+                let long = chars.peek() == Some(&'l');
+                if long {
+                    chars.next();
+                }
+
+                match chars.next() {
+                    Some('%') => output.push('%'),
+                    Some('R') => output += &format_duration(real, precision, long),
+                    Some('U') => output += &format_duration(usage.user, precision, long),
+                    Some('S') => output += &format_duration(usage.system, precision, long),
+                    Some('P') => {
+                        let cpu = (usage.user + usage.system).as_secs_f64();
+                        let real = real.as_secs_f64();
+                        let percent = if real > 0.0 { cpu * 100.0 / real } else { 0.0 };
+                        output += &format!("{:.2}", percent);
+                    },
+                    Some('M') => output += &usage.max_rss.to_string(),
+                    Some('w') => output += &usage.voluntary_switches.to_string(),
+                    Some('c') => output += &usage.involuntary_switches.to_string(),
+                    Some(c) => {
+                        output.push('%');
+                        output.push(c);
+                    },
+                    None => output.push('%'),
+                }
+            },
+            c => output.push(c),
+        }
+    }
+
+    output
+}
+
+/// The `times` builtin: user and system time of the shell, then of its children.
+pub fn times() -> i32 {
+    for who in [UsageWho::RUSAGE_SELF, UsageWho::RUSAGE_CHILDREN] {
+        let usage = ResourceUsage::of(who);
+        println!("{} {}", format_duration(usage.user, 3, true), format_duration(usage.system, 3, true));
+    }
+    0
+}
+
+/// Formats a time in seconds since the epoch as local time with a strftime(3) format.
+pub fn strftime(format: &str, timestamp: u64) -> String {
+    let format = match CString::new(format) {
+        Ok(format) => format,
+        Err(_) => return String::new(),
+    };
+    let time = timestamp as libc::time_t;
+    let mut tm = MaybeUninit::<libc::tm>::zeroed();
+    let mut buffer = [0u8; 256];
+
+    let len = unsafe {
+        libc::localtime_r(&time, tm.as_mut_ptr());
+        libc::strftime(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len(), format.as_ptr(), tm.as_ptr())
+    };
+    String::from_utf8_lossy(&buffer[..len]).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn argv(words: &[&str]) -> Vec<String> {
+        words.iter().map(|word| word.to_string()).collect()
+    }
+
+    #[test]
+    fn time_and_its_option_come_off_the_line() {
+        let mut line = argv(&["time", " ", "-p", " ", "sleep", " ", "1"]);
+        assert!(Timer::from_argv(&mut line).is_some_and(|timer| timer.posix));
+        assert_eq!(line, argv(&["sleep", " ", "1"]));
+
+        let mut line = argv(&["time", " ", "ls", " ", "-p"]);
+        assert!(Timer::from_argv(&mut line).is_some_and(|timer| !timer.posix));
+        assert_eq!(line, argv(&["ls", " ", "-p"]));
+
+        let mut line = argv(&["ls", " ", "time"]);
+        assert!(Timer::from_argv(&mut line).is_none());
+        assert_eq!(line.len(), 3);
+    }
+
+    #[test]
+    fn timeformat_escapes() {
+        let usage = ResourceUsage {
+            user: Duration::from_millis(1500),
+            system: Duration::from_millis(250),
+            max_rss: 2048,
+            voluntary_switches: 7,
+            involuntary_switches: 3,
+        };
+        let real = Duration::from_millis(61_250);
+        assert_eq!(format_times(DEFAULT_TIMEFORMAT, real, &usage), "\nreal\t1m1.250s\nuser\t0m1.500s\nsys\t0m0.250s");
+        assert_eq!(format_times(POSIX_TIMEFORMAT, real, &usage), "real 61.25\nuser 1.50\nsys 0.25");
+        assert_eq!(format_times("%0R %M %w %c %% %x", real, &usage), "61 2048 7 3 % %x");
+        assert_eq!(format_times("%P", Duration::from_millis(3500), &usage), "50.00");
+    }
+}
@@ -68,6 +68,10 @@ impl Variables {
         self.vars.get(key).cloned()
     }
 
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.vars.keys().map(String::as_str)
+    }
+
     pub fn get_func_args(&self) -> Vec<Rc<Variable>> {
         let mut args = Vec::new();
         for i in 1.. {
@@ -0,0 +1,158 @@
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+fn rshell(args: &[&str], input: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_RShell"))
+        .args(args)
+        .env("HOME", std::env::temp_dir())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+/// The lines a command printed, leaving out what debug builds add around them.
+fn printed(output: &Output, expected: &[&str]) -> bool {
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut lines = stdout.lines();
+    expected.iter().all(|line| lines.any(|printed| printed == *line))
+}
+
+#[test]
+fn piped_input_without_final_newline() {
+    let output = rshell(&[], "echo one\necho two");
+    assert_eq!(output.status.code(), Some(0));
+    assert!(printed(&output, &["one", "two"]));
+}
+
+#[test]
+fn command_without_final_newline() {
+    let output = rshell(&["-c", "echo hi"], "");
+    assert_eq!(output.status.code(), Some(0));
+    assert!(printed(&output, &["hi"]));
+}
+
+#[test]
+fn functions_take_arguments_and_return() {
+    let script = "greet() {\n    local who=$1\n    echo hello $who $#\n    return 3\n    echo not reached\n}\nwho=outer\ngreet world x\necho status $?\necho after $who\n";
+    let output = rshell(&[], script);
+    assert!(printed(&output, &["hello world 2", "status 3", "after outer"]));
+    assert!(!printed(&output, &["not reached"]));
+}
+
+#[test]
+fn complete_function_fills_compreply() {
+    let script = "_svc() {\n    COMPREPLY=( $(compgen -W 'start stop status' -- $2) )\n}\n\
+        _where() {\n    COMPREPLY=( $COMP_CWORD ${COMP_WORDS[0]} $1 )\n}\n\
+        complete -F _svc svc\ncomplete -p svc\ncompgen -F _svc sto\ncompgen -F _where x\ncompgen -A function _s\n";
+    let output = rshell(&[], script);
+    assert!(printed(&output, &["complete -F _svc svc", "stop", "1", "compgen", "_svc"]));
+    assert!(!printed(&output, &["start"]));
+}
+
+#[test]
+fn noexec_parses_without_running() {
+    let output = rshell(&["-n"], "echo ran\nls > | wc\n");
+    assert_eq!(output.status.code(), Some(2));
+    assert!(!printed(&output, &["ran"]));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("syntax error near unexpected token `|'"), "{}", stderr);
+
+    let output = rshell(&["-n"], "echo ran | wc -l > out\n");
+    assert_eq!(output.status.code(), Some(0));
+    assert!(!printed(&output, &["ran"]));
+}
+
+#[test]
+fn a_killed_command_sets_128_plus_the_signal() {
+    let output = rshell(&[], "sh -c 'kill -TERM $$'\necho status $?\n");
+    assert!(printed(&output, &["status 143"]));
+}
+
+#[test]
+fn pipestatus_and_pipefail() {
+    let script = "sh -c 'exit 3' | true\necho status $? ${PIPESTATUS[@]} $PIPESTATUS[1]\nset -o pipefail\nsh -c 'exit 3' | sh -c 'exit 4' | true\necho status $? ${PIPESTATUS[@]}\nset +o pipefail\nfalse | true\necho status $?\n";
+    let output = rshell(&[], script);
+    assert!(printed(&output, &["status 0 3 0 0", "status 4 3 4 0", "status 0"]));
+}
+
+#[test]
+fn time_reports_with_timeformat() {
+    let output = rshell(&[], "TIMEFORMAT='took %0R'\ntime true\ntime -p true\n");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.lines().any(|line| line == "took 0"), "{}", stderr);
+    assert!(stderr.lines().any(|line| line.starts_with("real 0.")), "{}", stderr);
+}
+
+#[test]
+fn exit_status_argument() {
+    assert_eq!(rshell(&[], "exit 3\necho not reached\n").status.code(), Some(3));
+    assert_eq!(rshell(&[], "exit 300\n").status.code(), Some(44));
+    assert_eq!(rshell(&[], "exit many\n").status.code(), Some(2));
+    assert_eq!(rshell(&[], "false\nexit\n").status.code(), Some(1));
+}
+
+#[test]
+fn huponexit_hangs_up_background_jobs() {
+    let dir = std::env::temp_dir().join(format!("rshell-hup-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (started, hung_up) = (dir.join("started"), dir.join("hung-up"));
+    let script = format!(
+        "set -o huponexit\nsh -c 'trap \"touch {hung_up}; exit\" HUP; touch {started}; while true; do sleep 0.1; done' &\nsh -c 'while [ ! -e {started} ]; do sleep 0.05; done'\nexit 0\n",
+        hung_up = hung_up.display(),
+        started = started.display(),
+    );
+    assert_eq!(rshell(&[], &script).status.code(), Some(0));
+    let hung_up = (0..50).any(|_| {
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        hung_up.exists()
+    });
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(hung_up);
+}
+
+/// The lines of `trap -p` among what a command printed.
+fn traps(output: &Output) -> Vec<String> {
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    stdout.lines().filter(|line| line.starts_with("trap -- ")).map(str::to_string).collect()
+}
+
+#[test]
+fn trap_p_round_trips() {
+    let output = rshell(&[], "trap 'echo bye' EXIT\ntrap 'echo it'\\''s' USR1\ntrap '' INT\ntrap -p\n");
+    let listed = traps(&output);
+    assert_eq!(listed, ["trap -- 'echo bye' EXIT", "trap -- '' SIGINT", "trap -- 'echo it'\\''s' SIGUSR1"]);
+    assert!(printed(&output, &["bye"]));
+
+    // what it prints sets the same traps again
+    let output = rshell(&[], &format!("{}\ntrap -p\n", listed.join("\n")));
+    assert_eq!(traps(&output), listed);
+
+    let output = rshell(&[], "trap 'echo bye' EXIT\ntrap 'echo hi' USR1\ntrap - USR1\ntrap EXIT\ntrap -p\n");
+    assert!(traps(&output).is_empty());
+    assert!(!printed(&output, &["bye"]));
+}
+
+#[test]
+fn source_takes_arguments_searches_path_and_returns() {
+    let dir = std::env::temp_dir().join(format!("rshell-source-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("lib.sh"), "echo args $1 $2 $#\nreturn 4\necho not reached\n").unwrap();
+    std::fs::write(dir.join("bad.sh"), "echo fine\nreturn x\n").unwrap();
+
+    let script = format!(
+        "source {dir}/lib.sh a b\necho status $?\necho after $#\nPATH={dir}:/usr/bin:/bin\n. lib.sh c\necho status $?\nsource bad.sh\nsource nowhere.sh\necho status $?\nreturn\necho status $?\n",
+        dir = dir.display(),
+    );
+    let output = rshell(&[], &script);
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(printed(&output, &["args a b 2", "status 4", "after 0", "args c $2 1", "status 4", "fine", "status 1", "status 1"]));
+    assert!(!printed(&output, &["not reached"]));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("bad.sh: line 2: return: x: numeric argument required"), "{}", stderr);
+    assert!(stderr.contains("source: nowhere.sh: No such file or directory"), "{}", stderr);
+    assert!(stderr.contains("return: can only `return' from a function or sourced script"), "{}", stderr);
+}